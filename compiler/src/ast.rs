@@ -85,3 +85,80 @@ pub struct ParsedFile {
     pub imports: Vec<ImportStatement>,
     pub decls: Vec<Declaration>
 }
+
+/// shorthands for building trees in tests, since the syntaxes can't write most of the language yet
+#[cfg(test)]
+pub mod build {
+    use super::*;
+
+    thread_local! {
+        static NEXT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// tags `value` with a span of its own, since the type checker finds expressions by their spans
+    pub fn tag<T>(value: T) -> OpTag<T> {
+        let left = NEXT.with(|next| next.replace(next.get() + 1));
+        OpTag { value, loc: Some(Loc { file: crate::source::FileId(0), left, right: left + 1 }) }
+    }
+
+    pub fn tpe(name: &str) -> OpTag<Tpe> {
+        tag(Tpe::Name(tag(name.to_string())))
+    }
+
+    pub fn num(text: &str) -> OpTag<Expression> {
+        tag(Expression::Literal(tag(Literal::Numeric(tag(text.to_string())))))
+    }
+
+    pub fn boolean(value: bool) -> OpTag<Expression> {
+        tag(Expression::Literal(tag(Literal::Boolean(tag(value.to_string())))))
+    }
+
+    pub fn var(name: &str) -> OpTag<Expression> {
+        tag(Expression::VarAccess(tag(name.to_string())))
+    }
+
+    pub fn let_(name: &str, explicit_type: Option<&str>, value: OpTag<Expression>) -> OpTag<Expression> {
+        tag(Expression::VarDef { name: tag(name.to_string()), explicit_type: explicit_type.map(tpe), value: Box::new(value) })
+    }
+
+    pub fn op(name: MethodName, args: Vec<OpTag<Expression>>) -> OpTag<Expression> {
+        tag(Expression::MethodCall { receiver: None, name: tag(name), args, type_params: vec![] })
+    }
+
+    pub fn call(func: &str, args: Vec<OpTag<Expression>>) -> OpTag<Expression> {
+        op(MethodName::Normal(tag(func.to_string())), args)
+    }
+
+    pub fn cast(target: &str, value: OpTag<Expression>) -> OpTag<Expression> {
+        tag(Expression::MethodCall { receiver: None, name: tag(MethodName::Cast), args: vec![value], type_params: vec![tpe(target)] })
+    }
+
+    pub fn eval(expr: OpTag<Expression>) -> OpTag<Statement> {
+        tag(Statement::ExpressionEval(expr))
+    }
+
+    pub fn ret(value: OpTag<Expression>) -> OpTag<Statement> {
+        eval(op(MethodName::Return, vec![value]))
+    }
+
+    pub fn if_(condition: OpTag<Expression>, block: Vec<OpTag<Statement>>, else_block: Option<Vec<OpTag<Statement>>>) -> OpTag<Statement> {
+        tag(Statement::If { condition, block, else_block })
+    }
+
+    pub fn while_(condition: OpTag<Expression>, block: Vec<OpTag<Statement>>) -> OpTag<Statement> {
+        tag(Statement::While { condition, block })
+    }
+
+    pub fn func(name: &str, parameters: &[(&str, &str)], return_tpe: Option<&str>, block: Vec<OpTag<Statement>>) -> Declaration {
+        Declaration::Func(FunctionDef {
+            name: tag(name.to_string()),
+            parameters: parameters.iter().map(|(name, t)| (tag(name.to_string()), tpe(t))).collect(),
+            return_tpe: return_tpe.map(tpe),
+            block,
+        })
+    }
+
+    pub fn file(decls: Vec<Declaration>) -> ParsedFile {
+        ParsedFile { package: None, imports: vec![], decls }
+    }
+}
@@ -1,10 +1,12 @@
 use std::{fmt::{Debug, Display}, hash::Hash, ops::{Deref, DerefMut}};
 
+use crate::source::FileId;
+
 use super::Expression;
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct Loc {
-    // TODO: input file UUID or something referencing a global hashtable
+    pub file: FileId,
     pub left: usize, pub right: usize
 }
 
@@ -75,6 +77,23 @@ pub struct OpTag<T> {
 
 traits!(OpTag);
 
+/// a `Tag` or `OpTag`, which `SourceMap::locate` can turn into `path:line:col`
+pub trait Located {
+    fn location(&self) -> Option<Loc>;
+}
+
+impl<T> Located for Tag<T> {
+    fn location(&self) -> Option<Loc> {
+        Some(self.loc)
+    }
+}
+
+impl<T> Located for OpTag<T> {
+    fn location(&self) -> Option<Loc> {
+        self.loc
+    }
+}
+
 pub type BTag<T> = Box<OpTag<T>>;
 pub type BTExpression = BTag<Expression>;
 
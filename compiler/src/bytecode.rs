@@ -1,4 +1,10 @@
-// TODO: `Display` implementation for these types, mnemonics for instructions (i.e. XORB, XORH and such)
+pub mod asm;
+pub mod module;
+pub mod verify;
+
+use std::fmt::Display;
+
+use crate::ast::types::Loc;
 
 // we can implement other sizes in Kitchen Sink code
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -15,7 +21,72 @@ pub enum FloatSize {
     F64,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl IntSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            IntSize::I8 => 1,
+            IntSize::I16 => 2,
+            IntSize::I32 => 4,
+            IntSize::I64 => 8,
+        }
+    }
+
+    /// the bits a value of this size uses
+    pub fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.bytes() * 8)
+    }
+
+    pub fn from_bytes(bytes: u64) -> Option<IntSize> {
+        match bytes {
+            1 => Some(IntSize::I8),
+            2 => Some(IntSize::I16),
+            4 => Some(IntSize::I32),
+            8 => Some(IntSize::I64),
+            _ => None,
+        }
+    }
+}
+
+/// the suffix used in mnemonics: byte, half, word, double
+impl Display for IntSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IntSize::I8 => "B",
+            IntSize::I16 => "H",
+            IntSize::I32 => "W",
+            IntSize::I64 => "D",
+        })
+    }
+}
+
+/// the suffix used in mnemonics: single, double
+impl Display for FloatSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FloatSize::F32 => "S",
+            FloatSize::F64 => "D",
+        })
+    }
+}
+
+impl FloatSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            FloatSize::F32 => 4,
+            FloatSize::F64 => 8,
+        }
+    }
+
+    /// the integer size with the same width, for moving floats around as raw bits
+    pub fn int_size(self) -> IntSize {
+        match self {
+            FloatSize::F32 => IntSize::I32,
+            FloatSize::F64 => IntSize::I64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     // logical ops
     /// bitwise ANDs the last two items on the stack of the provided size, popping them and pushing the result
@@ -33,6 +104,24 @@ pub enum Instruction {
     /// pops the last two items from the stack, shifts the second to last one right by the amount given by the last one (filling with zeroes), and pushes the result
     UShr(IntSize),
 
+    // conversions
+    /// pops an unsigned integer of size `from` and pushes it as size `to`, filling with zeroes (or truncating, if `to` is smaller)
+    ZeroExtend { from: IntSize, to: IntSize },
+    /// pops a signed integer of size `from` and pushes it as size `to`, filling with its sign bit (or truncating, if `to` is smaller)
+    SignExtend { from: IntSize, to: IntSize },
+    /// pops an integer of size `from` and pushes its low `to` bytes
+    Truncate { from: IntSize, to: IntSize },
+    /// pops a signed integer and pushes the nearest float of size `to`
+    IntToFloat { from: IntSize, to: FloatSize },
+    /// pops an unsigned integer and pushes the nearest float of size `to`
+    UIntToFloat { from: IntSize, to: FloatSize },
+    /// pops a float and pushes it as a signed integer, rounding towards zero and saturating at the integer's limits (NaN becomes 0)
+    FloatToInt { from: FloatSize, to: IntSize },
+    /// pops a float and pushes it as an unsigned integer, rounding towards zero and saturating at the integer's limits (NaN becomes 0)
+    FloatToUInt { from: FloatSize, to: IntSize },
+    /// pops a float of size `from` and pushes the nearest float of size `to`
+    FloatConvert { from: FloatSize, to: FloatSize },
+
     // basic arithmetic
     /// adds the last two integer items on the stack of the provided size, popping them and pushing the result
     Add(IntSize),
@@ -54,9 +143,47 @@ pub enum Instruction {
     Mod(IntSize),
     /// modulos the last two floating point items on the stack of the provided size, popping them and pushing the result
     Modf(FloatSize),
+    /// divides the last two signed integer items on the stack of the provided size, rounding towards zero, popping them and pushing the result (the minimum divided by -1 wraps back to the minimum)
+    SDiv(IntSize),
+    /// takes the remainder of dividing the last two signed integer items on the stack of the provided size, popping them and pushing the result, which has the sign of the first
+    SMod(IntSize),
+
+    // checked arithmetic, which faults with `FaultKind::Overflow` if the result doesn't fit
+    /// adds the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    AddChecked(IntSize),
+    /// adds the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SAddChecked(IntSize),
+    /// subtracts the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    SubChecked(IntSize),
+    /// subtracts the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SSubChecked(IntSize),
+    /// multiplies the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    MulChecked(IntSize),
+    /// multiplies the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SMulChecked(IntSize),
+    /// divides the last two signed integer items on the stack of the provided size, popping them and pushing the result (unsigned division can't overflow)
+    SDivChecked(IntSize),
+
+    // saturating arithmetic, which clamps the result to the limits of the size
+    /// adds the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    AddSat(IntSize),
+    /// adds the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SAddSat(IntSize),
+    /// subtracts the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    SubSat(IntSize),
+    /// subtracts the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SSubSat(IntSize),
+    /// multiplies the last two unsigned integer items on the stack of the provided size, popping them and pushing the result
+    MulSat(IntSize),
+    /// multiplies the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SMulSat(IntSize),
+    /// divides the last two signed integer items on the stack of the provided size, popping them and pushing the result
+    SDivSat(IntSize),
 
     /// compares the last two integer items on the stack of the provided size, popping them and pushing the result (less than = -1, equal = 0, greater than = 1)
     Cmp(IntSize),
+    /// compares the last two signed integer items on the stack of the provided size, popping them and pushing the result (less than = -1, equal = 0, greater than = 1)
+    SCmp(IntSize),
     /// compares the last two floating point items on the stack of the provided size, popping them and pushing the result (less than = -1, equal = 0, greater than = 1)
     Cmpf(FloatSize),
 
@@ -65,17 +192,69 @@ pub enum Instruction {
     /// pops the last item from the stack with the given size, then pops an address.  If the first value is zero, jumps the address
     Jz(IntSize),
 
+    // functions
+    /// pushes the address of the next instruction (u64) to the stack, then jumps to the given address
+    Call(i64),
+    /// calls the function the VM's embedder registered with the given ID, which takes its arguments from and leaves its results on the stack
+    CallHost(u32),
+    /// pops a return address (u64) from the stack and jumps to it
+    Ret,
+    /// pushes the frame pointer (u64), sets the frame pointer to the stack pointer, then reserves the given number of zeroed bytes for locals
+    Enter(u64),
+    /// sets the stack pointer to the frame pointer (discarding locals), then pops the previous frame pointer (u64)
+    Leave,
+    /// reads `size` bytes at the frame pointer plus `offset` and pushes them to the stack
+    LoadLocal { offset: i64, size: IntSize },
+    /// pops `size` bytes from the stack and writes them at the frame pointer plus `offset`
+    StoreLocal { offset: i64, size: IntSize },
+
     /// pops a 32 bit file descriptor, a destination address, and a 16 bit max size from the stack, then tries to read from the descriptor.  Returns the number of bytes read as a 16 bit integer, or -1 if there's an error
     Read,
     /// pops a 32 bit file descriptor, a source address, and a 16 bit max size from the stack, then tries to write to the descriptor.  Returns the number of bytes written as a 16 bit integer, or -1 if there's an error
     Write,
+    /// pops 8 bit flags (see `vm::host::OpenFlags`), a path address, and a 16 bit path length from the stack, then opens the UTF-8 path there.  Pushes the new 32 bit file descriptor, or a negative error code
+    Open,
+    /// pops a 32 bit file descriptor and closes it.  Pushes 0 as a 32 bit integer, or a negative error code
+    Close,
+    /// pops a 32 bit file descriptor, an 8 bit origin (0 = start, 1 = current position, 2 = end), and a 64 bit signed offset from the stack, then moves the descriptor's position.  Pushes the new position as a 64 bit integer, or a negative error code
+    Seek,
+    /// pops a 32 bit file descriptor and pushes the size in bytes of what it refers to as a 64 bit integer, or a negative error code
+    Stat,
 
-    /// push a byte to the stack (can be repeated to push larger types) (should we have it take a size param and allow for bigger payload?)
+    /// push a byte to the stack
     Push(u8),
+    /// pushes the low `size` bytes of `value` to the stack
+    PushImm { size: IntSize, value: u64 },
+    /// pushes a floating point constant, given as its bits (only the low 32 for `F32`) so instructions can be compared
+    PushImmf { size: FloatSize, bits: u64 },
+    /// pushes all the bytes of the program's constant with the given index, in order, so the first ends up deepest
+    PushConst(u32),
 
     /// pop some number of bytes from the stack
     Pop(usize),
 
+    // stack manipulation, on items of the given size
+    /// pushes a copy of the top item
+    Dup(IntSize),
+    /// swaps the top two items
+    Swap(IntSize),
+    /// pushes a copy of the second item from the top
+    Over(IntSize),
+    /// moves the third item from the top to the top, so `a b c` becomes `b c a`
+    Rot(IntSize),
+    /// pushes a copy of the item `depth` items below the top, so a depth of 0 is `Dup` and 1 is `Over`
+    Pick { size: IntSize, depth: u64 },
+    /// `Dup` for items of any number of bytes
+    DupBytes(u64),
+    /// `Swap` for items of any number of bytes
+    SwapBytes(u64),
+    /// `Over` for items of any number of bytes
+    OverBytes(u64),
+    /// `Rot` for items of any number of bytes
+    RotBytes(u64),
+    /// pushes a copy of the `width` bytes that end `offset` bytes below the top, so the items above it don't have to be the same size
+    PickBytes { width: u64, offset: u64 },
+
     /// pop the last word from the stack, read `size` bytes of memory at that location, and push it to the stack
     Load { size: IntSize },
     /// pop the last word from the stack as an address, pop `size` more, and store that in memnory
@@ -87,6 +266,387 @@ pub enum Instruction {
     /// pushes the program counter (u64) to the stack
     PushIP,
 
+    /// pushes the frame pointer (u64) to the stack
+    PushFP,
+
     /// pushes the size of the heap in bytes (u64) to the stack
     PushMaxHeapSize
 }
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::And(s) => write!(f, "AND{s}"),
+            Instruction::Or(s) => write!(f, "OR{s}"),
+            Instruction::Xor(s) => write!(f, "XOR{s}"),
+            Instruction::Not(s) => write!(f, "NOT{s}"),
+            Instruction::Shl(s) => write!(f, "SHL{s}"),
+            Instruction::Shr(s) => write!(f, "SHR{s}"),
+            Instruction::UShr(s) => write!(f, "USHR{s}"),
+            Instruction::ZeroExtend { from, to } => write!(f, "ZEXT{from}{to}"),
+            Instruction::SignExtend { from, to } => write!(f, "SEXT{from}{to}"),
+            Instruction::Truncate { from, to } => write!(f, "TRUNC{from}{to}"),
+            Instruction::IntToFloat { from, to } => write!(f, "ITOF{from}{to}"),
+            Instruction::UIntToFloat { from, to } => write!(f, "UTOF{from}{to}"),
+            Instruction::FloatToInt { from, to } => write!(f, "FTOI{from}{to}"),
+            Instruction::FloatToUInt { from, to } => write!(f, "FTOU{from}{to}"),
+            Instruction::FloatConvert { from, to } => write!(f, "FCVT{from}{to}"),
+            Instruction::Add(s) => write!(f, "ADD{s}"),
+            Instruction::Addf(s) => write!(f, "ADDF{s}"),
+            Instruction::Sub(s) => write!(f, "SUB{s}"),
+            Instruction::Subf(s) => write!(f, "SUBF{s}"),
+            Instruction::Mul(s) => write!(f, "MUL{s}"),
+            Instruction::Mulf(s) => write!(f, "MULF{s}"),
+            Instruction::Div(s) => write!(f, "DIV{s}"),
+            Instruction::Divf(s) => write!(f, "DIVF{s}"),
+            Instruction::Mod(s) => write!(f, "MOD{s}"),
+            Instruction::Modf(s) => write!(f, "MODF{s}"),
+            Instruction::SDiv(s) => write!(f, "SDIV{s}"),
+            Instruction::SMod(s) => write!(f, "SMOD{s}"),
+            Instruction::AddChecked(s) => write!(f, "ADDCHK{s}"),
+            Instruction::SAddChecked(s) => write!(f, "SADDCHK{s}"),
+            Instruction::SubChecked(s) => write!(f, "SUBCHK{s}"),
+            Instruction::SSubChecked(s) => write!(f, "SSUBCHK{s}"),
+            Instruction::MulChecked(s) => write!(f, "MULCHK{s}"),
+            Instruction::SMulChecked(s) => write!(f, "SMULCHK{s}"),
+            Instruction::SDivChecked(s) => write!(f, "SDIVCHK{s}"),
+            Instruction::AddSat(s) => write!(f, "ADDSAT{s}"),
+            Instruction::SAddSat(s) => write!(f, "SADDSAT{s}"),
+            Instruction::SubSat(s) => write!(f, "SUBSAT{s}"),
+            Instruction::SSubSat(s) => write!(f, "SSUBSAT{s}"),
+            Instruction::MulSat(s) => write!(f, "MULSAT{s}"),
+            Instruction::SMulSat(s) => write!(f, "SMULSAT{s}"),
+            Instruction::SDivSat(s) => write!(f, "SDIVSAT{s}"),
+            Instruction::Cmp(s) => write!(f, "CMP{s}"),
+            Instruction::SCmp(s) => write!(f, "SCMP{s}"),
+            Instruction::Cmpf(s) => write!(f, "CMPF{s}"),
+            Instruction::Jmp(addr) => write!(f, "JMP {addr}"),
+            Instruction::Jz(s) => write!(f, "JZ{s}"),
+            Instruction::Call(addr) => write!(f, "CALL {addr}"),
+            Instruction::CallHost(id) => write!(f, "CALLHOST {id}"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Enter(size) => write!(f, "ENTER {size}"),
+            Instruction::Leave => write!(f, "LEAVE"),
+            Instruction::LoadLocal { offset, size } => write!(f, "LDLOC{size} {offset}"),
+            Instruction::StoreLocal { offset, size } => write!(f, "STLOC{size} {offset}"),
+            Instruction::Read => write!(f, "READ"),
+            Instruction::Write => write!(f, "WRITE"),
+            Instruction::Open => write!(f, "OPEN"),
+            Instruction::Close => write!(f, "CLOSE"),
+            Instruction::Seek => write!(f, "SEEK"),
+            Instruction::Stat => write!(f, "STAT"),
+            Instruction::Push(v) => write!(f, "PUSH {v}"),
+            Instruction::PushImm { size, value } => write!(f, "PUSH{size} {}", value & size.mask()),
+            Instruction::PushImmf { size, bits } => {
+                let value = match size {
+                    FloatSize::F32 => f32::from_bits(*bits as u32) as f64,
+                    FloatSize::F64 => f64::from_bits(*bits),
+                };
+                // NaNs have payloads that wouldn't survive being printed as a number
+                if value.is_nan() { write!(f, "PUSHF{size} {bits:#x}") } else { write!(f, "PUSHF{size} {value}") }
+            }
+            Instruction::PushConst(index) => write!(f, "PUSHCONST {index}"),
+            Instruction::Pop(n) => write!(f, "POP {n}"),
+            Instruction::Dup(s) => write!(f, "DUP{s}"),
+            Instruction::Swap(s) => write!(f, "SWAP{s}"),
+            Instruction::Over(s) => write!(f, "OVER{s}"),
+            Instruction::Rot(s) => write!(f, "ROT{s}"),
+            Instruction::Pick { size, depth } => write!(f, "PICK{size} {depth}"),
+            Instruction::DupBytes(width) => write!(f, "DUPBYTES {width}"),
+            Instruction::SwapBytes(width) => write!(f, "SWAPBYTES {width}"),
+            Instruction::OverBytes(width) => write!(f, "OVERBYTES {width}"),
+            Instruction::RotBytes(width) => write!(f, "ROTBYTES {width}"),
+            Instruction::PickBytes { width, offset } => write!(f, "PICKBYTES {width}, {offset}"),
+            Instruction::Load { size } => write!(f, "LOAD{size}"),
+            Instruction::Store { size } => write!(f, "STORE{size}"),
+            Instruction::PushSP => write!(f, "PUSHSP"),
+            Instruction::PushIP => write!(f, "PUSHIP"),
+            Instruction::PushFP => write!(f, "PUSHFP"),
+            Instruction::PushMaxHeapSize => write!(f, "PUSHMAXHEAP"),
+        }
+    }
+}
+
+/// a name for an instruction index, like a function or label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub index: usize,
+}
+
+/// maps instructions back to the source code they were compiled from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// paths of the source files, indexed by the `FileId`s in `locs`
+    pub files: Vec<String>,
+    /// (instruction index, location) pairs sorted by index, each covering instructions up to the next one
+    pub locs: Vec<(usize, Loc)>,
+}
+
+impl DebugInfo {
+    /// the source location an instruction was compiled from, if it's known
+    pub fn loc(&self, index: usize) -> Option<Loc> {
+        let i = self.locs.partition_point(|(start, _)| *start <= index);
+        i.checked_sub(1).map(|i| self.locs[i].1)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// initial contents of main memory, starting at address 0
+    pub data: Vec<u8>,
+    /// byte strings `PushConst` pushes, for anything too big to push a piece at a time
+    pub constants: Vec<Vec<u8>>,
+    pub symbols: Vec<Symbol>,
+    pub debug: Option<DebugInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    UnknownOpcode(u8),
+    InvalidOperand,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode {op:#04x}"),
+            DecodeError::InvalidOperand => write!(f, "invalid operand"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// takes `n` bytes off the front of `bytes`
+pub fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if bytes.len() < n {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(taken)
+}
+
+trait Operand: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl Operand for IntSize {
+    fn encode(&self, out: &mut Vec<u8>) { out.push(*self as u8) }
+    fn decode(bytes: &mut &[u8]) -> Result<Self, DecodeError> {
+        match take(bytes, 1)?[0] {
+            0 => Ok(IntSize::I8),
+            1 => Ok(IntSize::I16),
+            2 => Ok(IntSize::I32),
+            3 => Ok(IntSize::I64),
+            _ => Err(DecodeError::InvalidOperand),
+        }
+    }
+}
+
+impl Operand for FloatSize {
+    fn encode(&self, out: &mut Vec<u8>) { out.push(*self as u8) }
+    fn decode(bytes: &mut &[u8]) -> Result<Self, DecodeError> {
+        match take(bytes, 1)?[0] {
+            0 => Ok(FloatSize::F32),
+            1 => Ok(FloatSize::F64),
+            _ => Err(DecodeError::InvalidOperand),
+        }
+    }
+}
+
+macro_rules! int_operand {
+    ($($tpe:ty),*) => {$(
+        impl Operand for $tpe {
+            fn encode(&self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()) }
+            fn decode(bytes: &mut &[u8]) -> Result<Self, DecodeError> {
+                Ok(<$tpe>::from_le_bytes(take(bytes, std::mem::size_of::<$tpe>())?.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+int_operand!(u8, u32, u64, i64);
+
+impl Operand for usize {
+    fn encode(&self, out: &mut Vec<u8>) { (*self as u64).encode(out) }
+    fn decode(bytes: &mut &[u8]) -> Result<Self, DecodeError> {
+        usize::try_from(u64::decode(bytes)?).map_err(|_| DecodeError::InvalidOperand)
+    }
+}
+
+// the opcode for each instruction, followed by its operands in order
+macro_rules! encoding {
+    ($($op:literal => $name:ident $(($($t:ident),*))? $({$($f:ident),*})?),* $(,)?) => {
+        impl Instruction {
+            /// the byte an instruction is encoded with, which is the same for every size and operand
+            pub fn opcode(&self) -> u8 {
+                match self {
+                    $(Instruction::$name { .. } => $op,)*
+                }
+            }
+
+            /// the variant's name, without its size or operands
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Instruction::$name { .. } => stringify!($name),)*
+                }
+            }
+
+            pub fn encode(&self, out: &mut Vec<u8>) {
+                match self {
+                    $(Instruction::$name $(($($t),*))? $({$($f),*})? => {
+                        out.push($op);
+                        $($(Operand::encode($t, out);)*)?
+                        $($(Operand::encode($f, out);)*)?
+                    })*
+                }
+            }
+
+            fn decode_operands(bytes: &mut &[u8]) -> Result<Instruction, DecodeError> {
+                Ok(match take(bytes, 1)?[0] {
+                    $($op => Instruction::$name $(($({ let $t = Operand::decode(bytes)?; $t }),*))? $({$($f: Operand::decode(bytes)?),*})?,)*
+                    op => return Err(DecodeError::UnknownOpcode(op)),
+                })
+            }
+        }
+    };
+}
+
+encoding! {
+    0x00 => And(size), 0x01 => Or(size), 0x02 => Xor(size), 0x03 => Not(size),
+    0x04 => Shl(size), 0x05 => Shr(size), 0x06 => UShr(size),
+    0x08 => ZeroExtend { from, to }, 0x09 => SignExtend { from, to }, 0x0a => Truncate { from, to },
+    0x0b => IntToFloat { from, to }, 0x0c => UIntToFloat { from, to }, 0x0d => FloatToInt { from, to },
+    0x0e => FloatToUInt { from, to }, 0x0f => FloatConvert { from, to },
+    0x10 => Add(size), 0x11 => Addf(size), 0x12 => Sub(size), 0x13 => Subf(size),
+    0x14 => Mul(size), 0x15 => Mulf(size), 0x16 => Div(size), 0x17 => Divf(size),
+    0x18 => Mod(size), 0x19 => Modf(size),
+    0x1a => Cmp(size), 0x1b => Cmpf(size), 0x1c => SDiv(size), 0x1d => SMod(size), 0x1e => SCmp(size),
+    0x20 => Jmp(addr), 0x21 => Jz(size),
+    0x28 => Call(addr), 0x29 => Ret, 0x2a => Enter(size), 0x2b => Leave,
+    0x2c => LoadLocal { offset, size }, 0x2d => StoreLocal { offset, size },
+    0x2e => CallHost(id),
+    0x30 => Read, 0x31 => Write, 0x32 => Open, 0x33 => Close, 0x34 => Seek, 0x35 => Stat,
+    0x40 => Push(value), 0x41 => Pop(count), 0x42 => PushImm { size, value }, 0x43 => PushImmf { size, bits },
+    0x44 => PushConst(index),
+    0x48 => Load { size }, 0x49 => Store { size },
+    0x50 => PushSP, 0x51 => PushIP, 0x52 => PushFP, 0x53 => PushMaxHeapSize,
+    0x60 => AddChecked(size), 0x61 => SAddChecked(size), 0x62 => SubChecked(size), 0x63 => SSubChecked(size),
+    0x64 => MulChecked(size), 0x65 => SMulChecked(size), 0x66 => SDivChecked(size),
+    0x68 => AddSat(size), 0x69 => SAddSat(size), 0x6a => SubSat(size), 0x6b => SSubSat(size),
+    0x6c => MulSat(size), 0x6d => SMulSat(size), 0x6e => SDivSat(size),
+    0x70 => Dup(size), 0x71 => Swap(size), 0x72 => Over(size), 0x73 => Rot(size), 0x74 => Pick { size, depth },
+    0x78 => DupBytes(width), 0x79 => SwapBytes(width), 0x7a => OverBytes(width), 0x7b => RotBytes(width),
+    0x7c => PickBytes { width, offset },
+}
+
+impl Instruction {
+    /// decodes one instruction off the front of `bytes`. immediates are encoded as 8 bytes whatever their size, so
+    /// anything past the size is dropped here, the same as it is when they're displayed or pushed
+    pub fn decode(bytes: &mut &[u8]) -> Result<Instruction, DecodeError> {
+        Ok(match Instruction::decode_operands(bytes)? {
+            Instruction::PushImm { size, value } => Instruction::PushImm { size, value: value & size.mask() },
+            Instruction::PushImmf { size, bits } => Instruction::PushImmf { size, bits: bits & size.int_size().mask() },
+            instruction => instruction,
+        })
+    }
+}
+
+/// encodes a whole program as an instruction count followed by each instruction
+pub fn encode_program(program: &[Instruction]) -> Vec<u8> {
+    let mut out = vec![];
+    (program.len() as u64).encode(&mut out);
+    for instruction in program {
+        instruction.encode(&mut out);
+    }
+    out
+}
+
+/// decodes a program off the front of `bytes`
+pub fn decode_program(bytes: &mut &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    let count = u64::decode(bytes)?;
+    // don't trust the count for the allocation, each instruction is at least a byte
+    let mut program = Vec::with_capacity((count as usize).min(bytes.len()));
+    for _ in 0..count {
+        program.push(Instruction::decode(bytes)?);
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// one of every instruction at every size, found by decoding each opcode with each size for its operands
+    pub(crate) fn every_instruction() -> Vec<Instruction> {
+        let mut found = vec![];
+        for op in 0..=u8::MAX {
+            for from in 0..4 {
+                for to in 0..4 {
+                    let bytes = [op, from, to, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+                    if let Ok(instruction) = Instruction::decode(&mut &bytes[..]) {
+                        if !found.contains(&instruction) {
+                            found.push(instruction);
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn every_opcode_is_found() {
+        let instructions = every_instruction();
+        let mut opcodes: Vec<u8> = instructions.iter().map(Instruction::opcode).collect();
+        opcodes.dedup();
+        // one for each opcode in `encoding!`
+        assert_eq!(opcodes.len(), 80);
+        assert!(instructions.contains(&Instruction::FloatConvert { from: FloatSize::F64, to: FloatSize::F32 }));
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let mut program = every_instruction();
+        program.extend([
+            Instruction::PushImm { size: IntSize::I64, value: u64::MAX },
+            Instruction::PushImmf { size: FloatSize::F64, bits: f64::NAN.to_bits() | 1 },
+            Instruction::Jmp(-1),
+            Instruction::LoadLocal { offset: -16, size: IntSize::I32 },
+            Instruction::PickBytes { width: 8, offset: u64::MAX },
+        ]);
+        let bytes = encode_program(&program);
+        let mut rest = &bytes[..];
+        assert_eq!(decode_program(&mut rest), Ok(program));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn immediates_are_masked_to_their_size_on_decode() {
+        let mut bytes = vec![];
+        Instruction::PushImm { size: IntSize::I8, value: 0x1ff }.encode(&mut bytes);
+        Instruction::PushImmf { size: FloatSize::F32, bits: 0xdead_0000_3fc0_0000 }.encode(&mut bytes);
+        let mut rest = &bytes[..];
+        let push = Instruction::decode(&mut rest).unwrap();
+        assert_eq!(push, Instruction::PushImm { size: IntSize::I8, value: 0xff });
+        assert_eq!(push.to_string(), Instruction::PushImm { size: IntSize::I8, value: 0x1ff }.to_string());
+        assert_eq!(Instruction::decode(&mut rest), Ok(Instruction::PushImmf { size: FloatSize::F32, bits: 0x3fc0_0000 }));
+    }
+
+    #[test]
+    fn bad_bytecode_is_rejected() {
+        assert_eq!(Instruction::decode(&mut &[0xff][..]), Err(DecodeError::UnknownOpcode(0xff)));
+        assert_eq!(Instruction::decode(&mut &[][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(Instruction::decode(&mut &[0x42, 3, 1, 2][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(Instruction::decode(&mut &[0x10, 4][..]), Err(DecodeError::InvalidOperand));
+        assert_eq!(Instruction::decode(&mut &[0x11, 2][..]), Err(DecodeError::InvalidOperand));
+        // a count with nothing after it
+        assert_eq!(decode_program(&mut &[5, 0, 0, 0, 0, 0, 0, 0][..]), Err(DecodeError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,564 @@
+//! a textual assembly language for the VM
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! main:               ; labels become symbols, unless they start with `.L`
+//!     PUSHADDR msg    ; pseudo-instruction: pushes a label's value as a `PUSHD`
+//!     LOADB
+//!     JZB
+//!     PUSHW -1        ; immediates for each size, and PUSHFS/PUSHFD for floats
+//!     PUSHCONST big   ; pushes a whole constant
+//!     JMP main        ; jump and call targets can be labels or instruction indices
+//! .data               ; switch to the data section, which is loaded at address 0
+//! msg: .ascii "hi\n"
+//!     .byte 1, 2, 0xff
+//!     .half 1         ; also .word and .dword, all little endian
+//!     .zero 16
+//! .const              ; the constant pool, where each label starts a new constant and is its index
+//! big: .dword 1, 2, 3
+//! .text               ; and back to instructions
+//! ```
+//!
+//! mnemonics are the instruction name with a size suffix where it takes one: B, H, W, D for 8, 16, 32 and 64
+//! bit integers, S and D for 32 and 64 bit floats (so `ADDW`, `ADDFS`, `LDLOCD -16`). conversions take two, the size
+//! they convert from and then the size they convert to (so `SEXTBD`, `ITOFWS`, `FCVTSD`)
+//!
+//! there's no syntax for debug info, so assembled programs don't have any, and disassembling leaves it out
+
+use std::{collections::HashMap, error::Error, fmt::{Display, Write}};
+
+use super::{FloatSize, Instruction, IntSize, Program, Symbol};
+
+#[derive(Debug, Clone)]
+pub struct AsmError {
+    /// 1-based line number
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// labels starting with this aren't exported as symbols
+const LOCAL_PREFIX: &str = ".L";
+
+const INT_SIZES: [IntSize; 4] = [IntSize::I8, IntSize::I16, IntSize::I32, IntSize::I64];
+const FLOAT_SIZES: [FloatSize; 2] = [FloatSize::F32, FloatSize::F64];
+
+fn int_ops(base: &str) -> Option<fn(IntSize) -> Instruction> {
+    Some(match base {
+        "AND" => Instruction::And, "OR" => Instruction::Or, "XOR" => Instruction::Xor, "NOT" => Instruction::Not,
+        "SHL" => Instruction::Shl, "SHR" => Instruction::Shr, "USHR" => Instruction::UShr,
+        "ADD" => Instruction::Add, "SUB" => Instruction::Sub, "MUL" => Instruction::Mul,
+        "DIV" => Instruction::Div, "MOD" => Instruction::Mod, "CMP" => Instruction::Cmp, "JZ" => Instruction::Jz,
+        "SDIV" => Instruction::SDiv, "SMOD" => Instruction::SMod, "SCMP" => Instruction::SCmp,
+        "ADDCHK" => Instruction::AddChecked, "SADDCHK" => Instruction::SAddChecked, "SUBCHK" => Instruction::SubChecked,
+        "SSUBCHK" => Instruction::SSubChecked, "MULCHK" => Instruction::MulChecked, "SMULCHK" => Instruction::SMulChecked,
+        "SDIVCHK" => Instruction::SDivChecked,
+        "ADDSAT" => Instruction::AddSat, "SADDSAT" => Instruction::SAddSat, "SUBSAT" => Instruction::SubSat,
+        "SSUBSAT" => Instruction::SSubSat, "MULSAT" => Instruction::MulSat, "SMULSAT" => Instruction::SMulSat,
+        "SDIVSAT" => Instruction::SDivSat,
+        "DUP" => Instruction::Dup, "SWAP" => Instruction::Swap, "OVER" => Instruction::Over, "ROT" => Instruction::Rot,
+        "LOAD" => |size| Instruction::Load { size }, "STORE" => |size| Instruction::Store { size },
+        _ => return None,
+    })
+}
+
+fn float_ops(base: &str) -> Option<fn(FloatSize) -> Instruction> {
+    Some(match base {
+        "ADDF" => Instruction::Addf, "SUBF" => Instruction::Subf, "MULF" => Instruction::Mulf,
+        "DIVF" => Instruction::Divf, "MODF" => Instruction::Modf, "CMPF" => Instruction::Cmpf,
+        _ => return None,
+    })
+}
+
+fn local_ops(base: &str) -> Option<fn(i64, IntSize) -> Instruction> {
+    Some(match base {
+        "LDLOC" => |offset, size| Instruction::LoadLocal { offset, size },
+        "STLOC" => |offset, size| Instruction::StoreLocal { offset, size },
+        _ => return None,
+    })
+}
+
+/// a conversion's two sizes, from the end of its mnemonic
+fn two_sizes<A: Display + Copy, B: Display + Copy>(suffix: &str, from: &[A], to: &[B]) -> Option<(A, B)> {
+    from.iter().find_map(|&a| {
+        let rest = suffix.strip_prefix(&a.to_string())?;
+        to.iter().find(|b| b.to_string() == rest).map(|&b| (a, b))
+    })
+}
+
+fn conversion_op(mnemonic: &str) -> Option<Instruction> {
+    let (base, suffix) = ["ZEXT", "SEXT", "TRUNC", "ITOF", "UTOF", "FTOI", "FTOU", "FCVT"]
+        .iter()
+        .find_map(|base| mnemonic.strip_prefix(base).map(|suffix| (*base, suffix)))?;
+    Some(match base {
+        "ZEXT" => two_sizes(suffix, &INT_SIZES, &INT_SIZES).map(|(from, to)| Instruction::ZeroExtend { from, to })?,
+        "SEXT" => two_sizes(suffix, &INT_SIZES, &INT_SIZES).map(|(from, to)| Instruction::SignExtend { from, to })?,
+        "TRUNC" => two_sizes(suffix, &INT_SIZES, &INT_SIZES).map(|(from, to)| Instruction::Truncate { from, to })?,
+        "ITOF" => two_sizes(suffix, &INT_SIZES, &FLOAT_SIZES).map(|(from, to)| Instruction::IntToFloat { from, to })?,
+        "UTOF" => two_sizes(suffix, &INT_SIZES, &FLOAT_SIZES).map(|(from, to)| Instruction::UIntToFloat { from, to })?,
+        "FTOI" => two_sizes(suffix, &FLOAT_SIZES, &INT_SIZES).map(|(from, to)| Instruction::FloatToInt { from, to })?,
+        "FTOU" => two_sizes(suffix, &FLOAT_SIZES, &INT_SIZES).map(|(from, to)| Instruction::FloatToUInt { from, to })?,
+        _ => two_sizes(suffix, &FLOAT_SIZES, &FLOAT_SIZES).map(|(from, to)| Instruction::FloatConvert { from, to })?,
+    })
+}
+
+fn plain_op(mnemonic: &str) -> Option<Instruction> {
+    Some(match mnemonic {
+        "RET" => Instruction::Ret, "LEAVE" => Instruction::Leave, "READ" => Instruction::Read, "WRITE" => Instruction::Write,
+        "OPEN" => Instruction::Open, "CLOSE" => Instruction::Close, "SEEK" => Instruction::Seek, "STAT" => Instruction::Stat,
+        "PUSHSP" => Instruction::PushSP, "PUSHIP" => Instruction::PushIP, "PUSHFP" => Instruction::PushFP,
+        "PUSHMAXHEAP" => Instruction::PushMaxHeapSize,
+        _ => return None,
+    })
+}
+
+/// splits a mnemonic like `ADDW` into `ADD` and the size it names
+fn split_size<T: Display + Copy>(mnemonic: &str, sizes: &[T]) -> Option<(String, T)> {
+    sizes.iter().find_map(|size| mnemonic.strip_suffix(&size.to_string()).map(|base| (base.to_string(), *size)))
+}
+
+fn parse_number(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i128::from_str_radix(bin, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// a float's bits: written as a number, or as its raw bits in hex or binary (which is how NaNs get disassembled)
+fn parse_float(text: &str, size: FloatSize) -> Option<u64> {
+    if text.starts_with("0x") || text.starts_with("0b") {
+        let bits = u64::try_from(parse_number(text)?).ok()?;
+        return (bits <= size.int_size().mask()).then_some(bits);
+    }
+    let value: f64 = text.parse().ok()?;
+    Some(match size {
+        FloatSize::F32 => (value as f32).to_bits() as u64,
+        FloatSize::F64 => value.to_bits(),
+    })
+}
+
+fn is_label_name(text: &str) -> bool {
+    let valid = |rest: &str| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    match text.strip_prefix(LOCAL_PREFIX) {
+        Some(rest) => valid(rest),
+        None => text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && valid(text),
+    }
+}
+
+/// strips a comment, ignoring semicolons inside string literals
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).ok_or("expected a quoted string")?;
+    let mut out = vec![];
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push(b'\n'),
+            Some('t') => out.push(b'\t'),
+            Some('r') => out.push(b'\r'),
+            Some('0') => out.push(0),
+            Some('\\') => out.push(b'\\'),
+            Some('"') => out.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                out.push(u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape `\\x{hex}`"))?);
+            }
+            other => return Err(format!("invalid escape `\\{}`", other.map(String::from).unwrap_or_default())),
+        }
+    }
+    Ok(out)
+}
+
+enum Fixup {
+    Jmp,
+    Call,
+    PushAddr,
+    PushConst,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+    Const,
+}
+
+struct Assembler {
+    program: Program,
+    labels: HashMap<String, i128>,
+    // (instruction index, kind, label, line)
+    fixups: Vec<(usize, Fixup, String, usize)>,
+    section: Section,
+    line: usize,
+}
+
+pub fn assemble(text: &str) -> Result<Program, AsmError> {
+    let mut asm = Assembler { program: Program::default(), labels: HashMap::new(), fixups: vec![], section: Section::Text, line: 0 };
+
+    for (i, line) in text.lines().enumerate() {
+        asm.line = i + 1;
+        asm.parse_line(strip_comment(line).trim()).map_err(|message| AsmError { line: i + 1, message })?;
+    }
+
+    for (at, fixup, label, line) in std::mem::take(&mut asm.fixups) {
+        let value = *asm.labels.get(&label).ok_or_else(|| AsmError { line, message: format!("unknown label `{label}`") })?;
+        match fixup {
+            Fixup::Jmp => asm.program.instructions[at] = Instruction::Jmp(value as i64),
+            Fixup::Call => asm.program.instructions[at] = Instruction::Call(value as i64),
+            Fixup::PushAddr => asm.program.instructions[at] = Instruction::PushImm { size: IntSize::I64, value: value as u64 },
+            Fixup::PushConst => {
+                let index = u32::try_from(value).map_err(|_| AsmError { line, message: format!("`{label}` isn't a constant") })?;
+                asm.program.instructions[at] = Instruction::PushConst(index);
+            }
+        }
+    }
+
+    Ok(asm.program)
+}
+
+impl Assembler {
+    fn parse_line(&mut self, mut line: &str) -> Result<(), String> {
+        // leading label, if any
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if is_label_name(label) {
+                let value = match self.section {
+                    Section::Text => self.program.instructions.len(),
+                    Section::Data => self.program.data.len(),
+                    Section::Const => {
+                        self.program.constants.push(vec![]);
+                        self.program.constants.len() - 1
+                    }
+                };
+                if self.labels.insert(label.to_string(), value as i128).is_some() {
+                    return Err(format!("label `{label}` is defined more than once"));
+                }
+                if self.section == Section::Text && !label.starts_with(LOCAL_PREFIX) {
+                    self.program.symbols.push(Symbol { name: label.to_string(), index: value });
+                }
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (head, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        if head.starts_with('.') {
+            self.directive(head, rest)
+        } else if self.section != Section::Text {
+            Err("instructions can't go in the data or constant sections".to_string())
+        } else {
+            self.instruction(&head.to_ascii_uppercase(), rest)
+        }
+    }
+
+    fn directive(&mut self, name: &str, args: &str) -> Result<(), String> {
+        let width = match name {
+            ".text" => { self.section = Section::Text; return Ok(()); }
+            ".data" => { self.section = Section::Data; return Ok(()); }
+            ".const" => { self.section = Section::Const; return Ok(()); }
+            ".byte" => 1,
+            ".half" => 2,
+            ".word" => 4,
+            ".dword" => 8,
+            ".ascii" => {
+                let bytes = parse_string(args)?;
+                self.bytes(name)?.extend(bytes);
+                return Ok(());
+            }
+            ".zero" => {
+                let n = parse_number(args).and_then(|n| usize::try_from(n).ok()).ok_or("expected a byte count")?;
+                let bytes = self.bytes(name)?;
+                bytes.resize(bytes.len() + n, 0);
+                return Ok(());
+            }
+            _ => return Err(format!("unknown directive `{name}`")),
+        };
+        self.bytes(name)?;
+        for item in args.split(',') {
+            let value = parse_number(item.trim()).ok_or_else(|| format!("expected a number, got `{}`", item.trim()))?;
+            let bits = width * 8;
+            if value < -(1 << (bits - 1)) || value >= 1 << bits {
+                return Err(format!("{value} doesn't fit in {width} byte(s)"));
+            }
+            self.bytes(name)?.extend_from_slice(&(value as u64).to_le_bytes()[..width]);
+        }
+        Ok(())
+    }
+
+    /// where a data directive puts its bytes: the data section, or the constant being defined
+    fn bytes(&mut self, directive: &str) -> Result<&mut Vec<u8>, String> {
+        match self.section {
+            Section::Text => Err(format!("`{directive}` only goes in the data or constant sections")),
+            Section::Data => Ok(&mut self.program.data),
+            Section::Const => self.program.constants.last_mut().ok_or_else(|| "constants have to start with a label".to_string()),
+        }
+    }
+
+    fn operand(&self, args: &str, min: i128, max: i128) -> Result<i128, String> {
+        let value = parse_number(args).ok_or_else(|| format!("expected a number, got `{args}`"))?;
+        if value < min || value > max {
+            return Err(format!("{value} is out of range"));
+        }
+        Ok(value)
+    }
+
+    /// a jump target: either a label, which gets fixed up later, or an instruction index
+    fn target(&mut self, args: &str, fixup: Fixup) -> Result<i128, String> {
+        if is_label_name(args) {
+            self.fixups.push((self.program.instructions.len(), fixup, args.to_string(), self.line));
+            return Ok(0);
+        }
+        self.operand(args, i64::MIN as i128, i64::MAX as i128)
+    }
+
+    fn instruction(&mut self, mnemonic: &str, args: &str) -> Result<(), String> {
+        let no_operands = |i| if args.is_empty() { Ok(i) } else { Err(format!("`{mnemonic}` doesn't take operands")) };
+
+        let instruction = if let Some(i) = plain_op(mnemonic) {
+            no_operands(i)?
+        } else if let Some(i) = conversion_op(mnemonic) {
+            match i {
+                Instruction::ZeroExtend { from, to } | Instruction::SignExtend { from, to } if to.bytes() < from.bytes() => {
+                    return Err(format!("`{mnemonic}` would narrow, use `TRUNC{from}{to}`"));
+                }
+                Instruction::Truncate { from, to } if to.bytes() > from.bytes() => {
+                    return Err(format!("`{mnemonic}` would widen, use `ZEXT{from}{to}` or `SEXT{from}{to}`"));
+                }
+                _ => no_operands(i)?,
+            }
+        } else {
+            match mnemonic {
+                "JMP" => Instruction::Jmp(self.target(args, Fixup::Jmp)? as i64),
+                "CALL" => Instruction::Call(self.target(args, Fixup::Call)? as i64),
+                "CALLHOST" => Instruction::CallHost(self.operand(args, 0, u32::MAX as i128)? as u32),
+                "ENTER" => Instruction::Enter(self.operand(args, 0, u64::MAX as i128)? as u64),
+                "PUSH" => Instruction::Push(self.operand(args, i8::MIN as i128, u8::MAX as i128)? as u8),
+                "POP" => Instruction::Pop(self.operand(args, 0, usize::MAX as i128)? as usize),
+                "DUPBYTES" => Instruction::DupBytes(self.operand(args, 0, u64::MAX as i128)? as u64),
+                "SWAPBYTES" => Instruction::SwapBytes(self.operand(args, 0, u64::MAX as i128)? as u64),
+                "OVERBYTES" => Instruction::OverBytes(self.operand(args, 0, u64::MAX as i128)? as u64),
+                "ROTBYTES" => Instruction::RotBytes(self.operand(args, 0, u64::MAX as i128)? as u64),
+                "PICKBYTES" => {
+                    let (width, offset) = args.split_once(',').ok_or_else(|| format!("expected a width and an offset, got `{args}`"))?;
+                    let width = self.operand(width.trim(), 0, u64::MAX as i128)? as u64;
+                    Instruction::PickBytes { width, offset: self.operand(offset.trim(), 0, u64::MAX as i128)? as u64 }
+                }
+                "PUSHADDR" => Instruction::PushImm { size: IntSize::I64, value: self.target(args, Fixup::PushAddr)? as u64 },
+                "PUSHCONST" if is_label_name(args) => Instruction::PushConst(self.target(args, Fixup::PushConst)? as u32),
+                "PUSHCONST" => Instruction::PushConst(self.operand(args, 0, u32::MAX as i128)? as u32),
+                _ => {
+                    if let Some((_, size)) = split_size(mnemonic, &INT_SIZES).filter(|(base, _)| base == "PUSH") {
+                        let bits = size.bytes() * 8;
+                        let value = self.operand(args, -(1 << (bits - 1)), (1 << bits) - 1)?;
+                        Instruction::PushImm { size, value: value as u64 & size.mask() }
+                    } else if let Some((_, size)) = split_size(mnemonic, &FLOAT_SIZES).filter(|(base, _)| base == "PUSHF") {
+                        Instruction::PushImmf { size, bits: parse_float(args, size).ok_or_else(|| format!("expected a number, got `{args}`"))? }
+                    } else if let Some((op, size)) = split_size(mnemonic, &INT_SIZES).and_then(|(base, size)| int_ops(&base).map(|op| (op, size))) {
+                        no_operands(op(size))?
+                    } else if let Some((op, size)) = split_size(mnemonic, &FLOAT_SIZES).and_then(|(base, size)| float_ops(&base).map(|op| (op, size))) {
+                        no_operands(op(size))?
+                    } else if let Some((op, size)) = split_size(mnemonic, &INT_SIZES).and_then(|(base, size)| local_ops(&base).map(|op| (op, size))) {
+                        op(self.operand(args, i64::MIN as i128, i64::MAX as i128)? as i64, size)
+                    } else if let Some((_, size)) = split_size(mnemonic, &INT_SIZES).filter(|(base, _)| base == "PICK") {
+                        Instruction::Pick { size, depth: self.operand(args, 0, u64::MAX as i128)? as u64 }
+                    } else {
+                        return Err(format!("unknown instruction `{mnemonic}`"));
+                    }
+                }
+            }
+        };
+        self.program.instructions.push(instruction);
+        Ok(())
+    }
+}
+
+/// prints a program as assembly that reassembles to the same instructions, data, constants and symbols, but not its
+/// debug info, which assembly can't express
+pub fn disassemble(program: &Program) -> String {
+    let len = program.instructions.len();
+
+    // every symbol gets a label, and so does every jump or call target that doesn't already have one
+    let mut labels: HashMap<usize, Vec<String>> = HashMap::new();
+    for symbol in &program.symbols {
+        labels.entry(symbol.index).or_default().push(symbol.name.clone());
+    }
+    for instruction in &program.instructions {
+        if let Instruction::Jmp(target) | Instruction::Call(target) = instruction {
+            if (0..=len as i64).contains(target) {
+                labels.entry(*target as usize).or_insert_with(|| vec![format!("{LOCAL_PREFIX}{target}")]);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for index in 0..=len {
+        for label in labels.get(&index).into_iter().flatten() {
+            let _ = writeln!(out, "{label}:");
+        }
+        let Some(instruction) = program.instructions.get(index) else { break };
+        let _ = match instruction {
+            Instruction::Jmp(target) | Instruction::Call(target) if *target >= 0 && labels.contains_key(&(*target as usize)) => {
+                let mnemonic = if matches!(instruction, Instruction::Jmp(_)) { "JMP" } else { "CALL" };
+                writeln!(out, "    {mnemonic} {}", labels[&(*target as usize)][0])
+            }
+            _ => writeln!(out, "    {instruction}"),
+        };
+    }
+
+    let write_bytes = |out: &mut String, bytes: &[u8]| {
+        for chunk in bytes.chunks(16) {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{b:#04x}")).collect();
+            let _ = writeln!(out, "    .byte {}", bytes.join(", "));
+        }
+    };
+    if !program.data.is_empty() {
+        out.push_str(".data\n");
+        write_bytes(&mut out, &program.data);
+    }
+    if !program.constants.is_empty() {
+        // `PUSHCONST` is printed with indices, which is what these labels are
+        out.push_str(".const\n");
+        for (i, constant) in program.constants.iter().enumerate() {
+            let _ = writeln!(out, "{LOCAL_PREFIX}const{i}:");
+            write_bytes(&mut out, constant);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::tests::every_instruction;
+
+    fn error(text: &str) -> (usize, String) {
+        let e = assemble(text).unwrap_err();
+        (e.line, e.message)
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        // the assembler refuses conversions that go the wrong way, which nothing should emit anyway
+        let instructions: Vec<Instruction> = every_instruction().into_iter().filter(|i| match i {
+            Instruction::ZeroExtend { from, to } | Instruction::SignExtend { from, to } => to.bytes() >= from.bytes(),
+            Instruction::Truncate { from, to } => to.bytes() <= from.bytes(),
+            _ => true,
+        }).chain([
+            Instruction::PushImm { size: IntSize::I16, value: 0xffff },
+            Instruction::PushImmf { size: FloatSize::F32, bits: 0x7fc0_0001 },
+            Instruction::PushImmf { size: FloatSize::F64, bits: (-2.5f64).to_bits() },
+            Instruction::Jmp(3),
+            Instruction::Call(-1),
+            Instruction::StoreLocal { offset: -24, size: IntSize::I64 },
+        ]).collect();
+        let program = Program { instructions, ..Program::default() };
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn sections_and_symbols_round_trip() {
+        let text = "\
+main:
+    PUSHADDR msg
+    CALL helper
+    PUSHCONST big
+.Lloop:
+    JMP .Lloop
+helper:
+    RET
+.data
+msg: .ascii \"hi\\n\\x00\"
+    .half 1
+    .zero 2
+.const
+big: .dword 1, -1
+";
+        let program = assemble(text).unwrap();
+        assert_eq!(program.instructions, [
+            Instruction::PushImm { size: IntSize::I64, value: 0 },
+            Instruction::Call(4),
+            Instruction::PushConst(0),
+            Instruction::Jmp(3),
+            Instruction::Ret,
+        ]);
+        assert_eq!(program.data, b"hi\n\0\x01\0\0\0");
+        assert_eq!(program.constants, [[1, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]]);
+        assert_eq!(program.symbols.iter().map(|s| (s.name.as_str(), s.index)).collect::<Vec<_>>(), [("main", 0), ("helper", 4)]);
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn debug_info_is_left_out() {
+        let program = Program {
+            instructions: vec![Instruction::Ret],
+            debug: Some(crate::bytecode::DebugInfo { files: vec!["a.ks".to_string()], locs: vec![] }),
+            ..Program::default()
+        };
+        assert_eq!(assemble(&disassemble(&program)).unwrap().debug, None);
+    }
+
+    #[test]
+    fn negative_immediates_wrap_to_their_size() {
+        let program = assemble("PUSHB -1\nPUSHW -2147483648").unwrap();
+        assert_eq!(program.instructions, [
+            Instruction::PushImm { size: IntSize::I8, value: 0xff },
+            Instruction::PushImm { size: IntSize::I32, value: 0x8000_0000 },
+        ]);
+        assert_eq!(program.instructions[0].to_string(), "PUSHB 255");
+    }
+
+    #[test]
+    fn bad_assembly_is_rejected() {
+        assert_eq!(error("RET\nFROB"), (2, "unknown instruction `FROB`".to_string()));
+        assert_eq!(error("PUSHB 256"), (1, "256 is out of range".to_string()));
+        assert_eq!(error("PUSHB x"), (1, "expected a number, got `x`".to_string()));
+        assert_eq!(error("RET 1"), (1, "`RET` doesn't take operands".to_string()));
+        assert_eq!(error("\n\nJMP nowhere"), (3, "unknown label `nowhere`".to_string()));
+        assert_eq!(error("a:\na:"), (2, "label `a` is defined more than once".to_string()));
+        assert_eq!(error("ZEXTDB"), (1, "`ZEXTDB` would narrow, use `TRUNCDB`".to_string()));
+        assert_eq!(error(".data\nRET"), (2, "instructions can't go in the data or constant sections".to_string()));
+        assert_eq!(error(".byte 1"), (1, "`.byte` only goes in the data or constant sections".to_string()));
+        assert_eq!(error(".data\n.byte 256"), (2, "256 doesn't fit in 1 byte(s)".to_string()));
+        assert_eq!(error(".data\n.ascii \"\\q\""), (2, "invalid escape `\\q`".to_string()));
+        assert_eq!(error(".const\n.byte 1"), (2, "constants have to start with a label".to_string()));
+        assert_eq!(error(".frob"), (1, "unknown directive `.frob`".to_string()));
+    }
+}
@@ -0,0 +1,409 @@
+//! the on-disk format for compiled programs
+//!
+//! a module is the magic bytes, a little endian u16 format version, and a u32 section count, followed by
+//! that many sections. each section is a one byte ID, a u64 length, and then its contents:
+//!
+//! - code (required): a u64 instruction count, then each instruction as encoded by `Instruction::encode`
+//! - data: the raw bytes main memory starts with
+//! - symbols: a u32 count, then each symbol's u64 instruction index and string name
+//! - constants: a u32 count, then each constant's u64 length and bytes
+//! - debug: a u32 file count and each file's string path, then a u64 count of (u64 instruction index,
+//!   u32 file, u64 left, u64 right) entries
+//!
+//! strings are a u32 byte length followed by UTF-8
+//!
+//! version 2 added the constants section and the instructions from sized pushes onwards. version 1 never shipped, so
+//! only the current version is read
+
+use std::{error::Error, fmt::Display};
+
+use crate::{ast::types::Loc, source::FileId, vm::VM};
+
+use super::{decode_program, encode_program, take, DebugInfo, DecodeError, Program, Symbol};
+
+pub const MAGIC: &[u8; 4] = b"KSNK";
+pub const VERSION: u16 = 2;
+
+const SECTION_CODE: u8 = 1;
+const SECTION_DATA: u8 = 2;
+const SECTION_SYMBOLS: u8 = 3;
+const SECTION_DEBUG: u8 = 4;
+const SECTION_CONSTANTS: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Decode(DecodeError),
+    UnknownSection(u8),
+    DuplicateSection(u8),
+    MissingCode,
+    /// a section (or the module itself, if `None`) had bytes left over after its contents were read
+    TrailingBytes(Option<u8>),
+    InvalidString,
+    InvalidSymbol(String),
+    InvalidDebugInfo,
+    /// the data section doesn't fit in the main memory the VM was given
+    DataTooLarge { data: usize, memory: usize },
+}
+
+impl Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::BadMagic => write!(f, "not a kitchen-sink module"),
+            ModuleError::UnsupportedVersion(v) => write!(f, "unsupported module version {v} (expected {VERSION})"),
+            ModuleError::Decode(e) => write!(f, "{e}"),
+            ModuleError::UnknownSection(id) => write!(f, "unknown section {id}"),
+            ModuleError::DuplicateSection(id) => write!(f, "section {id} appears more than once"),
+            ModuleError::MissingCode => write!(f, "missing code section"),
+            ModuleError::TrailingBytes(Some(id)) => write!(f, "section {id} has trailing bytes"),
+            ModuleError::TrailingBytes(None) => write!(f, "trailing bytes after the last section"),
+            ModuleError::InvalidString => write!(f, "invalid UTF-8 string"),
+            ModuleError::InvalidSymbol(name) => write!(f, "symbol `{name}` points outside the program"),
+            ModuleError::InvalidDebugInfo => write!(f, "debug info points outside the program or its files"),
+            ModuleError::DataTooLarge { data, memory } => write!(f, "{data} bytes of data don't fit in {memory} bytes of memory"),
+        }
+    }
+}
+
+impl Error for ModuleError {}
+
+impl From<DecodeError> for ModuleError {
+    fn from(e: DecodeError) -> ModuleError { ModuleError::Decode(e) }
+}
+
+impl From<HeaderError> for ModuleError {
+    fn from(e: HeaderError) -> ModuleError {
+        match e {
+            HeaderError::BadMagic => ModuleError::BadMagic,
+            HeaderError::UnsupportedVersion(v) => ModuleError::UnsupportedVersion(v),
+            HeaderError::Decode(e) => ModuleError::Decode(e),
+        }
+    }
+}
+
+/// what's wrong with the magic bytes and version that modules, snapshots and recordings all start with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HeaderError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Decode(DecodeError),
+}
+
+/// the start of a file: `magic` and a little endian u16 `version`
+pub(crate) fn header(magic: &[u8; 4], version: u16) -> Vec<u8> {
+    let mut out = magic.to_vec();
+    out.extend_from_slice(&version.to_le_bytes());
+    out
+}
+
+/// reads past the `header` a file starts with, checking it's the one expected
+pub(crate) fn read_header(bytes: &mut &[u8], magic: &[u8; 4], version: u16) -> Result<(), HeaderError> {
+    if take(bytes, 4).ok() != Some(magic.as_slice()) {
+        return Err(HeaderError::BadMagic);
+    }
+    match read_u16(bytes).map_err(HeaderError::Decode)? {
+        v if v == version => Ok(()),
+        v => Err(HeaderError::UnsupportedVersion(v)),
+    }
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, v: u32) { out.extend_from_slice(&v.to_le_bytes()) }
+pub(crate) fn write_u64(out: &mut Vec<u8>, v: u64) { out.extend_from_slice(&v.to_le_bytes()) }
+
+pub(crate) fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn read_u16(bytes: &mut &[u8]) -> Result<u16, DecodeError> { Ok(u16::from_le_bytes(take(bytes, 2)?.try_into().unwrap())) }
+pub(crate) fn read_u32(bytes: &mut &[u8]) -> Result<u32, DecodeError> { Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap())) }
+pub(crate) fn read_u64(bytes: &mut &[u8]) -> Result<u64, DecodeError> { Ok(u64::from_le_bytes(take(bytes, 8)?.try_into().unwrap())) }
+
+pub(crate) fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+pub(crate) fn read_bytes<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+    let len = usize::try_from(read_u64(bytes)?).map_err(|_| DecodeError::UnexpectedEnd)?;
+    take(bytes, len)
+}
+
+pub(crate) fn read_string(bytes: &mut &[u8]) -> Result<String, ModuleError> {
+    let len = read_u32(bytes)? as usize;
+    String::from_utf8(take(bytes, len)?.to_vec()).map_err(|_| ModuleError::InvalidString)
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    write_u64(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut sections = vec![(SECTION_CODE, encode_program(&program.instructions))];
+
+    if !program.data.is_empty() {
+        sections.push((SECTION_DATA, program.data.clone()));
+    }
+
+    if !program.constants.is_empty() {
+        let mut constants = vec![];
+        write_u32(&mut constants, program.constants.len() as u32);
+        for constant in &program.constants {
+            write_bytes(&mut constants, constant);
+        }
+        sections.push((SECTION_CONSTANTS, constants));
+    }
+
+    if !program.symbols.is_empty() {
+        let mut symbols = vec![];
+        write_u32(&mut symbols, program.symbols.len() as u32);
+        for symbol in &program.symbols {
+            write_u64(&mut symbols, symbol.index as u64);
+            write_string(&mut symbols, &symbol.name);
+        }
+        sections.push((SECTION_SYMBOLS, symbols));
+    }
+
+    if let Some(debug) = &program.debug {
+        let mut out = vec![];
+        write_u32(&mut out, debug.files.len() as u32);
+        for file in &debug.files {
+            write_string(&mut out, file);
+        }
+        write_u64(&mut out, debug.locs.len() as u64);
+        for (index, loc) in &debug.locs {
+            write_u64(&mut out, *index as u64);
+            write_u32(&mut out, loc.file.0);
+            write_u64(&mut out, loc.left as u64);
+            write_u64(&mut out, loc.right as u64);
+        }
+        sections.push((SECTION_DEBUG, out));
+    }
+
+    let mut out = header(MAGIC, VERSION);
+    write_u32(&mut out, sections.len() as u32);
+    for (id, contents) in sections {
+        write_section(&mut out, id, &contents);
+    }
+    out
+}
+
+/// reads and validates a module
+pub fn read(mut bytes: &[u8]) -> Result<Program, ModuleError> {
+    read_header(&mut bytes, MAGIC, VERSION)?;
+
+    let mut code = None;
+    let mut program = Program::default();
+    let mut seen = vec![];
+    for _ in 0..read_u32(&mut bytes)? {
+        let id = take(&mut bytes, 1)?[0];
+        let len = usize::try_from(read_u64(&mut bytes)?).map_err(|_| DecodeError::UnexpectedEnd)?;
+        let mut contents = take(&mut bytes, len)?;
+        if seen.contains(&id) {
+            return Err(ModuleError::DuplicateSection(id));
+        }
+        seen.push(id);
+
+        match id {
+            SECTION_CODE => {
+                code = Some(decode_program(&mut contents)?);
+            }
+            SECTION_DATA => {
+                program.data = contents.to_vec();
+                contents = &[];
+            }
+            SECTION_CONSTANTS => {
+                for _ in 0..read_u32(&mut contents)? {
+                    program.constants.push(read_bytes(&mut contents)?.to_vec());
+                }
+            }
+            SECTION_SYMBOLS => {
+                for _ in 0..read_u32(&mut contents)? {
+                    let index = read_u64(&mut contents)? as usize;
+                    let name = read_string(&mut contents)?;
+                    program.symbols.push(Symbol { name, index });
+                }
+            }
+            SECTION_DEBUG => {
+                let mut debug = DebugInfo::default();
+                for _ in 0..read_u32(&mut contents)? {
+                    debug.files.push(read_string(&mut contents)?);
+                }
+                for _ in 0..read_u64(&mut contents)? {
+                    let index = read_u64(&mut contents)? as usize;
+                    let file = FileId(read_u32(&mut contents)?);
+                    let left = read_u64(&mut contents)? as usize;
+                    let right = read_u64(&mut contents)? as usize;
+                    debug.locs.push((index, Loc { file, left, right }));
+                }
+                program.debug = Some(debug);
+            }
+            _ => return Err(ModuleError::UnknownSection(id)),
+        }
+        if !contents.is_empty() {
+            return Err(ModuleError::TrailingBytes(Some(id)));
+        }
+    }
+    if !bytes.is_empty() {
+        return Err(ModuleError::TrailingBytes(None));
+    }
+
+    program.instructions = code.ok_or(ModuleError::MissingCode)?;
+    let len = program.instructions.len();
+    if let Some(symbol) = program.symbols.iter().find(|s| s.index > len) {
+        return Err(ModuleError::InvalidSymbol(symbol.name.clone()));
+    }
+    if let Some(debug) = &program.debug {
+        let sorted = debug.locs.windows(2).all(|w| w[0].0 <= w[1].0);
+        let in_range = debug.locs.iter().all(|(index, loc)| *index <= len && (loc.file.0 as usize) < debug.files.len() && loc.left <= loc.right);
+        if !sorted || !in_range {
+            return Err(ModuleError::InvalidDebugInfo);
+        }
+    }
+
+    Ok(program)
+}
+
+/// reads a module and sets up a VM to run it
+pub fn load(bytes: &[u8], main_memory_len: usize, stack_len: usize) -> Result<(VM, Program), ModuleError> {
+    let program = read(bytes)?;
+    let vm = VM::load(&program, main_memory_len, stack_len)
+        .map_err(|_| ModuleError::DataTooLarge { data: program.data.len(), memory: main_memory_len })?;
+    Ok((vm, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bytecode::{tests::every_instruction, Instruction},
+        compiler::{codegen, syntaxes::{ast_syntax::AstSyntax, Syntax}, typeck},
+        source::SourceMap,
+    };
+
+    fn program() -> Program {
+        Program {
+            instructions: every_instruction(),
+            data: b"hello".to_vec(),
+            constants: vec![vec![1, 2, 3], vec![]],
+            symbols: vec![Symbol { name: "main".to_string(), index: 0 }, Symbol { name: "end".to_string(), index: every_instruction().len() }],
+            debug: Some(DebugInfo {
+                files: vec!["a.ks".to_string(), "b.ks".to_string()],
+                locs: vec![(0, Loc { file: FileId(1), left: 3, right: 9 }), (4, Loc { file: FileId(0), left: 0, right: 0 })],
+            }),
+        }
+    }
+
+    /// a module with the given sections, each already encoded
+    fn module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut out = header(MAGIC, VERSION);
+        write_u32(&mut out, sections.len() as u32);
+        for (id, contents) in sections {
+            write_section(&mut out, *id, contents);
+        }
+        out
+    }
+
+    #[test]
+    fn modules_round_trip() {
+        assert_eq!(read(&write(&program())), Ok(program()));
+        let empty = Program::default();
+        assert_eq!(read(&write(&empty)), Ok(empty));
+    }
+
+    #[test]
+    fn compiled_programs_round_trip_with_their_debug_info() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("main.ks", "fun main -> i64 () { (return (+ (1) (2))) }");
+        let parsed = AstSyntax::parse(file, &sources.get(file).text).unwrap();
+        let types = typeck::check(&parsed).unwrap();
+        let program = codegen::compile(&parsed, &types, &sources, codegen::Overflow::Wrap).unwrap();
+        assert!(!program.debug.as_ref().unwrap().locs.is_empty());
+        assert_eq!(read(&write(&program)), Ok(program));
+    }
+
+    #[test]
+    fn data_has_to_fit_in_memory() {
+        let bytes = write(&program());
+        let (vm, loaded) = load(&bytes, 5, 256).unwrap();
+        assert_eq!((vm.get_bytes(0, 5).unwrap(), loaded), (&b"hello"[..], program()));
+        assert_eq!(load(&bytes, 4, 256).err(), Some(ModuleError::DataTooLarge { data: 5, memory: 4 }));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let good = write(&program());
+        assert_eq!(read(b"KSN"), Err(ModuleError::BadMagic));
+        assert_eq!(read(b"ELF\x7f\x01\x00"), Err(ModuleError::BadMagic));
+        let mut future = good.clone();
+        future[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(read(&future), Err(ModuleError::UnsupportedVersion(VERSION + 1)));
+        for old in [0u16, 1] {
+            let mut bytes = good.clone();
+            bytes[4..6].copy_from_slice(&old.to_le_bytes());
+            assert_eq!(read(&bytes), Err(ModuleError::UnsupportedVersion(old)));
+        }
+        assert_eq!(read(&good[..good.len() - 1]), Err(ModuleError::Decode(DecodeError::UnexpectedEnd)));
+        let mut trailing = good.clone();
+        trailing.push(0);
+        assert_eq!(read(&trailing), Err(ModuleError::TrailingBytes(None)));
+    }
+
+    #[test]
+    fn bad_sections_are_rejected() {
+        let code = encode_program(&[Instruction::Ret]);
+        assert_eq!(read(&module(&[])), Err(ModuleError::MissingCode));
+        assert_eq!(read(&module(&[(SECTION_CODE, &code), (SECTION_CODE, &code)])), Err(ModuleError::DuplicateSection(SECTION_CODE)));
+        assert_eq!(read(&module(&[(SECTION_CODE, &code), (99, &[])])), Err(ModuleError::UnknownSection(99)));
+        let mut long_code = code.clone();
+        long_code.push(0);
+        assert_eq!(read(&module(&[(SECTION_CODE, &long_code)])), Err(ModuleError::TrailingBytes(Some(SECTION_CODE))));
+        assert_eq!(read(&module(&[(SECTION_CODE, &[1, 0, 0, 0, 0, 0, 0, 0, 0xff])])), Err(ModuleError::Decode(DecodeError::UnknownOpcode(0xff))));
+    }
+
+    #[test]
+    fn bad_symbols_and_debug_info_are_rejected() {
+        let code = encode_program(&[Instruction::Ret]);
+
+        let mut symbols = vec![];
+        write_u32(&mut symbols, 1);
+        write_u64(&mut symbols, 2);
+        write_string(&mut symbols, "past_the_end");
+        assert_eq!(read(&module(&[(SECTION_CODE, &code), (SECTION_SYMBOLS, &symbols)])), Err(ModuleError::InvalidSymbol("past_the_end".to_string())));
+
+        let mut not_utf8 = vec![];
+        write_u32(&mut not_utf8, 1);
+        write_u64(&mut not_utf8, 0);
+        write_u32(&mut not_utf8, 1);
+        not_utf8.push(0xff);
+        assert_eq!(read(&module(&[(SECTION_CODE, &code), (SECTION_SYMBOLS, &not_utf8)])), Err(ModuleError::InvalidString));
+
+        let debug = |files: u32, locs: &[(u64, u32, u64, u64)]| {
+            let mut out = vec![];
+            write_u32(&mut out, files);
+            for _ in 0..files {
+                write_string(&mut out, "a.ks");
+            }
+            write_u64(&mut out, locs.len() as u64);
+            for &(index, file, left, right) in locs {
+                write_u64(&mut out, index);
+                write_u32(&mut out, file);
+                write_u64(&mut out, left);
+                write_u64(&mut out, right);
+            }
+            read(&module(&[(SECTION_CODE, &code), (SECTION_DEBUG, &out)]))
+        };
+        assert!(debug(1, &[(0, 0, 1, 2), (1, 0, 2, 2)]).is_ok());
+        // no files for the loc to be in
+        assert_eq!(debug(0, &[(0, 0, 1, 2)]), Err(ModuleError::InvalidDebugInfo));
+        // past the end of the code
+        assert_eq!(debug(1, &[(2, 0, 1, 2)]), Err(ModuleError::InvalidDebugInfo));
+        // out of order
+        assert_eq!(debug(1, &[(1, 0, 1, 2), (0, 0, 1, 2)]), Err(ModuleError::InvalidDebugInfo));
+        // backwards
+        assert_eq!(debug(1, &[(0, 0, 2, 1)]), Err(ModuleError::InvalidDebugInfo));
+    }
+}
@@ -0,0 +1,402 @@
+//! checking bytecode before it runs
+//!
+//! every reachable instruction gets a stack depth in bytes, relative to the top of the stack when the function it's
+//! in was called (or when the program started), and every way of reaching an instruction has to agree on it. code is
+//! followed from instruction 0 and from the target of every `Call`, which is assumed to come back with the stack how
+//! it was, since callees leave their results in slots the caller pushed
+//!
+//! `Jz` jumps to an address it pops, so the verifier also tracks which bytes on the stack are constants from the push
+//! instructions (or `PushIP`), and the eight below the condition have to be
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    error::Error,
+    fmt::Display,
+};
+
+use super::{Instruction, IntSize, Program};
+
+/// why bytecode was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    /// an instruction pops more than its function (or frame, if it's entered one) has pushed
+    StackUnderflow { depth: u64, needed: u64 },
+    /// the stack depth or frames differ depending on how an instruction is reached
+    MergeMismatch { expected: u64, found: u64 },
+    /// a jump or call that goes before the start or past the end of the program
+    JumpOutOfRange(i64),
+    /// the eight bytes under a `Jz`'s condition aren't a constant address
+    JzWithoutAddress,
+    /// `PushConst` with an index past the end of the constant pool
+    UnknownConstant(u32),
+    /// `CallHost` with an ID the verifier doesn't know the stack effect of
+    UnknownHostFunction(u32),
+    /// a `Ret` with values left on the stack, or that isn't in a function at all
+    UnbalancedReturn { depth: u64 },
+    /// a `Ret` in code that was reached without a `Call`
+    ReturnOutsideFunction,
+    LeaveWithoutEnter,
+    /// the depth doesn't fit in a u64, which no stack can hold
+    DepthOverflow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// the instruction the problem was found at
+    pub index: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "instruction {}: ", self.index)?;
+        match &self.kind {
+            VerifyErrorKind::StackUnderflow { depth, needed } => write!(f, "pops {needed} bytes with only {depth} on the stack"),
+            VerifyErrorKind::MergeMismatch { expected, found } if expected == found => {
+                write!(f, "reached inside different frames or functions depending on how it's reached")
+            }
+            VerifyErrorKind::MergeMismatch { expected, found } => {
+                write!(f, "reached with a stack depth of {found} bytes here but {expected} elsewhere")
+            }
+            VerifyErrorKind::JumpOutOfRange(target) => write!(f, "jumps to {target}, outside the program"),
+            VerifyErrorKind::JzWithoutAddress => write!(f, "JZ's address isn't a constant pushed before its condition"),
+            VerifyErrorKind::UnknownConstant(index) => write!(f, "pushes constant {index}, which doesn't exist"),
+            VerifyErrorKind::UnknownHostFunction(id) => write!(f, "calls host function {id}, which has no known stack effect"),
+            VerifyErrorKind::UnbalancedReturn { depth } => write!(f, "returns with {depth} bytes left on the stack"),
+            VerifyErrorKind::ReturnOutsideFunction => write!(f, "returns outside of a function"),
+            VerifyErrorKind::LeaveWithoutEnter => write!(f, "leaves a frame that was never entered"),
+            VerifyErrorKind::DepthOverflow => write!(f, "the stack depth overflows"),
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// what the verifier knows about the stack before an instruction runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    depth: u64,
+    /// the depth of the frame pointer for each `Enter` we're inside, innermost last
+    frames: Vec<u64>,
+    /// whether this is code reached from a `Call`, so `Ret` has somewhere to go
+    in_function: bool,
+    /// bytes known to be constant, by how far from the bottom they are
+    known: BTreeMap<u64, u8>,
+}
+
+impl State {
+    /// pops `count` bytes, which can't go below the innermost frame's saved frame pointer
+    fn pop(&mut self, index: usize, count: u64) -> Result<(), VerifyError> {
+        let floor = self.frames.last().copied().unwrap_or(0);
+        let available = self.depth - floor;
+        if count > available {
+            return Err(VerifyError { index, kind: VerifyErrorKind::StackUnderflow { depth: available, needed: count } });
+        }
+        self.depth -= count;
+        self.known.split_off(&self.depth);
+        Ok(())
+    }
+
+    fn push(&mut self, index: usize, count: u64) -> Result<(), VerifyError> {
+        self.depth = self.depth.checked_add(count).ok_or(VerifyError { index, kind: VerifyErrorKind::DepthOverflow })?;
+        Ok(())
+    }
+
+    /// pops `pops` bytes and pushes `pushes` that aren't known, like most instructions
+    fn effect(&mut self, index: usize, pops: u64, pushes: u64) -> Result<(), VerifyError> {
+        self.pop(index, pops)?;
+        self.push(index, pushes)
+    }
+
+    fn push_known(&mut self, index: usize, bytes: &[u8]) -> Result<(), VerifyError> {
+        for &b in bytes {
+            self.known.insert(self.depth, b);
+            self.push(index, 1)?;
+        }
+        Ok(())
+    }
+
+    /// checks the top `len` bytes are above the innermost frame, returning where they start
+    fn top(&self, index: usize, len: Option<u64>) -> Result<u64, VerifyError> {
+        let floor = self.frames.last().copied().unwrap_or(0);
+        let available = self.depth - floor;
+        match len {
+            Some(len) if len <= available => Ok(self.depth - len),
+            _ => Err(VerifyError { index, kind: VerifyErrorKind::StackUnderflow { depth: available, needed: len.unwrap_or(u64::MAX) } }),
+        }
+    }
+
+    /// `VM::pick`, keeping track of which of the copied bytes are known
+    fn pick(&mut self, index: usize, width: u64, offset: u64) -> Result<(), VerifyError> {
+        let start = self.top(index, offset.checked_add(width))?;
+        let copied: Vec<(u64, u8)> = self.known.range(start..start + width).map(|(at, b)| (at - start, *b)).collect();
+        for (offset, b) in copied {
+            self.known.insert(self.depth + offset, b);
+        }
+        self.push(index, width)
+    }
+
+    /// `VM::rotate`, moving known bytes along with the item they're in
+    fn rotate(&mut self, index: usize, width: u64, items: u64) -> Result<(), VerifyError> {
+        let start = self.top(index, items.checked_mul(width))?;
+        let moved = self.known.split_off(&start);
+        for (at, b) in moved {
+            let offset = at - start;
+            let offset = if offset < width { offset + (items - 1) * width } else { offset - width };
+            self.known.insert(start + offset, b);
+        }
+        Ok(())
+    }
+
+    /// the little endian u64 right below the top `above` bytes, if it's known
+    fn known_u64(&self, above: u64) -> Option<u64> {
+        let start = self.depth.checked_sub(above + 8)?;
+        let mut bytes = [0; 8];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = *self.known.get(&(start + i as u64))?;
+        }
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
+/// checks programs before they run; host functions' stack effects have to be declared, since they can't be seen
+#[derive(Debug, Clone, Default)]
+pub struct Verifier {
+    host_functions: HashMap<u32, (u64, u64)>,
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier::default()
+    }
+
+    /// declares that `CallHost(id)` pops `pops` bytes and then pushes `pushes`
+    pub fn with_host_function(mut self, id: u32, pops: u64, pushes: u64) -> Verifier {
+        self.host_functions.insert(id, (pops, pushes));
+        self
+    }
+
+    pub fn verify(&self, program: &Program) -> Result<(), VerifyError> {
+        let (constants, program) = (&program.constants, &program.instructions);
+        let mut states: Vec<Option<State>> = vec![None; program.len()];
+        let mut queue = VecDeque::new();
+
+        // reaching the end of the program ends it, whatever's on the stack
+        let reach = |states: &mut Vec<Option<State>>, queue: &mut VecDeque<usize>, from: usize, target: i64, state: State| {
+            let out_of_range = VerifyError { index: from, kind: VerifyErrorKind::JumpOutOfRange(target) };
+            let target = usize::try_from(target).map_err(|_| out_of_range.clone())?;
+            if target == program.len() {
+                return Ok(());
+            }
+            let slot = states.get_mut(target).ok_or(out_of_range)?;
+            match slot {
+                None => *slot = Some(state),
+                Some(existing) => {
+                    if existing.depth != state.depth || existing.frames != state.frames || existing.in_function != state.in_function {
+                        return Err(VerifyError { index: target, kind: VerifyErrorKind::MergeMismatch { expected: existing.depth, found: state.depth } });
+                    }
+                    // only what's constant both ways is known, which can only shrink, so this terminates
+                    let before = existing.known.len();
+                    existing.known.retain(|at, b| state.known.get(at) == Some(b));
+                    if existing.known.len() == before {
+                        return Ok(());
+                    }
+                }
+            }
+            queue.push_back(target);
+            Ok(())
+        };
+
+        if program.is_empty() {
+            return Ok(());
+        }
+        reach(&mut states, &mut queue, 0, 0, State { depth: 0, frames: vec![], in_function: false, known: BTreeMap::new() })?;
+
+        while let Some(index) = queue.pop_front() {
+            let mut state = states[index].clone().expect("queued instructions have a state");
+            let next = index as i64 + 1;
+            match program[index] {
+                Instruction::And(s) | Instruction::Or(s) | Instruction::Xor(s) | Instruction::Shl(s) | Instruction::Shr(s)
+                | Instruction::UShr(s) | Instruction::Add(s) | Instruction::Sub(s) | Instruction::Mul(s) | Instruction::Div(s)
+                | Instruction::Mod(s) | Instruction::SDiv(s) | Instruction::SMod(s)
+                | Instruction::AddChecked(s) | Instruction::SAddChecked(s) | Instruction::SubChecked(s) | Instruction::SSubChecked(s)
+                | Instruction::MulChecked(s) | Instruction::SMulChecked(s) | Instruction::SDivChecked(s)
+                | Instruction::AddSat(s) | Instruction::SAddSat(s) | Instruction::SubSat(s) | Instruction::SSubSat(s)
+                | Instruction::MulSat(s) | Instruction::SMulSat(s) | Instruction::SDivSat(s) => state.effect(index, s.bytes() * 2, s.bytes())?,
+                Instruction::Addf(s) | Instruction::Subf(s) | Instruction::Mulf(s) | Instruction::Divf(s) | Instruction::Modf(s) => {
+                    state.effect(index, s.bytes() * 2, s.bytes())?
+                }
+                Instruction::Not(s) => state.effect(index, s.bytes(), s.bytes())?,
+                // the sizes are different types depending on the conversion, so these can't share an arm
+                Instruction::ZeroExtend { from, to } | Instruction::SignExtend { from, to } | Instruction::Truncate { from, to } => {
+                    state.effect(index, from.bytes(), to.bytes())?
+                }
+                Instruction::IntToFloat { from, to } | Instruction::UIntToFloat { from, to } => state.effect(index, from.bytes(), to.bytes())?,
+                Instruction::FloatToInt { from, to } | Instruction::FloatToUInt { from, to } => state.effect(index, from.bytes(), to.bytes())?,
+                Instruction::FloatConvert { from, to } => state.effect(index, from.bytes(), to.bytes())?,
+                Instruction::Cmp(s) | Instruction::SCmp(s) => state.effect(index, s.bytes() * 2, 8)?,
+                Instruction::Cmpf(s) => state.effect(index, s.bytes() * 2, 8)?,
+                Instruction::Jmp(target) => {
+                    reach(&mut states, &mut queue, index, target, state)?;
+                    continue;
+                }
+                Instruction::Jz(s) => {
+                    let target = state.known_u64(s.bytes()).ok_or(VerifyError { index, kind: VerifyErrorKind::JzWithoutAddress })?;
+                    state.pop(index, s.bytes() + 8)?;
+                    // anything too big for an i64 is out of range anyway
+                    reach(&mut states, &mut queue, index, i64::try_from(target).unwrap_or(-1), state.clone())?;
+                }
+                Instruction::Call(target) => {
+                    if !(0..program.len() as i64).contains(&target) {
+                        return Err(VerifyError { index, kind: VerifyErrorKind::JumpOutOfRange(target) });
+                    }
+                    let entry = State { depth: 0, frames: vec![], in_function: true, known: BTreeMap::new() };
+                    reach(&mut states, &mut queue, index, target, entry)?;
+                    // the return address has to fit
+                    state.push(index, 8)?;
+                    state.pop(index, 8)?;
+                }
+                Instruction::CallHost(id) => {
+                    let (pops, pushes) = *self.host_functions.get(&id)
+                        .ok_or(VerifyError { index, kind: VerifyErrorKind::UnknownHostFunction(id) })?;
+                    state.effect(index, pops, pushes)?;
+                }
+                Instruction::Ret => {
+                    if !state.in_function {
+                        return Err(VerifyError { index, kind: VerifyErrorKind::ReturnOutsideFunction });
+                    }
+                    if state.depth != 0 || !state.frames.is_empty() {
+                        return Err(VerifyError { index, kind: VerifyErrorKind::UnbalancedReturn { depth: state.depth } });
+                    }
+                    continue;
+                }
+                Instruction::Enter(size) => {
+                    state.push(index, 8)?;
+                    state.frames.push(state.depth);
+                    state.push(index, size)?;
+                }
+                Instruction::Leave => {
+                    let frame = *state.frames.last().ok_or(VerifyError { index, kind: VerifyErrorKind::LeaveWithoutEnter })?;
+                    state.pop(index, state.depth - frame)?;
+                    state.frames.pop();
+                    state.pop(index, 8)?;
+                }
+                Instruction::LoadLocal { size, .. } => state.push(index, size.bytes())?,
+                Instruction::StoreLocal { size, .. } => state.pop(index, size.bytes())?,
+                Instruction::Read | Instruction::Write => state.effect(index, 4 + 8 + 2, 2)?,
+                Instruction::Open => state.effect(index, 1 + 8 + 2, 4)?,
+                Instruction::Close => state.effect(index, 4, 4)?,
+                Instruction::Seek => state.effect(index, 4 + 1 + 8, 8)?,
+                Instruction::Stat => state.effect(index, 4, 8)?,
+                Instruction::Push(v) => state.push_known(index, &[v])?,
+                Instruction::PushImm { size, value } => state.push_known(index, &value.to_le_bytes()[..size.bytes() as usize])?,
+                Instruction::PushImmf { size, bits } => state.push_known(index, &bits.to_le_bytes()[..size.bytes() as usize])?,
+                Instruction::PushConst(i) => {
+                    let constant = constants.get(i as usize).ok_or(VerifyError { index, kind: VerifyErrorKind::UnknownConstant(i) })?;
+                    state.push_known(index, constant)?;
+                }
+                Instruction::Pop(n) => state.pop(index, n as u64)?,
+                Instruction::Dup(s) => state.pick(index, s.bytes(), 0)?,
+                Instruction::Swap(s) => state.rotate(index, s.bytes(), 2)?,
+                Instruction::Over(s) => state.pick(index, s.bytes(), s.bytes())?,
+                Instruction::Rot(s) => state.rotate(index, s.bytes(), 3)?,
+                Instruction::Pick { size, depth } => state.pick(index, size.bytes(), depth.saturating_mul(size.bytes()))?,
+                Instruction::DupBytes(width) => state.pick(index, width, 0)?,
+                Instruction::SwapBytes(width) => state.rotate(index, width, 2)?,
+                Instruction::OverBytes(width) => state.pick(index, width, width)?,
+                Instruction::RotBytes(width) => state.rotate(index, width, 3)?,
+                Instruction::PickBytes { width, offset } => state.pick(index, width, offset)?,
+                Instruction::Load { size } => state.effect(index, 8, size.bytes())?,
+                Instruction::Store { size } => state.pop(index, 8 + size.bytes())?,
+                // the program counter has already moved on when it's pushed
+                Instruction::PushIP => state.push_known(index, &(next as u64).to_le_bytes())?,
+                Instruction::PushSP | Instruction::PushFP | Instruction::PushMaxHeapSize => state.push(index, IntSize::I64.bytes())?,
+            }
+            reach(&mut states, &mut queue, index, next, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// verifies a program that doesn't call any host functions
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    Verifier::new().verify(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{asm::assemble, tests::every_instruction};
+
+    fn error(text: &str) -> VerifyError {
+        verify(&assemble(text).unwrap()).unwrap_err()
+    }
+
+    fn at(index: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError { index, kind }
+    }
+
+    #[test]
+    fn accepts_the_benchmarks() {
+        for text in [include_str!("../../benches/fib.kasm"), include_str!("../../benches/loop.kasm"), include_str!("../../benches/newton.kasm"), include_str!("../../benches/sieve.kasm")] {
+            verify(&assemble(text).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn any_single_instruction_is_either_accepted_or_rejected() {
+        for instruction in every_instruction() {
+            let _ = verify(&Program { instructions: vec![instruction], ..Program::default() });
+        }
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        assert_eq!(error("POP 1\n"), at(0, VerifyErrorKind::StackUnderflow { depth: 0, needed: 1 }));
+        assert_eq!(error("PUSHB 1\nADDW\n"), at(1, VerifyErrorKind::StackUnderflow { depth: 1, needed: 8 }));
+        // a frame's saved frame pointer can't be popped, or anything under it
+        assert_eq!(error("PUSHB 1\nENTER 0\nPOP 1\n"), at(2, VerifyErrorKind::StackUnderflow { depth: 0, needed: 1 }));
+    }
+
+    #[test]
+    fn rejects_inconsistent_depths_and_bad_jumps() {
+        let text = "PUSHADDR .Ljoin\nPUSHB 0\nJZB\nPUSHB 1\n.Ljoin:\nPUSHB 2\n";
+        assert_eq!(error(text), at(4, VerifyErrorKind::MergeMismatch { expected: 0, found: 1 }));
+        assert_eq!(error("JMP 2\n"), at(0, VerifyErrorKind::JumpOutOfRange(2)));
+        assert_eq!(error("CALL 1\n"), at(0, VerifyErrorKind::JumpOutOfRange(1)));
+        assert_eq!(error("PUSHSP\nPUSHB 0\nJZB\n"), at(2, VerifyErrorKind::JzWithoutAddress));
+        // jumping to just past the end exits
+        verify(&assemble("JMP 1\n").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_returns_and_frames() {
+        assert_eq!(error("RET\n"), at(0, VerifyErrorKind::ReturnOutsideFunction));
+        assert_eq!(error("CALL f\nJMP .Lend\nf:\nPUSHB 1\nRET\n.Lend:\n"), at(3, VerifyErrorKind::UnbalancedReturn { depth: 1 }));
+        assert_eq!(error("LEAVE\n"), at(0, VerifyErrorKind::LeaveWithoutEnter));
+        verify(&assemble("CALL f\nJMP .Lend\nf:\nENTER 16\nLEAVE\nRET\n.Lend:\n").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn host_functions_and_constants_have_to_be_known() {
+        assert_eq!(error("CALLHOST 1\n"), at(0, VerifyErrorKind::UnknownHostFunction(1)));
+        let program = assemble("PUSHB 0\nCALLHOST 1\nPOP 2\n").unwrap();
+        Verifier::new().with_host_function(1, 1, 2).verify(&program).unwrap();
+        assert_eq!(Verifier::new().with_host_function(1, 2, 0).verify(&program).unwrap_err(), at(1, VerifyErrorKind::StackUnderflow { depth: 1, needed: 2 }));
+
+        let program = Program { instructions: vec![Instruction::PushConst(0)], ..Program::default() };
+        assert_eq!(verify(&program).unwrap_err(), at(0, VerifyErrorKind::UnknownConstant(0)));
+        // constants are known bytes, so they can be jump addresses
+        let program = Program {
+            instructions: vec![Instruction::PushConst(0), Instruction::Jz(IntSize::I8)],
+            constants: vec![vec![2, 0, 0, 0, 0, 0, 0, 0, 0]],
+            ..Program::default()
+        };
+        verify(&program).unwrap();
+    }
+
+    #[test]
+    fn errors_say_where_they_are() {
+        assert_eq!(error("PUSHB 1\nPOP 2\n").to_string(), "instruction 1: pops 2 bytes with only 1 on the stack");
+    }
+}
@@ -1,6 +1,8 @@
 pub mod syntaxes;
+pub mod codegen;
+pub mod typeck;
 
-use crate::bytecode::{IntSize, FloatSize};
+use crate::{ast::{types::OpTag, Tpe}, bytecode::{FloatSize, Instruction, IntSize}};
 
 // TODO: display implementation
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -11,21 +13,117 @@ pub enum PrimitiveType {
     Bool
 }
 
+impl PrimitiveType {
+    /// looks up a builtin type name like `i32`, `u8`, `f64` or `bool`
+    pub fn from_name(name: &str) -> Option<PrimitiveType> {
+        Some(match name {
+            "i8" => PrimitiveType::Integer { signed: true, size: IntSize::I8 },
+            "i16" => PrimitiveType::Integer { signed: true, size: IntSize::I16 },
+            "i32" => PrimitiveType::Integer { signed: true, size: IntSize::I32 },
+            "i64" => PrimitiveType::Integer { signed: true, size: IntSize::I64 },
+            "u8" => PrimitiveType::Integer { signed: false, size: IntSize::I8 },
+            "u16" => PrimitiveType::Integer { signed: false, size: IntSize::I16 },
+            "u32" => PrimitiveType::Integer { signed: false, size: IntSize::I32 },
+            "u64" => PrimitiveType::Integer { signed: false, size: IntSize::I64 },
+            "f32" => PrimitiveType::Float(FloatSize::F32),
+            "f64" => PrimitiveType::Float(FloatSize::F64),
+            "char" => PrimitiveType::Char(IntSize::I32),
+            "bool" => PrimitiveType::Bool,
+            _ => return None,
+        })
+    }
+
+    /// the integer size used to move this type around on the stack
+    pub fn int_size(self) -> IntSize {
+        match self {
+            PrimitiveType::Integer { size, .. } => size,
+            PrimitiveType::Float(size) => size.int_size(),
+            PrimitiveType::Char(size) => size,
+            PrimitiveType::Bool => IntSize::I8,
+        }
+    }
+
+    /// the instructions for a `Cast` from this type to `to`, or `None` if there's no such cast
+    ///
+    /// chars and bools convert like unsigned integers, but nothing converts to a bool (that's what comparisons are
+    /// for) and chars only convert to and from integers
+    pub fn conversion(self, to: PrimitiveType) -> Option<Vec<Instruction>> {
+        // (signed, size) for anything integer-like
+        let as_int = |p: PrimitiveType| match p {
+            PrimitiveType::Integer { signed, size } => Some((signed, size)),
+            PrimitiveType::Char(size) => Some((false, size)),
+            PrimitiveType::Bool => Some((false, IntSize::I8)),
+            PrimitiveType::Float(_) => None,
+        };
+        let instruction = match (self, to) {
+            _ if self == to => return Some(vec![]),
+            (_, PrimitiveType::Bool) => return None,
+            (PrimitiveType::Char(_), PrimitiveType::Float(_)) | (PrimitiveType::Float(_), PrimitiveType::Char(_)) => return None,
+            (PrimitiveType::Float(from), PrimitiveType::Float(to)) => Instruction::FloatConvert { from, to },
+            (PrimitiveType::Float(from), _) => {
+                let (signed, to) = as_int(to)?;
+                if signed { Instruction::FloatToInt { from, to } } else { Instruction::FloatToUInt { from, to } }
+            }
+            (_, PrimitiveType::Float(to)) => {
+                let (signed, from) = as_int(self)?;
+                if signed { Instruction::IntToFloat { from, to } } else { Instruction::UIntToFloat { from, to } }
+            }
+            _ => {
+                let ((signed, from), (_, to)) = (as_int(self)?, as_int(to)?);
+                match from.bytes().cmp(&to.bytes()) {
+                    // only the signedness changes, which doesn't touch the bits
+                    std::cmp::Ordering::Equal => return Some(vec![]),
+                    std::cmp::Ordering::Greater => Instruction::Truncate { from, to },
+                    std::cmp::Ordering::Less if signed => Instruction::SignExtend { from, to },
+                    std::cmp::Ordering::Less => Instruction::ZeroExtend { from, to },
+                }
+            }
+        };
+        Some(vec![instruction])
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct UUID(u64);
+pub struct Uuid(u64);
 
 pub struct StructData {
-    uuid: UUID,
-    name: String
+    pub uuid: Uuid,
+    pub name: String
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     Primitive(PrimitiveType),
+    Pointer(Box<Type>),
+    /// the type of expressions that don't produce a value
+    Unit,
+    // nothing produces these until there are structs and type parameters
     Struct {  },
     Parameter { name: String },
     Dynamic
 }
 
+impl Type {
+    /// resolves a syntactic type, returning the offending name if it doesn't refer to anything
+    pub fn from_tpe(tpe: &Tpe) -> Result<Type, &OpTag<String>> {
+        match tpe {
+            Tpe::Primitive(p) => Ok(Type::Primitive(*p)),
+            Tpe::Name(name) => PrimitiveType::from_name(name).map(Type::Primitive).ok_or(name),
+            Tpe::Pointer(inner) => Ok(Type::Pointer(Box::new(Type::from_tpe(inner)?))),
+        }
+    }
+
+    /// size of a value of this type on the stack, if it has a fixed one
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Type::Primitive(p) => Some(p.int_size().bytes()),
+            Type::Pointer(_) => Some(8),
+            Type::Unit => Some(0),
+            Type::Struct {  } | Type::Parameter { .. } | Type::Dynamic => None,
+        }
+    }
+}
+
 pub enum MemoryRepr {
     InPlace,
     Dynamic
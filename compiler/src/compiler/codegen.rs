@@ -0,0 +1,731 @@
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use crate::{
+    ast::{types::{Loc, OpTag}, Comp, Declaration, Expression, FunctionDef, Literal, MethodName, ParsedFile, Statement},
+    bytecode::{DebugInfo, Instruction, IntSize, Program, Symbol},
+    source::SourceMap,
+};
+
+use super::{typeck::{literal_value, Signature, Types}, PrimitiveType, Type};
+
+// calling convention:
+//  - the caller pushes zeroes for the return value, then each argument in order, then `Call`s
+//  - the callee `Enter`s, so right below the frame pointer is the old frame pointer, then the return address,
+//    then the arguments, then the return slot
+//  - the callee stores its result in the return slot, then `Leave`s and `Ret`s
+//  - the caller pops the arguments, leaving the return value on the stack
+const FRAME_HEADER: i64 = 16;
+
+#[derive(Debug, Clone)]
+pub struct CodegenError {
+    pub message: String,
+    pub loc: Option<Loc>,
+}
+
+impl CodegenError {
+    fn new(message: impl Into<String>, loc: Option<Loc>) -> CodegenError {
+        CodegenError { message: message.into(), loc }
+    }
+}
+
+impl Display for CodegenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for CodegenError {}
+
+type CResult<T> = Result<T, CodegenError>;
+
+/// what integer `+`, `-`, `*` and signed `/` do when the result doesn't fit in its type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// keep the low bits
+    #[default]
+    Wrap,
+    /// fault
+    Check,
+    /// clamp the result to the type's limits
+    Saturate,
+}
+
+#[derive(Clone)]
+struct Local {
+    offset: i64,
+    tpe: Type,
+}
+
+struct Frame {
+    name: String,
+    scopes: Vec<HashMap<String, Local>>,
+    size: u64,
+    ret_offset: i64,
+}
+
+impl Frame {
+    fn get(&self, name: &OpTag<String>) -> CResult<Local> {
+        self.scopes.iter().rev().find_map(|s| s.get(&name.value)).cloned().ok_or_else(|| unchecked(name.loc))
+    }
+
+    fn alloc(&mut self, bytes: u64) -> i64 {
+        let offset = self.size as i64;
+        self.size += bytes;
+        offset
+    }
+}
+
+struct Generator<'t> {
+    code: Vec<Instruction>,
+    types: &'t Types,
+    symbols: Vec<Symbol>,
+    // (instruction index, callee, call site)
+    call_fixups: Vec<(usize, String, Option<Loc>)>,
+    locs: Vec<(usize, Loc)>,
+    overflow: Overflow,
+}
+
+/// lowers a type checked file to a program for `vm::VM`, which starts by calling `main`. `sources` is where the
+/// file was loaded from, which its debug info refers to
+pub fn compile(file: &ParsedFile, types: &Types, sources: &SourceMap, overflow: Overflow) -> CResult<Program> {
+    for decl in &file.decls {
+        match decl {
+            Declaration::Func(func) => {
+                let sig = signature(types, &func.name)?;
+                if let Some(t) = sig.params.iter().chain(std::iter::once(&sig.ret)).find(|t| t.size().is_none()) {
+                    return Err(CodegenError::new(format!("functions can't take or return values of type {t:?} yet"), func.name.loc));
+                }
+            }
+        }
+    }
+
+    let mut gen = Generator { code: vec![], types, symbols: vec![], call_fixups: vec![], locs: vec![], overflow };
+
+    let main = types.signature("main").ok_or_else(|| CodegenError::new("no `main` function", None))?;
+    if !main.params.is_empty() {
+        return Err(CodegenError::new("`main` can't take parameters", None));
+    }
+
+    // prelude: call main, leaving its return value on the stack, then jump past the end of the program
+    gen.push_zeroes(main.ret.size().unwrap_or(0));
+    gen.call_fixups.push((gen.code.len(), "main".to_string(), None));
+    gen.code.push(Instruction::Call(0));
+    let exit = gen.code.len();
+    gen.code.push(Instruction::Jmp(0));
+
+    for decl in &file.decls {
+        match decl {
+            Declaration::Func(func) => gen.function(func)?,
+        }
+    }
+
+    let end = gen.code.len();
+    gen.code[exit] = Instruction::Jmp(end as i64);
+
+    for (at, callee, loc) in std::mem::take(&mut gen.call_fixups) {
+        let target = gen.symbols.iter().find(|s| s.name == callee)
+            .ok_or_else(|| CodegenError::new(format!("unknown function `{callee}`"), loc))?;
+        gen.code[at] = Instruction::Call(target.index as i64);
+    }
+
+    let debug = DebugInfo { files: sources.paths().map(String::from).collect(), locs: gen.locs };
+    Ok(Program { instructions: gen.code, data: vec![], constants: vec![], symbols: gen.symbols, debug: Some(debug) })
+}
+
+/// for anything the type checker should have rejected, so codegen doesn't have to explain it again
+fn unchecked(loc: Option<Loc>) -> CodegenError {
+    CodegenError::new("internal error: this wasn't type checked", loc)
+}
+
+fn signature<'t>(types: &'t Types, func: &OpTag<String>) -> CResult<&'t Signature> {
+    types.signature(func).ok_or_else(|| unchecked(func.loc))
+}
+
+fn int_size(tpe: &Type, loc: Option<Loc>) -> CResult<IntSize> {
+    tpe.size().and_then(IntSize::from_bytes)
+        .ok_or_else(|| CodegenError::new(format!("values of type {tpe:?} can't be moved around yet"), loc))
+}
+
+impl<'t> Generator<'t> {
+    /// attributes the instructions emitted from here on to `loc`
+    fn mark(&mut self, loc: Option<Loc>) {
+        let Some(loc) = loc else { return };
+        match self.locs.last_mut() {
+            Some((index, last)) if *index == self.code.len() => *last = loc,
+            Some((_, last)) if *last == loc => {}
+            _ => self.locs.push((self.code.len(), loc)),
+        }
+    }
+
+    fn push_const(&mut self, size: IntSize, value: u64) {
+        self.code.push(Instruction::PushImm { size, value });
+    }
+
+    /// pushes `count` zero bytes, as few at a time as possible
+    fn push_zeroes(&mut self, mut count: u64) {
+        for size in [IntSize::I64, IntSize::I32, IntSize::I16, IntSize::I8] {
+            while count >= size.bytes() {
+                self.push_const(size, 0);
+                count -= size.bytes();
+            }
+        }
+    }
+
+    /// pushes an address that gets filled in later with `patch_addr`
+    fn push_addr_placeholder(&mut self) -> usize {
+        let at = self.code.len();
+        self.push_const(IntSize::I64, 0);
+        at
+    }
+
+    fn patch_addr(&mut self, at: usize, target: usize) {
+        self.code[at] = Instruction::PushImm { size: IntSize::I64, value: target as u64 };
+    }
+
+    fn load_local(&mut self, local: &Local, loc: Option<Loc>) -> CResult<()> {
+        if local.tpe != Type::Unit {
+            let size = int_size(&local.tpe, loc)?;
+            self.code.push(Instruction::LoadLocal { offset: local.offset, size });
+        }
+        Ok(())
+    }
+
+    fn store_local(&mut self, local: &Local, loc: Option<Loc>) -> CResult<()> {
+        if local.tpe != Type::Unit {
+            let size = int_size(&local.tpe, loc)?;
+            self.code.push(Instruction::StoreLocal { offset: local.offset, size });
+        }
+        Ok(())
+    }
+
+    /// pushes the address of a frame slot
+    fn local_address(&mut self, offset: i64) {
+        self.code.push(Instruction::PushFP);
+        if offset != 0 {
+            self.push_const(IntSize::I64, offset.unsigned_abs());
+            self.code.push(if offset > 0 { Instruction::Add(IntSize::I64) } else { Instruction::Sub(IntSize::I64) });
+        }
+    }
+
+    fn function(&mut self, func: &FunctionDef) -> CResult<()> {
+        self.symbols.push(Symbol { name: func.name.value.clone(), index: self.code.len() });
+        self.mark(func.name.loc);
+
+        let sig = signature(self.types, &func.name)?;
+        let args_size: u64 = sig.params.iter().map(|t| t.size().unwrap_or(0)).sum();
+        let ret_size = sig.ret.size().unwrap_or(0);
+
+        let mut params = HashMap::new();
+        let mut offset = -FRAME_HEADER - args_size as i64;
+        for ((name, _), tpe) in func.parameters.iter().zip(&sig.params) {
+            params.insert(name.value.clone(), Local { offset, tpe: tpe.clone() });
+            offset += tpe.size().unwrap_or(0) as i64;
+        }
+
+        let mut frame = Frame {
+            name: func.name.value.clone(),
+            scopes: vec![params],
+            size: 0,
+            ret_offset: -FRAME_HEADER - args_size as i64 - ret_size as i64,
+        };
+
+        let enter = self.code.len();
+        self.code.push(Instruction::Enter(0));
+        self.block(&mut frame, &func.block)?;
+        self.code.push(Instruction::Leave);
+        self.code.push(Instruction::Ret);
+        self.code[enter] = Instruction::Enter(frame.size);
+
+        Ok(())
+    }
+
+    fn block(&mut self, frame: &mut Frame, block: &[OpTag<Statement>]) -> CResult<()> {
+        frame.scopes.push(HashMap::new());
+        for stmt in block {
+            self.statement(frame, stmt)?;
+        }
+        frame.scopes.pop();
+        Ok(())
+    }
+
+    fn condition(&mut self, frame: &mut Frame, cond: &OpTag<Expression>) -> CResult<()> {
+        self.expression(frame, cond)?;
+        self.code.push(Instruction::Jz(IntSize::I8));
+        Ok(())
+    }
+
+    fn statement(&mut self, frame: &mut Frame, stmt: &OpTag<Statement>) -> CResult<()> {
+        self.mark(stmt.loc);
+        match &stmt.value {
+            Statement::ExpressionEval(e) => {
+                let tpe = self.expression(frame, e)?;
+                let size = tpe.size().unwrap_or(0);
+                if size != 0 {
+                    self.code.push(Instruction::Pop(size as usize));
+                }
+            }
+            Statement::If { condition, block, else_block } => {
+                let else_addr = self.push_addr_placeholder();
+                self.condition(frame, condition)?;
+                self.block(frame, block)?;
+                match else_block {
+                    Some(else_block) => {
+                        let skip_else = self.code.len();
+                        self.code.push(Instruction::Jmp(0));
+                        self.patch_addr(else_addr, self.code.len());
+                        self.block(frame, else_block)?;
+                        self.code[skip_else] = Instruction::Jmp(self.code.len() as i64);
+                    }
+                    None => self.patch_addr(else_addr, self.code.len()),
+                }
+            }
+            Statement::While { condition, block } => {
+                let start = self.code.len();
+                let end_addr = self.push_addr_placeholder();
+                self.condition(frame, condition)?;
+                self.block(frame, block)?;
+                self.code.push(Instruction::Jmp(start as i64));
+                self.patch_addr(end_addr, self.code.len());
+            }
+            Statement::Label(name) => {
+                let name = format!("{}.{}", frame.name, **name);
+                if self.symbols.iter().any(|s| s.name == name) {
+                    return Err(CodegenError::new(format!("label `{name}` is defined more than once"), stmt.loc));
+                }
+                self.symbols.push(Symbol { name, index: self.code.len() });
+            }
+        }
+        Ok(())
+    }
+
+    /// emits the code for an expression, returning the type the type checker gave it
+    fn expression(&mut self, frame: &mut Frame, expr: &OpTag<Expression>) -> CResult<Type> {
+        self.mark(expr.loc);
+        let tpe = self.types.of(expr).cloned().ok_or_else(|| unchecked(expr.loc))?;
+        match &expr.value {
+            Expression::Literal(lit) => self.literal(lit, &tpe, false, expr.loc)?,
+            Expression::VarAccess(name) => {
+                let local = frame.get(name)?;
+                self.load_local(&local, expr.loc)?;
+            }
+            Expression::VarDef { name, value, .. } => {
+                let tpe = self.expression(frame, value)?;
+                let size = tpe.size().ok_or_else(|| CodegenError::new(format!("can't store values of type {tpe:?} in variables yet"), expr.loc))?;
+                let local = Local { offset: frame.alloc(size), tpe };
+                self.store_local(&local, expr.loc)?;
+                frame.scopes.last_mut().unwrap().insert(name.value.clone(), local);
+            }
+            Expression::FieldAccess { .. } => return Err(unchecked(expr.loc)),
+            Expression::MethodCall { receiver, name, args, .. } => {
+                let args: Vec<&OpTag<Expression>> = receiver.iter().map(|r| &**r).chain(args.iter()).collect();
+                self.method_call(frame, name, &args, &tpe, expr.loc)?;
+            }
+        }
+        Ok(tpe)
+    }
+
+    /// pushes a literal, which the type checker gave type `tpe`
+    fn literal(&mut self, lit: &Literal, tpe: &Type, negated: bool, loc: Option<Loc>) -> CResult<()> {
+        let &Type::Primitive(prim) = tpe else { return Err(unchecked(loc)) };
+        let value = literal_value(lit, prim, negated).ok_or_else(|| unchecked(loc))?;
+        match prim {
+            PrimitiveType::Float(size) => self.code.push(Instruction::PushImmf { size, bits: value }),
+            _ => self.push_const(prim.int_size(), value),
+        }
+        Ok(())
+    }
+
+    /// emits the code for a call whose result has type `tpe`
+    fn method_call(&mut self, frame: &mut Frame, name: &OpTag<MethodName>, args: &[&OpTag<Expression>], tpe: &Type, loc: Option<Loc>) -> CResult<()> {
+        match (&name.value, args) {
+            (MethodName::Normal(func), _) => self.call(frame, func, args, loc)?,
+            (MethodName::Minus, [arg]) => {
+                // the type checker checked a negated number as one literal, which might not fit without its sign
+                if let Expression::Literal(lit) = &arg.value {
+                    if matches!(lit.value, Literal::Numeric(_)) {
+                        return self.literal(lit, tpe, true, arg.loc);
+                    }
+                }
+                self.expression(frame, arg)?;
+                match *tpe {
+                    Type::Primitive(PrimitiveType::Integer { size, .. }) if self.overflow == Overflow::Wrap => {
+                        self.code.push(Instruction::Not(size));
+                        self.push_const(size, 1);
+                        self.code.push(Instruction::Add(size));
+                    }
+                    // 0 - x, so negating the minimum (or any unsigned value but 0) overflows
+                    Type::Primitive(PrimitiveType::Integer { signed, size }) => {
+                        self.push_const(size, 0);
+                        self.code.push(Instruction::Swap(size));
+                        self.code.push(self.int_arithmetic(&MethodName::Minus, signed, size).expect("minus is arithmetic"));
+                    }
+                    Type::Primitive(PrimitiveType::Float(size)) => {
+                        let bits = size.int_size();
+                        self.push_const(bits, 1 << (bits.bytes() * 8 - 1));
+                        self.code.push(Instruction::Xor(bits));
+                    }
+                    _ => return Err(unchecked(loc)),
+                }
+            }
+            (MethodName::Plus | MethodName::Minus | MethodName::Times | MethodName::Divide | MethodName::Modulo
+                | MethodName::Comparison(_) | MethodName::BitAnd | MethodName::BitOr | MethodName::BitXor
+                | MethodName::BitShl | MethodName::BitShr | MethodName::BitUShr | MethodName::BoolXor, [left, right]) => {
+                let operands = self.expression(frame, left)?;
+                self.expression(frame, right)?;
+                self.binary_op(&name.value, &operands, loc)?;
+            }
+            (MethodName::BitNot, [arg]) => {
+                self.expression(frame, arg)?;
+                let Type::Primitive(PrimitiveType::Integer { size, .. }) = *tpe else { return Err(unchecked(loc)) };
+                self.code.push(Instruction::Not(size));
+            }
+            (MethodName::BoolNot, [arg]) => {
+                self.expression(frame, arg)?;
+                self.push_const(IntSize::I8, 1);
+                self.code.push(Instruction::Xor(IntSize::I8));
+            }
+            (MethodName::BoolAnd, [left, right]) => {
+                // a && b  =>  if a { b } else { false }
+                let false_addr = self.push_addr_placeholder();
+                self.expression(frame, left)?;
+                self.code.push(Instruction::Jz(IntSize::I8));
+                self.expression(frame, right)?;
+                let skip = self.code.len();
+                self.code.push(Instruction::Jmp(0));
+                self.patch_addr(false_addr, self.code.len());
+                self.push_const(IntSize::I8, 0);
+                self.code[skip] = Instruction::Jmp(self.code.len() as i64);
+            }
+            (MethodName::BoolOr, [left, right]) => {
+                // a || b  =>  if a { true } else { b }
+                let rhs_addr = self.push_addr_placeholder();
+                self.expression(frame, left)?;
+                self.code.push(Instruction::Jz(IntSize::I8));
+                self.push_const(IntSize::I8, 1);
+                let skip = self.code.len();
+                self.code.push(Instruction::Jmp(0));
+                self.patch_addr(rhs_addr, self.code.len());
+                self.expression(frame, right)?;
+                self.code[skip] = Instruction::Jmp(self.code.len() as i64);
+            }
+            (MethodName::Ternery, [cond, then, otherwise]) => {
+                let else_addr = self.push_addr_placeholder();
+                self.expression(frame, cond)?;
+                self.code.push(Instruction::Jz(IntSize::I8));
+                self.expression(frame, then)?;
+                let skip = self.code.len();
+                self.code.push(Instruction::Jmp(0));
+                self.patch_addr(else_addr, self.code.len());
+                self.expression(frame, otherwise)?;
+                self.code[skip] = Instruction::Jmp(self.code.len() as i64);
+            }
+            (MethodName::Dereference, [ptr]) => {
+                self.expression(frame, ptr)?;
+                let size = int_size(tpe, loc)?;
+                self.code.push(Instruction::Load { size });
+            }
+            (MethodName::Reference, [arg]) => {
+                let Expression::VarAccess(var) = &arg.value else { return Err(unchecked(arg.loc)) };
+                let local = frame.get(var)?;
+                self.local_address(local.offset);
+            }
+            (MethodName::ArrayIndex, [ptr, index]) => {
+                self.element_address(frame, ptr, index, loc)?;
+                let size = int_size(tpe, loc)?;
+                self.code.push(Instruction::Load { size });
+            }
+            (MethodName::Cast | MethodName::Bitcast, [arg]) => {
+                let from = self.expression(frame, arg)?;
+                if from != *tpe && name.value == MethodName::Cast {
+                    let (Type::Primitive(from), Type::Primitive(to)) = (&from, tpe) else { return Err(unchecked(loc)) };
+                    self.code.extend(from.conversion(*to).ok_or_else(|| unchecked(loc))?);
+                }
+            }
+            (MethodName::Return, []) => {
+                self.code.push(Instruction::Leave);
+                self.code.push(Instruction::Ret);
+            }
+            (MethodName::Return, [value]) => {
+                let tpe = self.expression(frame, value)?;
+                let slot = Local { offset: frame.ret_offset, tpe };
+                self.store_local(&slot, loc)?;
+                self.code.push(Instruction::Leave);
+                self.code.push(Instruction::Ret);
+            }
+            (MethodName::ExprAssign, [place, value]) => {
+                self.expression(frame, value)?;
+                self.store_target(frame, place)?;
+            }
+            (MethodName::ExprAssignOp(op), [place, value]) => self.assign_op(frame, op, place, value, loc)?,
+            _ => return Err(unchecked(loc)),
+        }
+        Ok(())
+    }
+
+    /// pushes the address of `ptr[index]`, returning the element type
+    fn element_address(&mut self, frame: &mut Frame, ptr: &OpTag<Expression>, index: &OpTag<Expression>, loc: Option<Loc>) -> CResult<Type> {
+        let Type::Pointer(inner) = self.expression(frame, ptr)? else { return Err(unchecked(ptr.loc)) };
+        self.expression(frame, index)?;
+        let size = inner.size().ok_or_else(|| CodegenError::new(format!("can't index into {inner:?}"), loc))?;
+        self.push_const(IntSize::I64, size);
+        self.code.push(Instruction::Mul(IntSize::I64));
+        self.code.push(Instruction::Add(IntSize::I64));
+        Ok(*inner)
+    }
+
+    /// pushes the address of an assignable expression that isn't a plain variable, returning the type stored there
+    fn place_address(&mut self, frame: &mut Frame, place: &OpTag<Expression>) -> CResult<Type> {
+        if let Expression::MethodCall { receiver, name, args, .. } = &place.value {
+            let args: Vec<&OpTag<Expression>> = receiver.iter().map(|r| &**r).chain(args.iter()).collect();
+            match (&name.value, args.as_slice()) {
+                (MethodName::Dereference, [ptr]) => {
+                    if let Type::Pointer(inner) = self.expression(frame, ptr)? {
+                        return Ok(*inner);
+                    }
+                }
+                (MethodName::ArrayIndex, [ptr, index]) => return self.element_address(frame, ptr, index, place.loc),
+                _ => {}
+            }
+        }
+        Err(unchecked(place.loc))
+    }
+
+    /// pops a value off the stack into an assignable expression
+    fn store_target(&mut self, frame: &mut Frame, place: &OpTag<Expression>) -> CResult<()> {
+        if let Expression::VarAccess(var) = &place.value {
+            let local = frame.get(var)?;
+            return self.store_local(&local, place.loc);
+        }
+        let tpe = self.place_address(frame, place)?;
+        let size = int_size(&tpe, place.loc)?;
+        self.code.push(Instruction::Store { size });
+        Ok(())
+    }
+
+    fn assign_op(&mut self, frame: &mut Frame, op: &MethodName, place: &OpTag<Expression>, value: &OpTag<Expression>, loc: Option<Loc>) -> CResult<()> {
+        if let Expression::VarAccess(var) = &place.value {
+            let local = frame.get(var)?;
+            self.load_local(&local, place.loc)?;
+            self.expression(frame, value)?;
+            self.binary_op(op, &local.tpe, loc)?;
+            return self.store_local(&local, place.loc);
+        }
+
+        // evaluate the address once, keeping it under the value until it's stored through
+        let tpe = self.place_address(frame, place)?;
+        let size = int_size(&tpe, place.loc)?;
+        self.code.push(Instruction::Dup(IntSize::I64));
+        self.code.push(Instruction::Load { size });
+        self.expression(frame, value)?;
+        self.binary_op(op, &tpe, loc)?;
+        self.code.push(Instruction::PickBytes { width: 8, offset: size.bytes() });
+        self.code.push(Instruction::Store { size });
+        self.code.push(Instruction::Pop(8));
+        Ok(())
+    }
+
+    /// emits the instructions for an operator whose operands (both of type `tpe`) are on the stack
+    fn binary_op(&mut self, op: &MethodName, tpe: &Type, loc: Option<Loc>) -> CResult<()> {
+        let Type::Primitive(prim) = *tpe else { return Err(unchecked(loc)) };
+
+        if let MethodName::Comparison(comp) = op {
+            match prim {
+                PrimitiveType::Float(size) => self.code.push(Instruction::Cmpf(size)),
+                PrimitiveType::Integer { signed: true, size } => self.code.push(Instruction::SCmp(size)),
+                _ => self.code.push(Instruction::Cmp(prim.int_size())),
+            }
+            self.comparison_result(*comp);
+            return Ok(());
+        }
+
+        if let PrimitiveType::Integer { signed, size } = prim {
+            if let Some(instruction) = self.int_arithmetic(op, signed, size) {
+                self.code.push(instruction);
+                return Ok(());
+            }
+        }
+
+        let instruction = match (op, prim) {
+            (MethodName::Plus, PrimitiveType::Float(size)) => Instruction::Addf(size),
+            (MethodName::Minus, PrimitiveType::Float(size)) => Instruction::Subf(size),
+            (MethodName::Times, PrimitiveType::Float(size)) => Instruction::Mulf(size),
+            (MethodName::Divide, PrimitiveType::Float(size)) => Instruction::Divf(size),
+            (MethodName::Modulo, PrimitiveType::Float(size)) => Instruction::Modf(size),
+            (MethodName::BitAnd, PrimitiveType::Integer { size, .. }) => Instruction::And(size),
+            (MethodName::BitOr, PrimitiveType::Integer { size, .. }) => Instruction::Or(size),
+            (MethodName::BitXor, PrimitiveType::Integer { size, .. }) => Instruction::Xor(size),
+            (MethodName::BitShl, PrimitiveType::Integer { size, .. }) => Instruction::Shl(size),
+            (MethodName::BitShr, PrimitiveType::Integer { size, .. }) => Instruction::Shr(size),
+            (MethodName::BitUShr, PrimitiveType::Integer { size, .. }) => Instruction::UShr(size),
+            (MethodName::BitAnd | MethodName::BoolAnd, PrimitiveType::Bool) => Instruction::And(IntSize::I8),
+            (MethodName::BitOr | MethodName::BoolOr, PrimitiveType::Bool) => Instruction::Or(IntSize::I8),
+            (MethodName::BitXor | MethodName::BoolXor, PrimitiveType::Bool) => Instruction::Xor(IntSize::I8),
+            _ => return Err(unchecked(loc)),
+        };
+        self.code.push(instruction);
+        Ok(())
+    }
+
+    /// the instruction for an arithmetic operator on integers, which depends on their signedness and the overflow mode
+    fn int_arithmetic(&self, op: &MethodName, signed: bool, size: IntSize) -> Option<Instruction> {
+        use Instruction::*;
+
+        let op: fn(IntSize) -> Instruction = match (op, signed, self.overflow) {
+            (MethodName::Plus, _, Overflow::Wrap) => Add,
+            (MethodName::Plus, false, Overflow::Check) => AddChecked,
+            (MethodName::Plus, true, Overflow::Check) => SAddChecked,
+            (MethodName::Plus, false, Overflow::Saturate) => AddSat,
+            (MethodName::Plus, true, Overflow::Saturate) => SAddSat,
+            (MethodName::Minus, _, Overflow::Wrap) => Sub,
+            (MethodName::Minus, false, Overflow::Check) => SubChecked,
+            (MethodName::Minus, true, Overflow::Check) => SSubChecked,
+            (MethodName::Minus, false, Overflow::Saturate) => SubSat,
+            (MethodName::Minus, true, Overflow::Saturate) => SSubSat,
+            (MethodName::Times, _, Overflow::Wrap) => Mul,
+            (MethodName::Times, false, Overflow::Check) => MulChecked,
+            (MethodName::Times, true, Overflow::Check) => SMulChecked,
+            (MethodName::Times, false, Overflow::Saturate) => MulSat,
+            (MethodName::Times, true, Overflow::Saturate) => SMulSat,
+            // unsigned division and remainders can't overflow, and neither can signed remainders
+            (MethodName::Divide, false, _) => Div,
+            (MethodName::Divide, true, Overflow::Wrap) => SDiv,
+            (MethodName::Divide, true, Overflow::Check) => SDivChecked,
+            (MethodName::Divide, true, Overflow::Saturate) => SDivSat,
+            (MethodName::Modulo, false, _) => Mod,
+            (MethodName::Modulo, true, _) => SMod,
+            _ => return None,
+        };
+        Some(op(size))
+    }
+
+    /// turns the -1/0/1 i64 left by `Cmp` into a bool
+    fn comparison_result(&mut self, comp: Comp) {
+        use Instruction::*;
+
+        // with c in {-1, 0, 1}: c >>> 63 is (c == -1), and c & 1 is (c != 0)
+        let imm = |value| PushImm { size: IntSize::I64, value };
+        let is_less = [imm(63), UShr(IntSize::I64)];
+        let is_not_eq = [imm(1), And(IntSize::I64)];
+        let invert = [imm(1), Xor(IntSize::I64)];
+        match comp {
+            Comp::LessThan => self.code.extend(is_less),
+            Comp::GreaterThanEq => { self.code.extend(is_less); self.code.extend(invert); }
+            Comp::NotEq => self.code.extend(is_not_eq),
+            Comp::Eq => { self.code.extend(is_not_eq); self.code.extend(invert); }
+            Comp::GreaterThan | Comp::LessThanEq => {
+                self.code.push(Dup(IntSize::I64));
+                self.code.extend(is_not_eq);
+                self.code.push(Swap(IntSize::I64));
+                self.code.extend(is_less);
+                self.code.push(Xor(IntSize::I64));
+                if comp == Comp::LessThanEq {
+                    self.code.extend(invert);
+                }
+            }
+        }
+        self.code.push(Truncate { from: IntSize::I64, to: IntSize::I8 });
+    }
+
+    fn call(&mut self, frame: &mut Frame, func: &OpTag<String>, args: &[&OpTag<Expression>], loc: Option<Loc>) -> CResult<()> {
+        let sig = signature(self.types, func)?;
+        self.push_zeroes(sig.ret.size().unwrap_or(0));
+        for arg in args {
+            self.expression(frame, arg)?;
+        }
+        self.call_fixups.push((self.code.len(), func.value.clone(), loc));
+        self.code.push(Instruction::Call(0));
+        let args_size: u64 = sig.params.iter().map(|t| t.size().unwrap_or(0)).sum();
+        if args_size != 0 {
+            self.code.push(Instruction::Pop(args_size as usize));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ast::build::*, bytecode::verify, compiler::typeck, vm::{FaultKind, VM}};
+
+    fn compiled(file: &ParsedFile, overflow: Overflow) -> Program {
+        let types = typeck::check(file).unwrap();
+        let program = compile(file, &types, &SourceMap::new(), overflow).unwrap();
+        verify::verify(&program).unwrap();
+        program
+    }
+
+    fn run(file: &ParsedFile, overflow: Overflow) -> Result<VM, FaultKind> {
+        let mut vm = VM::load(&compiled(file, overflow), 0, 4096).unwrap();
+        vm.run().map_err(|fault| fault.kind)?;
+        Ok(vm)
+    }
+
+    #[test]
+    fn calls_and_loops() {
+        let file = file(vec![
+            func("fib", &[("n", "i64")], Some("i64"), vec![
+                if_(op(MethodName::Comparison(Comp::LessThan), vec![var("n"), num("2")]), vec![ret(var("n"))], None),
+                ret(op(MethodName::Plus, vec![
+                    call("fib", vec![op(MethodName::Minus, vec![var("n"), num("1")])]),
+                    call("fib", vec![op(MethodName::Minus, vec![var("n"), num("2")])]),
+                ])),
+            ]),
+            func("main", &[], Some("i64"), vec![
+                eval(let_("i", None, num("0"))),
+                eval(let_("acc", None, num("0"))),
+                while_(op(MethodName::Comparison(Comp::LessThanEq), vec![var("i"), num("10")]), vec![
+                    eval(op(MethodName::ExprAssignOp(Box::new(MethodName::Plus)), vec![var("acc"), call("fib", vec![var("i")])])),
+                    eval(op(MethodName::ExprAssignOp(Box::new(MethodName::Plus)), vec![var("i"), num("1")])),
+                ]),
+                ret(op(MethodName::Minus, vec![var("acc")])),
+            ]),
+        ]);
+        assert_eq!(run(&file, Overflow::Wrap).unwrap().pop_u64().unwrap() as i64, -143);
+    }
+
+    #[test]
+    fn literals_are_pushed_at_their_inferred_size() {
+        let wraps = file(vec![func("main", &[], Some("u8"), vec![
+            eval(let_("x", Some("u8"), num("250"))),
+            eval(op(MethodName::ExprAssignOp(Box::new(MethodName::Plus)), vec![var("x"), num("10")])),
+            ret(var("x")),
+        ])]);
+        let mut vm = run(&wraps, Overflow::Wrap).unwrap();
+        assert_eq!(vm.pop_u8().unwrap(), 4);
+        assert_eq!(vm.stack_pointer(), VM::STACK_START);
+        assert_eq!(run(&wraps, Overflow::Check).err(), Some(FaultKind::Overflow));
+
+        let float = file(vec![func("main", &[], Some("f32"), vec![ret(op(MethodName::Plus, vec![num("1.5"), cast("f32", num("2"))]))])]);
+        assert_eq!(run(&float, Overflow::Wrap).unwrap().pop_f32().unwrap(), 3.5);
+    }
+
+    #[test]
+    fn negated_literals_are_pushed_whole() {
+        for (tpe, text, value) in [("i8", "128", i8::MIN as i64), ("i16", "32768", i16::MIN as i64), ("i32", "2147483648", i32::MIN as i64), ("i64", "9223372036854775808", i64::MIN)] {
+            let file = file(vec![func("main", &[], Some("i64"), vec![
+                eval(let_("x", Some(tpe), op(MethodName::Minus, vec![num(text)]))),
+                ret(cast("i64", var("x"))),
+            ])]);
+            assert_eq!(run(&file, Overflow::Check).unwrap().pop_u64().unwrap() as i64, value, "-{text} as {tpe}");
+        }
+        let float = file(vec![func("main", &[], Some("f64"), vec![ret(op(MethodName::Minus, vec![num("2.5")]))])]);
+        assert_eq!(run(&float, Overflow::Check).unwrap().pop_f64().unwrap(), -2.5);
+    }
+
+    #[test]
+    fn missing_types_are_errors_rather_than_panics() {
+        let checked = file(vec![func("main", &[], None, vec![])]);
+        let types = typeck::check(&checked).unwrap();
+        let other = file(vec![func("main", &[], None, vec![eval(num("1"))])]);
+        assert_eq!(compile(&other, &types, &SourceMap::new(), Overflow::Wrap).unwrap_err().message, "internal error: this wasn't type checked");
+        let unknown = file(vec![func("main", &[], None, vec![]), func("helper", &[], None, vec![])]);
+        assert_eq!(compile(&unknown, &types, &SourceMap::new(), Overflow::Wrap).unwrap_err().message, "internal error: this wasn't type checked");
+    }
+
+    #[test]
+    fn main_is_required() {
+        let file = file(vec![func("start", &[], None, vec![])]);
+        let types = typeck::check(&file).unwrap();
+        assert_eq!(compile(&file, &types, &SourceMap::new(), Overflow::Wrap).unwrap_err().message, "no `main` function");
+    }
+}
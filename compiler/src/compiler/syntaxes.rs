@@ -1,13 +1,20 @@
 use std::error::Error;
 
-use crate::ast::{types::Loc, ParsedFile};
+use crate::{ast::{types::Loc, ParsedFile}, source::FileId};
 
 pub mod ast_syntax;
 
 pub trait ParseError : Error {
+    /// where the error is, which is the whole of the broken declaration if there is one
     fn loc(&self) -> Loc;
+
+    /// exactly where parsing stopped making sense, if that's narrower than `loc`
+    fn at(&self) -> Option<Loc> {
+        None
+    }
 }
 
 pub trait Syntax {
-    fn parse(inp: &str) -> Result<ParsedFile, Box<dyn ParseError>>;
+    /// parses the text of a file, tagging every `Loc` with its ID in the `SourceMap`
+    fn parse(file: FileId, inp: &str) -> Result<ParsedFile, Vec<Box<dyn ParseError>>>;
 }
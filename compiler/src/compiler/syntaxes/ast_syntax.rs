@@ -1,16 +1,20 @@
-use crate::ast::{types::{OpTag, Loc, QualifiedName}, MethodName, Expression, Statement, Tpe, FunctionDef, Declaration, ParsedFile};
+use std::{error::Error, fmt::Display};
 
-use super::Syntax;
+use peg::str::LineCol;
+
+use crate::{ast::{types::{OpTag, Loc, QualifiedName}, MethodName, Expression, Statement, Tpe, FunctionDef, Declaration, ParsedFile}, source::FileId};
+
+use super::{ParseError, Syntax};
 
 peg::parser! {
-    grammar ast() for str {
+    grammar ast(file: FileId) for str {
         // stole this from uwulang, can't remember if it works
         rule ___ = [' ' | '\n']*
         rule __ = "//" [^'\n']*
         rule _ = ___ __? ___
 
         rule tag<T>(inner: rule<T>) -> OpTag<T> =
-            left:position!() item:inner() right:position!() { OpTag { value: item, loc: Some(Loc { left, right }) } }
+            left:position!() item:inner() right:position!() { OpTag { value: item, loc: Some(Loc { file, left, right }) } }
         
         rule ident() -> OpTag<String> = tag(<v:$(['a'..='z' | 'A'..='Z'] (['a'..='z' | 'A'..='Z' | '_' | '0'..='9'])*) { v.to_string() }>)
         
@@ -59,17 +63,142 @@ peg::parser! {
             func:function_def() { Declaration::Func(func) }
             // TODO: type declarations
         
-        pub rule file() -> ParsedFile =
+        // error recovery: skip a broken declaration by resyncing at the next thing that looks like the start of one.
+        // words are skipped whole, so the end of an identifier like `myfun` isn't mistaken for a `fun`
+        rule word_char() = ['a'..='z' | 'A'..='Z' | '_' | '0'..='9']
+        rule decl_start() = "fun" !word_char()
+        rule skipped() = word_char()+ / [_]
+        rule recover() -> (usize, usize) =
+            left:position!() skipped() (!decl_start() skipped())* right:position!() { (left, right) }
+
+        rule decl_or_recover() -> Result<Declaration, (usize, usize)> =
+            d:decl() { Ok(d) } /
+            left:recover() { Err(left) }
+
+        rule skip_to(start: usize) = #{|_, pos| if pos <= start { peg::RuleResult::Matched(start, ()) } else { peg::RuleResult::Failed }}
+
+        /// reparses the declaration starting at `start` on its own, to find out why it's broken
+        pub rule decl_at(start: usize) -> Declaration =
+            skip_to(start) d:decl() [_]* { d }
+
+        /// the parsed file, along with the spans of any declarations that failed to parse
+        pub rule file() -> (ParsedFile, Vec<(usize, usize)>) =
             // TODO: package, imports
-            _ decls:decl() ** _ _ { ParsedFile { package: None, imports: vec![], decls } }
+            _ items:decl_or_recover() ** _ _ {
+                let (decls, broken): (Vec<_>, Vec<_>) = items.into_iter().partition(|i| i.is_ok());
+                (
+                    ParsedFile { package: None, imports: vec![], decls: decls.into_iter().filter_map(Result::ok).collect() },
+                    broken.into_iter().filter_map(Result::err).collect()
+                )
+            }
+    }
+}
+
+#[derive(Debug)]
+pub struct AstParseError {
+    pub file: FileId,
+    pub error: peg::error::ParseError<LineCol>,
+    /// the span of the broken declaration, if the error is in one
+    pub decl: Option<(usize, usize)>,
+}
+
+impl Display for AstParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {}", self.error.expected)
+    }
+}
+
+impl Error for AstParseError {}
+
+impl AstParseError {
+    /// the tokens that would have been accepted where parsing failed
+    pub fn expected(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.error.expected.tokens()
+    }
+
+    fn point(&self) -> Loc {
+        Loc { file: self.file, left: self.error.location.offset, right: self.error.location.offset }
+    }
+}
+
+impl ParseError for AstParseError {
+    fn loc(&self) -> Loc {
+        match self.decl {
+            Some((left, right)) => Loc { file: self.file, left, right },
+            None => self.point(),
+        }
+    }
+
+    fn at(&self) -> Option<Loc> {
+        Some(self.point())
     }
 }
 
 pub struct AstSyntax;
 
 impl Syntax for AstSyntax {
-    fn parse(inp: &str) -> Result<ParsedFile, Box<dyn super::ParseError>> {
-        // TODO: defuck
-        Ok(ast::file(inp).unwrap())
+    fn parse(file: FileId, inp: &str) -> Result<ParsedFile, Vec<Box<dyn ParseError>>> {
+        let (parsed, broken) = match ast::file(inp, file) {
+            Ok(v) => v,
+            // recovery should soak up anything, but just in case
+            Err(error) => return Err(vec![Box::new(AstParseError { file, error, decl: None })]),
+        };
+        if broken.is_empty() {
+            return Ok(parsed);
+        }
+        // a declaration parses the same way on its own as it does in the file, so this always finds an error
+        Err(broken.into_iter()
+            .filter_map(|(start, end)| {
+                let error = ast::decl_at(inp, file, start).err()?;
+                // the span runs up to the next declaration, so leave out the whitespace before it
+                let end = start + inp[start..end].trim_end().len();
+                Some(Box::new(AstParseError { file, error, decl: Some((start, end)) }) as Box<dyn ParseError>)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> Vec<(Loc, Option<Loc>)> {
+        match AstSyntax::parse(FileId(0), text) {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(|e| (e.loc(), e.at())).collect(),
+        }
+    }
+
+    fn span(left: usize, right: usize) -> Loc {
+        Loc { file: FileId(0), left, right }
+    }
+
+    #[test]
+    fn parses_functions() {
+        let parsed = AstSyntax::parse(FileId(0), "fun main -> i64 () { (return (+ (1) (2))) }\nfun f (i64 a, u8 b) { (f (a) (b)) }").unwrap();
+        assert_eq!(parsed.decls.len(), 2);
+        let Declaration::Func(f) = &parsed.decls[1];
+        assert_eq!(*f.name, "f");
+        assert_eq!(f.parameters.iter().map(|(name, _)| name.value.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(f.name.loc, Some(span(48, 49)));
+    }
+
+    #[test]
+    fn reports_each_broken_declaration() {
+        let text = "fun main -> i64 () { (return (1)) }\nfun bad ( { }\nfun ok () { }\nfun worse -> () {}\n";
+        let bad = text.find("fun bad").unwrap();
+        let worse = text.find("fun worse").unwrap();
+        assert_eq!(errors(text), [
+            (span(bad, bad + "fun bad ( { }".len()), Some(span(bad + 10, bad + 10))),
+            (span(worse, worse + "fun worse -> () {}".len()), Some(span(worse + 13, worse + 13))),
+        ]);
+    }
+
+    #[test]
+    fn recovery_only_resyncs_at_whole_words() {
+        // `myfun` and `funny` both contain `fun`, but neither starts a declaration
+        let text = "fun bad ( { myfun \n funny }\nfun ok () {}";
+        assert_eq!(errors(text).len(), 1);
+        assert_eq!(errors(text)[0].0, span(0, text.find("\nfun ok").unwrap()));
     }
 }
@@ -0,0 +1,678 @@
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use crate::{
+    ast::{types::{Loc, OpTag}, Declaration, Expression, FunctionDef, Literal, MethodName, ParsedFile, Statement, Tpe},
+    bytecode::{FloatSize, IntSize},
+};
+
+use super::{PrimitiveType, Type};
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub message: String,
+    pub loc: Option<Loc>,
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for TypeError {}
+
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+/// what type checking found out about a file, for codegen to use instead of working it out again
+pub struct Types {
+    signatures: HashMap<String, Signature>,
+    // keyed by span, so a copy of the file finds the same types. no two expressions in a parsed file have the same
+    // one, and expressions without one can't be looked up
+    exprs: HashMap<Loc, Type>,
+}
+
+impl Types {
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.signatures.get(name)
+    }
+
+    /// the type of an expression in the checked file
+    pub fn of(&self, expr: &OpTag<Expression>) -> Option<&Type> {
+        self.exprs.get(&expr.loc?)
+    }
+}
+
+/// types a whole file, reporting every error found rather than stopping at the first
+pub fn check(file: &ParsedFile) -> Result<Types, Vec<TypeError>> {
+    let mut checker = Checker { signatures: HashMap::new(), exprs: HashMap::new(), errors: vec![], scopes: vec![], ret: Type::Unit };
+
+    for decl in &file.decls {
+        match decl {
+            Declaration::Func(func) => {
+                let params = func.parameters.iter().map(|(_, t)| checker.resolve(t).unwrap_or(Type::Dynamic)).collect();
+                let ret = match &func.return_tpe {
+                    Some(t) => checker.resolve(t).unwrap_or(Type::Dynamic),
+                    None => Type::Unit,
+                };
+                if checker.signatures.insert(func.name.value.clone(), Signature { params, ret }).is_some() {
+                    checker.error(format!("function `{}` is defined more than once", *func.name), func.name.loc);
+                }
+            }
+        }
+    }
+
+    for decl in &file.decls {
+        match decl {
+            Declaration::Func(func) => checker.function(func),
+        }
+    }
+
+    if checker.errors.is_empty() {
+        Ok(Types { signatures: checker.signatures, exprs: checker.exprs })
+    } else {
+        Err(checker.errors)
+    }
+}
+
+/// what a literal of type `tpe` is pushed as, or `None` if it isn't a valid one. a `negated` number is checked
+/// together with its minus sign, so the smallest signed value of each size is a valid literal
+pub fn literal_value(lit: &Literal, tpe: PrimitiveType, negated: bool) -> Option<u64> {
+    match (lit, tpe) {
+        (Literal::Numeric(text), PrimitiveType::Float(FloatSize::F32)) => {
+            text.parse::<f32>().ok().map(|v| if negated { -v } else { v }.to_bits() as u64)
+        }
+        (Literal::Numeric(text), PrimitiveType::Float(FloatSize::F64)) => text.parse::<f64>().ok().map(|v| if negated { -v } else { v }.to_bits()),
+        (Literal::Numeric(text), PrimitiveType::Integer { signed, size }) => {
+            let bits = size.bytes() * 8;
+            let (min, max) = if signed { (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1) } else { (0, (1i128 << bits) - 1) };
+            let value = text.parse::<u64>().ok()? as i128;
+            let value = if negated { -value } else { value };
+            (min..=max).contains(&value).then_some(value as u64 & size.mask())
+        }
+        _ if negated => None,
+        (Literal::Boolean(text), PrimitiveType::Bool) => match text.as_str() {
+            "true" => Some(1),
+            "false" => Some(0),
+            _ => None,
+        },
+        (Literal::Char(text), PrimitiveType::Char(_)) => {
+            let mut chars = text.trim_matches('\'').chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c as u64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// whether `expr` is a literal, possibly negated, which gets its type from whatever it's used with
+fn is_literal(expr: &Expression) -> bool {
+    match expr {
+        Expression::Literal(_) => true,
+        Expression::MethodCall { receiver: None, name, args, .. } if name.value == MethodName::Minus => {
+            matches!(args.as_slice(), [arg] if is_literal(arg))
+        }
+        _ => false,
+    }
+}
+
+/// whether running `block` always ends with a `return`
+fn returns(block: &[OpTag<Statement>]) -> bool {
+    block.iter().any(|stmt| match &stmt.value {
+        Statement::ExpressionEval(e) => matches!(&e.value, Expression::MethodCall { name, .. } if name.value == MethodName::Return),
+        Statement::If { block, else_block: Some(else_block), .. } => returns(block) && returns(else_block),
+        _ => false,
+    })
+}
+
+// `None` means the expression had an error that's already been reported, so anything depending on it
+// is skipped instead of producing a cascade of follow-up errors
+type Checked = Option<Type>;
+
+struct Checker {
+    signatures: HashMap<String, Signature>,
+    exprs: HashMap<Loc, Type>,
+    errors: Vec<TypeError>,
+    scopes: Vec<HashMap<String, Type>>,
+    ret: Type,
+}
+
+const BOOL: Type = Type::Primitive(PrimitiveType::Bool);
+
+impl Checker {
+    fn error(&mut self, message: impl Into<String>, loc: Option<Loc>) {
+        self.errors.push(TypeError { message: message.into(), loc });
+    }
+
+    fn resolve(&mut self, tpe: &OpTag<Tpe>) -> Checked {
+        match Type::from_tpe(tpe) {
+            Ok(t) => Some(t),
+            Err(name) => {
+                self.error(format!("unknown type `{}`", **name), name.loc.or(tpe.loc));
+                None
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Type> {
+        self.scopes.iter().rev().find_map(|s| s.get(name))
+    }
+
+    fn expect(&mut self, expected: &Type, found: Checked, loc: Option<Loc>) {
+        match found {
+            Some(found) if found != *expected && found != Type::Dynamic && *expected != Type::Dynamic => {
+                self.error(format!("expected {expected:?}, found {found:?}"), loc);
+            }
+            _ => {}
+        }
+    }
+
+    /// checks an expression that should have type `expected`, which is also what any literals in it become
+    fn check_as(&mut self, expected: &Type, expr: &OpTag<Expression>) {
+        let found = self.expression_expecting(expr, Some(expected));
+        self.expect(expected, found, expr.loc);
+    }
+
+    fn function(&mut self, func: &FunctionDef) {
+        let sig = self.signatures[&func.name.value].clone();
+        let mut params = HashMap::new();
+        for ((name, _), tpe) in func.parameters.iter().zip(sig.params) {
+            if params.insert(name.value.clone(), tpe).is_some() {
+                self.error(format!("parameter `{}` is declared more than once", **name), name.loc);
+            }
+        }
+        self.scopes = vec![params];
+        self.ret = sig.ret;
+        self.block(&func.block);
+        if !matches!(self.ret, Type::Unit | Type::Dynamic) && !returns(&func.block) {
+            self.error(format!("`{}` can reach its end without returning a value of type {:?}", *func.name, self.ret), func.name.loc);
+        }
+    }
+
+    fn block(&mut self, block: &[OpTag<Statement>]) {
+        self.scopes.push(HashMap::new());
+        for stmt in block {
+            self.statement(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, stmt: &OpTag<Statement>) {
+        match &stmt.value {
+            Statement::ExpressionEval(e) => { self.expression(e); }
+            Statement::If { condition, block, else_block } => {
+                self.check_as(&BOOL, condition);
+                self.block(block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            Statement::While { condition, block } => {
+                self.check_as(&BOOL, condition);
+                self.block(block);
+            }
+            Statement::Label(_) => {}
+        }
+    }
+
+    /// numbers are whichever size of number is expected, falling back to i64 and f64
+    fn literal(&mut self, lit: &OpTag<Literal>, expected: Option<&Type>, negated: bool) -> Checked {
+        let tpe = match (&lit.value, expected) {
+            (Literal::Numeric(text), Some(&Type::Primitive(PrimitiveType::Float(size)))) if text.contains('.') => PrimitiveType::Float(size),
+            (Literal::Numeric(text), _) if text.contains('.') => PrimitiveType::Float(FloatSize::F64),
+            (Literal::Numeric(_), Some(&Type::Primitive(PrimitiveType::Integer { signed, size }))) => PrimitiveType::Integer { signed, size },
+            (Literal::Numeric(_), _) => PrimitiveType::Integer { signed: true, size: IntSize::I64 },
+            (Literal::Boolean(_), _) => PrimitiveType::Bool,
+            (Literal::Char(_), _) => PrimitiveType::Char(IntSize::I32),
+            (Literal::String(_), _) => {
+                self.error("string literals aren't supported yet", lit.loc);
+                return None;
+            }
+        };
+        if literal_value(&lit.value, tpe, negated).is_none() {
+            let (Literal::Numeric(text) | Literal::Boolean(text) | Literal::Char(text) | Literal::String(text)) = &lit.value;
+            self.error(format!("`{}{}` isn't a valid {tpe:?}", if negated { "-" } else { "" }, **text), lit.loc);
+            return None;
+        }
+        Some(Type::Primitive(tpe))
+    }
+
+    fn expression(&mut self, expr: &OpTag<Expression>) -> Checked {
+        self.expression_expecting(expr, None)
+    }
+
+    /// checks an expression whose literals should become `expected` if they can, without checking the result is `expected`
+    fn expression_expecting(&mut self, expr: &OpTag<Expression>, expected: Option<&Type>) -> Checked {
+        let tpe = self.infer(expr, expected);
+        if let Some(tpe) = &tpe {
+            self.record(expr, tpe);
+        }
+        tpe
+    }
+
+    fn record(&mut self, expr: &OpTag<Expression>, tpe: &Type) {
+        if let Some(loc) = expr.loc {
+            self.exprs.insert(loc, tpe.clone());
+        }
+    }
+
+    fn infer(&mut self, expr: &OpTag<Expression>, expected: Option<&Type>) -> Checked {
+        match &expr.value {
+            Expression::Literal(lit) => self.literal(lit, expected, false),
+            Expression::VarAccess(name) => {
+                match self.lookup(name).cloned() {
+                    Some(Type::Dynamic) => None,
+                    Some(tpe) => Some(tpe),
+                    None => {
+                        self.error(format!("unknown variable `{}`", **name), expr.loc);
+                        None
+                    }
+                }
+            }
+            Expression::VarDef { name, explicit_type, value } => {
+                let tpe = match explicit_type {
+                    Some(explicit) => {
+                        let explicit = self.resolve(explicit);
+                        match &explicit {
+                            Some(explicit) => self.check_as(explicit, value),
+                            None => { self.expression(value); }
+                        }
+                        explicit
+                    }
+                    None => self.expression(value),
+                };
+                // declare it even if it's broken, so later uses don't complain about an unknown variable
+                self.scopes.last_mut().unwrap().insert(name.value.clone(), tpe.unwrap_or(Type::Dynamic));
+                Some(Type::Unit)
+            }
+            Expression::FieldAccess { left, .. } => {
+                self.expression(left);
+                self.error("field access needs struct types, which aren't supported yet", expr.loc);
+                None
+            }
+            Expression::MethodCall { receiver, name, args, type_params } => {
+                let args: Vec<&OpTag<Expression>> = receiver.iter().map(|r| &**r).chain(args.iter()).collect();
+                self.method_call(name, &args, type_params, expected, expr.loc)
+            }
+        }
+    }
+
+    fn arity(&mut self, args: &[&OpTag<Expression>], n: usize, what: &str, loc: Option<Loc>) -> bool {
+        if args.len() != n {
+            self.error(format!("{what} takes {n} argument(s), got {}", args.len()), loc);
+            for arg in args {
+                self.expression(arg);
+            }
+            return false;
+        }
+        true
+    }
+
+    fn method_call(&mut self, name: &OpTag<MethodName>, args: &[&OpTag<Expression>], type_params: &[OpTag<Tpe>], expected: Option<&Type>, loc: Option<Loc>) -> Checked {
+        match &name.value {
+            MethodName::Normal(func) => {
+                let Some(sig) = self.signatures.get(&func.value).cloned() else {
+                    self.error(format!("unknown function `{}`", **func), func.loc);
+                    for arg in args {
+                        self.expression(arg);
+                    }
+                    return None;
+                };
+                if sig.params.len() != args.len() {
+                    self.error(format!("`{}` takes {} argument(s), got {}", **func, sig.params.len(), args.len()), loc);
+                }
+                for (arg, param) in args.iter().zip(&sig.params) {
+                    self.check_as(param, arg);
+                }
+                Some(sig.ret).filter(|t| *t != Type::Dynamic)
+            }
+            MethodName::Minus if args.len() == 1 => {
+                let tpe = match &args[0].value {
+                    // checked as one literal, since the number on its own might be out of range
+                    Expression::Literal(lit) if matches!(lit.value, Literal::Numeric(_)) => {
+                        let tpe = self.literal(lit, expected, true)?;
+                        self.record(args[0], &tpe);
+                        tpe
+                    }
+                    _ => self.expression_expecting(args[0], expected)?,
+                };
+                match tpe {
+                    Type::Primitive(PrimitiveType::Integer { .. } | PrimitiveType::Float(_)) => Some(tpe),
+                    _ => {
+                        self.error(format!("can't negate {tpe:?}"), loc);
+                        None
+                    }
+                }
+            }
+            MethodName::Plus | MethodName::Minus | MethodName::Times | MethodName::Divide | MethodName::Modulo
+                | MethodName::Comparison(_) | MethodName::BitAnd | MethodName::BitOr | MethodName::BitXor
+                | MethodName::BitShl | MethodName::BitShr | MethodName::BitUShr | MethodName::BoolXor => {
+                if !self.arity(args, 2, "a binary operator", loc) {
+                    return None;
+                }
+                // comparisons are bool whatever they compare, but other operators give back their operands' type
+                let expected = if matches!(name.value, MethodName::Comparison(_)) { None } else { expected };
+                let (left, right) = self.same_types(args[0], args[1], expected);
+                let (left, right) = (left?, right?);
+                if left != right {
+                    self.error(format!("operands have different types: {left:?} and {right:?}"), loc);
+                    return None;
+                }
+                self.operator(&name.value, left, loc)
+            }
+            MethodName::BitNot => {
+                if !self.arity(args, 1, "`~`", loc) {
+                    return None;
+                }
+                let tpe = self.expression_expecting(args[0], expected)?;
+                if !matches!(tpe, Type::Primitive(PrimitiveType::Integer { .. })) {
+                    self.error(format!("can't bitwise negate {tpe:?}"), loc);
+                    return None;
+                }
+                Some(tpe)
+            }
+            MethodName::BoolNot => {
+                if self.arity(args, 1, "`!`", loc) {
+                    self.check_as(&BOOL, args[0]);
+                }
+                Some(BOOL)
+            }
+            MethodName::BoolAnd | MethodName::BoolOr => {
+                if self.arity(args, 2, "a boolean operator", loc) {
+                    for arg in args {
+                        self.check_as(&BOOL, arg);
+                    }
+                }
+                Some(BOOL)
+            }
+            MethodName::Ternery => {
+                if !self.arity(args, 3, "`?:`", loc) {
+                    return None;
+                }
+                self.check_as(&BOOL, args[0]);
+                let (then_tpe, else_tpe) = self.same_types(args[1], args[2], expected);
+                let (then_tpe, else_tpe) = (then_tpe?, else_tpe?);
+                if then_tpe != else_tpe {
+                    self.error(format!("branches have different types: {then_tpe:?} and {else_tpe:?}"), loc);
+                    return None;
+                }
+                Some(then_tpe)
+            }
+            MethodName::Dereference => {
+                if !self.arity(args, 1, "`*`", loc) {
+                    return None;
+                }
+                match self.expression(args[0])? {
+                    Type::Pointer(inner) => Some(*inner),
+                    tpe => {
+                        self.error(format!("can't dereference {tpe:?}"), loc);
+                        None
+                    }
+                }
+            }
+            MethodName::Reference => {
+                if !self.arity(args, 1, "`&`", loc) {
+                    return None;
+                }
+                if !matches!(args[0].value, Expression::VarAccess(_)) {
+                    self.error("only variables can be referenced", args[0].loc);
+                }
+                self.expression(args[0]).map(|t| Type::Pointer(Box::new(t)))
+            }
+            MethodName::ArrayIndex => {
+                if !self.arity(args, 2, "indexing", loc) {
+                    return None;
+                }
+                self.element(args[0], args[1])
+            }
+            MethodName::Cast | MethodName::Bitcast => {
+                let target = match type_params {
+                    [target] => self.resolve(target),
+                    _ => {
+                        self.error("casts take exactly one type parameter", loc);
+                        None
+                    }
+                };
+                if !self.arity(args, 1, "a cast", loc) {
+                    return target;
+                }
+                let (tpe, target) = (self.expression(args[0])?, target?);
+                let ok = tpe == target || match (&name.value, &tpe, &target) {
+                    (MethodName::Bitcast, _, _) => tpe.size().is_some() && tpe.size() == target.size(),
+                    (_, Type::Primitive(from), Type::Primitive(to)) => from.conversion(*to).is_some(),
+                    _ => false,
+                };
+                if !ok {
+                    self.error(format!("can't convert {tpe:?} to {target:?}"), loc);
+                }
+                Some(target)
+            }
+            MethodName::Return => {
+                match args {
+                    [] => {
+                        if self.ret != Type::Unit {
+                            self.error(format!("missing return value of type {:?}", self.ret), loc);
+                        }
+                    }
+                    [value] => {
+                        let ret = self.ret.clone();
+                        self.check_as(&ret, value);
+                    }
+                    _ => { self.arity(args, 1, "`return`", loc); }
+                }
+                Some(Type::Unit)
+            }
+            MethodName::ExprAssign => {
+                if self.arity(args, 2, "`=`", loc) {
+                    match self.place(args[0]) {
+                        Some(target) => self.check_as(&target, args[1]),
+                        None => { self.expression(args[1]); }
+                    }
+                }
+                Some(Type::Unit)
+            }
+            MethodName::ExprAssignOp(op) => {
+                if self.arity(args, 2, "an assignment operator", loc) {
+                    let target = self.place(args[0]);
+                    let value = self.expression_expecting(args[1], target.as_ref());
+                    if let Some(target) = target {
+                        self.expect(&target, value, args[1].loc);
+                        if let Some(result) = self.operator(op, target.clone(), loc) {
+                            if result != target {
+                                self.error(format!("{:?} can't be used as an assignment operator", **op), loc);
+                            }
+                        }
+                    }
+                }
+                Some(Type::Unit)
+            }
+        }
+    }
+
+    /// checks two expressions that should have the same type, checking a literal after the other one so it can take its type
+    fn same_types(&mut self, a: &OpTag<Expression>, b: &OpTag<Expression>, expected: Option<&Type>) -> (Checked, Checked) {
+        if is_literal(&a.value) && !is_literal(&b.value) {
+            let b_tpe = self.expression_expecting(b, expected);
+            (self.expression_expecting(a, b_tpe.as_ref().or(expected)), b_tpe)
+        } else {
+            let a_tpe = self.expression_expecting(a, expected);
+            let b_tpe = self.expression_expecting(b, a_tpe.as_ref().or(expected));
+            (a_tpe, b_tpe)
+        }
+    }
+
+    fn element(&mut self, ptr: &OpTag<Expression>, index: &OpTag<Expression>) -> Checked {
+        let tpe = self.expression(ptr);
+        let idx = self.expression_expecting(index, Some(&Type::Primitive(PrimitiveType::Integer { signed: true, size: IntSize::I64 })));
+        if let Some(idx) = idx {
+            if !matches!(idx, Type::Primitive(PrimitiveType::Integer { size: IntSize::I64, .. })) {
+                self.error(format!("indices must be 64 bit integers, got {idx:?}"), index.loc);
+            }
+        }
+        match tpe? {
+            Type::Pointer(inner) => Some(*inner),
+            tpe => {
+                self.error(format!("can't index into {tpe:?}"), ptr.loc);
+                None
+            }
+        }
+    }
+
+    /// checks an assignable expression, returning the type stored there
+    fn place(&mut self, place: &OpTag<Expression>) -> Checked {
+        if let Expression::MethodCall { name, .. } = &place.value {
+            if !matches!(name.value, MethodName::Dereference | MethodName::ArrayIndex) {
+                self.error("can't assign to this expression", place.loc);
+                return None;
+            }
+        } else if !matches!(place.value, Expression::VarAccess(_)) {
+            self.error("can't assign to this expression", place.loc);
+            return None;
+        }
+        self.expression(place)
+    }
+
+    /// the result of applying a binary operator to two operands of type `tpe`
+    fn operator(&mut self, op: &MethodName, tpe: Type, loc: Option<Loc>) -> Checked {
+        let ok = match (op, &tpe) {
+            (MethodName::Comparison(_), Type::Primitive(_)) => return Some(BOOL),
+            (MethodName::Plus | MethodName::Minus | MethodName::Times | MethodName::Divide | MethodName::Modulo,
+                Type::Primitive(PrimitiveType::Integer { .. } | PrimitiveType::Float(_))) => true,
+            (MethodName::BitAnd | MethodName::BitOr | MethodName::BitXor | MethodName::BitShl | MethodName::BitShr | MethodName::BitUShr,
+                Type::Primitive(PrimitiveType::Integer { .. })) => true,
+            (MethodName::BitAnd | MethodName::BitOr | MethodName::BitXor | MethodName::BoolAnd | MethodName::BoolOr | MethodName::BoolXor,
+                Type::Primitive(PrimitiveType::Bool)) => true,
+            _ => false,
+        };
+        if ok {
+            Some(tpe)
+        } else {
+            self.error(format!("{op:?} isn't supported for {tpe:?}"), loc);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::build::*;
+
+    const U8: Type = Type::Primitive(PrimitiveType::Integer { signed: false, size: IntSize::I8 });
+    const I64: Type = Type::Primitive(PrimitiveType::Integer { signed: true, size: IntSize::I64 });
+
+    fn errors(file: &ParsedFile) -> Vec<String> {
+        match check(file) {
+            Ok(_) => vec![],
+            Err(errors) => errors.into_iter().map(|e| e.message).collect(),
+        }
+    }
+
+    fn body(file: &ParsedFile) -> &[OpTag<Statement>] {
+        let Declaration::Func(func) = &file.decls[0];
+        &func.block
+    }
+
+    #[test]
+    fn literals_take_the_expected_type() {
+        let file = file(vec![func("main", &[], Some("u8"), vec![
+            eval(let_("x", Some("u8"), num("5"))),
+            ret(op(MethodName::Plus, vec![num("1"), var("x")])),
+        ])]);
+        let types = check(&file).unwrap();
+        let Statement::ExpressionEval(def) = &body(&file)[0].value else { unreachable!() };
+        let Expression::VarDef { value, .. } = &def.value else { unreachable!() };
+        assert_eq!(types.of(value), Some(&U8));
+        assert_eq!(types.of(def), Some(&Type::Unit));
+    }
+
+    #[test]
+    fn copies_of_the_file_have_the_same_types() {
+        let file = file(vec![func("main", &[], None, vec![eval(let_("x", Some("u8"), num("5")))])]);
+        let types = check(&file).unwrap();
+        let copy = file.clone();
+        let Statement::ExpressionEval(def) = &body(&copy)[0].value else { unreachable!() };
+        let Expression::VarDef { value, .. } = &def.value else { unreachable!() };
+        assert_eq!((types.of(def), types.of(value)), (Some(&Type::Unit), Some(&U8)));
+        assert_eq!(types.of(&OpTag { value: Expression::VarAccess(tag("x".to_string())), loc: None }), None);
+    }
+
+    #[test]
+    fn literals_default_to_i64_and_f64() {
+        let file = file(vec![func("main", &[], None, vec![eval(let_("x", None, num("5"))), eval(let_("y", None, num("0.5")))])]);
+        let types = check(&file).unwrap();
+        let literal = |i: usize| {
+            let Statement::ExpressionEval(def) = &body(&file)[i].value else { unreachable!() };
+            let Expression::VarDef { value, .. } = &def.value else { unreachable!() };
+            types.of(value).cloned()
+        };
+        assert_eq!(literal(0), Some(I64));
+        assert_eq!(literal(1), Some(Type::Primitive(PrimitiveType::Float(FloatSize::F64))));
+    }
+
+    #[test]
+    fn literals_must_fit() {
+        let file = file(vec![func("main", &[], None, vec![
+            eval(let_("x", Some("u8"), num("256"))),
+            eval(let_("y", Some("i8"), num("127"))),
+            eval(let_("z", Some("i8"), num("128"))),
+        ])]);
+        assert_eq!(errors(&file), ["`256` isn't a valid Integer { signed: false, size: I8 }", "`128` isn't a valid Integer { signed: true, size: I8 }"]);
+    }
+
+    #[test]
+    fn negated_literals_reach_the_signed_minimum() {
+        let negated = |tpe: &str, text: &str| eval(let_("x", Some(tpe), op(MethodName::Minus, vec![num(text)])));
+        let file = file(vec![func("main", &[], None, vec![
+            negated("i8", "128"),
+            negated("i16", "32768"),
+            negated("i32", "2147483648"),
+            negated("i64", "9223372036854775808"),
+            negated("i8", "129"),
+            negated("u8", "1"),
+            eval(let_("y", Some("u8"), op(MethodName::Minus, vec![num("0")]))),
+        ])]);
+        assert_eq!(errors(&file), ["`-129` isn't a valid Integer { signed: true, size: I8 }", "`-1` isn't a valid Integer { signed: false, size: I8 }"]);
+    }
+
+    #[test]
+    fn comparisons_type_literals_from_the_other_side() {
+        let file = file(vec![func("f", &[("x", "u16")], Some("bool"), vec![
+            ret(op(MethodName::Comparison(crate::ast::Comp::LessThan), vec![num("3"), var("x")])),
+        ])]);
+        assert_eq!(errors(&file), Vec::<String>::new());
+    }
+
+    #[test]
+    fn mismatches_are_still_errors() {
+        let file = file(vec![func("main", &[], None, vec![
+            eval(let_("x", Some("u8"), boolean(true))),
+            eval(let_("y", None, var("nope"))),
+            eval(call("missing", vec![])),
+        ])]);
+        assert_eq!(errors(&file), [
+            "expected Primitive(Integer { signed: false, size: I8 }), found Primitive(Bool)",
+            "unknown variable `nope`",
+            "unknown function `missing`",
+        ]);
+    }
+
+    #[test]
+    fn non_unit_functions_must_return() {
+        let falls_off = file(vec![func("f", &[("c", "bool")], Some("i64"), vec![if_(var("c"), vec![ret(num("1"))], None)])]);
+        assert_eq!(errors(&falls_off), ["`f` can reach its end without returning a value of type Primitive(Integer { signed: true, size: I64 })"]);
+
+        let both_branches = file(vec![func("f", &[("c", "bool")], Some("i64"), vec![
+            if_(var("c"), vec![ret(num("1"))], Some(vec![ret(num("2"))])),
+        ])]);
+        assert_eq!(errors(&both_branches), Vec::<String>::new());
+
+        let unit = file(vec![func("f", &[], None, vec![])]);
+        assert_eq!(errors(&unit), Vec::<String>::new());
+    }
+}
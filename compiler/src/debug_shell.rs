@@ -0,0 +1,236 @@
+//! the `debug` command: a line-based shell over `vm::debugger`
+//!
+//! commands are read from stdin, which the program being debugged shares
+
+use std::io::Write;
+
+use kitchen_sink::{
+    bytecode::Program,
+    source::{FileId, SourceMap},
+    vm::{debugger::{line_indices, Debugger, Stop}, VM},
+};
+
+const HELP: &str = "\
+commands:
+    break, b <where>         stop before running an instruction; <where> is an instruction index, a symbol,
+                             or a source line as <file>:<line> (or :<line> for the first file)
+    delete, d <index>        remove a breakpoint
+    watch, w <addr> [len]    stop when an instruction changes memory (default: 8 bytes)
+    unwatch <addr>           remove a watchpoint
+    step, s [n]              run n instructions (default: 1)
+    continue, c              run until a breakpoint, watchpoint, fault or the end of the program
+    regs, r                  show the program counter, stack pointer and frame pointer
+    stack [len]              dump the top of the stack (default: 64 bytes)
+    mem, x <addr> [len]      dump memory (default: 64 bytes)
+    list, l [n]              show the instructions around the program counter (default: 5 either side)
+    info                     list breakpoints and watchpoints
+    help, h                  show this
+    quit, q                  stop debugging";
+
+struct Shell<'a> {
+    program: &'a Program,
+    sources: SourceMap,
+    vm: VM,
+    debugger: Debugger,
+    /// set once the program ends or faults, after which it can only be inspected
+    finished: bool,
+}
+
+fn parse_u64(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn hex_dump(out: &mut String, address: u64, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+        let text: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        out.push_str(&format!("{:#018x}  {:<47}  {text}\n", address + i as u64 * 16, hex.join(" ")));
+    }
+}
+
+impl Shell<'_> {
+    /// one instruction, with its symbol and where it came from if those are known
+    fn describe(&self, index: u64) -> String {
+        let mut line = String::new();
+        if let Some(symbol) = self.program.symbols.iter().find(|s| s.index as u64 == index) {
+            line.push_str(&format!("{}:\n", symbol.name));
+        }
+        match self.vm.program().get(index as usize) {
+            Some(instruction) => line.push_str(&format!("{index:>6}  {instruction}")),
+            None => line.push_str(&format!("{index:>6}  (end of program)")),
+        }
+        let debug = self.program.debug.as_ref();
+        if let Some(loc) = debug.and_then(|d| d.loc(index as usize).filter(|loc| (loc.file.0 as usize) < d.files.len())) {
+            line.push_str(&format!("    ; {}", self.sources.describe(loc)));
+        }
+        line
+    }
+
+    /// instruction indices for a breakpoint location
+    fn resolve(&self, text: &str) -> Result<Vec<u64>, String> {
+        if let Some(index) = parse_u64(text) {
+            return Ok(vec![index]);
+        }
+        if let Some(symbol) = self.program.symbols.iter().find(|s| s.name == text) {
+            return Ok(vec![symbol.index as u64]);
+        }
+
+        let (file, line) = match text.rsplit_once(':') {
+            Some(("", line)) => (FileId(0), line),
+            Some((path, line)) => {
+                let index = self.program.debug.iter().flat_map(|d| &d.files).position(|f| f == path || f.ends_with(&format!("/{path}")));
+                (FileId(index.ok_or_else(|| format!("no source file `{path}`"))? as u32), line)
+            }
+            None => return Err(format!("`{text}` isn't an instruction index, symbol or source line")),
+        };
+        let line: usize = line.parse().map_err(|_| format!("`{line}` isn't a line number"))?;
+        let debug = self.program.debug.as_ref().ok_or("the program has no debug info, so it has no source lines")?;
+        let indices = line_indices(debug, &self.sources, file, line);
+        if indices.is_empty() {
+            return Err(format!("no instructions on line {line}"));
+        }
+        Ok(indices)
+    }
+
+    fn report(&mut self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint(index) => format!("breakpoint at {index}\n{}", self.describe(index)),
+            Stop::Watchpoint { pc, address, old, new } => {
+                let show = |v: Option<Vec<u8>>| v.map_or("(unreadable)".to_string(), |b| format!("{b:02x?}"));
+                format!("watchpoint {address:#x} changed by instruction {pc}: {} -> {}\n{}", show(old), show(new), self.describe(self.vm.program_counter))
+            }
+            Stop::Stepped => self.describe(self.vm.program_counter),
+            Stop::Ended => {
+                self.finished = true;
+                "program ended".to_string()
+            }
+            Stop::Fault(fault) => {
+                self.finished = true;
+                format!("fault: {fault}\n{}", self.describe(fault.pc))
+            }
+        }
+    }
+
+    /// runs one command, returning what to print, or `None` to quit
+    fn command(&mut self, line: &str) -> Option<String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Some(String::new()) };
+        if matches!(command, "quit" | "q") {
+            return None;
+        }
+        let args: Vec<&str> = words.collect();
+        let arg = |i: usize, default: u64| match args.get(i) {
+            Some(text) => parse_u64(text).ok_or_else(|| format!("`{text}` isn't a number")),
+            None => Ok(default),
+        };
+        let running = |finished: bool| if finished { Err("the program has finished".to_string()) } else { Ok(()) };
+
+        let result: Result<String, String> = (|| match command {
+            "break" | "b" => {
+                let indices = self.resolve(args.first().ok_or("break needs a location")?)?;
+                let mut out = String::new();
+                for index in indices {
+                    self.debugger.add_breakpoint(index);
+                    out.push_str(&format!("breakpoint at {index}\n{}\n", self.describe(index)));
+                }
+                Ok(out.trim_end().to_string())
+            }
+            "delete" | "d" => {
+                let index = arg(0, u64::MAX)?;
+                if self.debugger.remove_breakpoint(index) { Ok(format!("removed breakpoint at {index}")) } else { Err(format!("no breakpoint at {index}")) }
+            }
+            "watch" | "w" => {
+                let address = args.first().and_then(|a| parse_u64(a)).ok_or("watch needs an address")?;
+                self.debugger.add_watchpoint(&self.vm, address, arg(1, 8)?);
+                Ok(format!("watching {address:#x}"))
+            }
+            "unwatch" => {
+                let address = args.first().and_then(|a| parse_u64(a)).ok_or("unwatch needs an address")?;
+                if self.debugger.remove_watchpoint(address) { Ok(format!("stopped watching {address:#x}")) } else { Err(format!("not watching {address:#x}")) }
+            }
+            "step" | "s" => {
+                running(self.finished)?;
+                let stop = self.debugger.step(&mut self.vm, arg(0, 1)?);
+                Ok(self.report(stop))
+            }
+            "continue" | "c" => {
+                running(self.finished)?;
+                let stop = self.debugger.resume(&mut self.vm);
+                Ok(self.report(stop))
+            }
+            "regs" | "r" => Ok(format!(
+                "pc {}\nsp {:#x}\nfp {:#x}\n{}",
+                self.vm.program_counter, self.vm.stack_pointer(), self.vm.frame_pointer(), self.describe(self.vm.program_counter)
+            )),
+            "stack" => {
+                let len = arg(0, 64)?.min(self.vm.stack_pointer() - VM::STACK_START);
+                let start = self.vm.stack_pointer() - len;
+                let bytes = self.vm.get_bytes(start, len).map_err(|fault| fault.kind.to_string())?;
+                let mut out = String::new();
+                hex_dump(&mut out, start, bytes);
+                Ok(out.trim_end().to_string())
+            }
+            "mem" | "x" => {
+                let address = args.first().and_then(|a| parse_u64(a)).ok_or("mem needs an address")?;
+                let bytes = self.vm.get_bytes(address, arg(1, 64)?).map_err(|fault| fault.kind.to_string())?;
+                let mut out = String::new();
+                hex_dump(&mut out, address, bytes);
+                Ok(out.trim_end().to_string())
+            }
+            "list" | "l" => {
+                let n = arg(0, 5)?;
+                let pc = self.vm.program_counter;
+                let end = (pc + n + 1).min(self.vm.program().len() as u64);
+                let lines: Vec<String> = (pc.saturating_sub(n)..end)
+                    .map(|i| format!("{} {}", if i == pc { "=>" } else { "  " }, self.describe(i)))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            "info" => {
+                let mut out = String::new();
+                for index in self.debugger.breakpoints() {
+                    out.push_str(&format!("breakpoint at {index}\n{}\n", self.describe(index)));
+                }
+                for watchpoint in self.debugger.watchpoints() {
+                    out.push_str(&format!("watchpoint {:#x} ({} bytes)\n", watchpoint.address, watchpoint.len));
+                }
+                Ok(out.trim_end().to_string())
+            }
+            "help" | "h" => Ok(HELP.to_string()),
+            other => Err(format!("unknown command `{other}` (try `help`)")),
+        })();
+
+        Some(result.unwrap_or_else(|message| format!("error: {message}")))
+    }
+}
+
+/// debugs `vm`, which should have been loaded from `program`, until the user quits or stdin closes
+pub fn run(program: &Program, vm: VM) {
+    let mut sources = SourceMap::new();
+    for path in program.debug.iter().flat_map(|d| &d.files) {
+        // keep the IDs lined up with the debug info even if a file's gone
+        if sources.load(path).is_err() {
+            sources.add(path.clone(), "");
+        }
+    }
+
+    let mut shell = Shell { program, sources, vm, debugger: Debugger::new(), finished: false };
+    println!("{}", shell.describe(shell.vm.program_counter));
+    loop {
+        print!("(debug) ");
+        let _ = std::io::stdout().flush();
+        // not holding the lock between lines, since the program reads stdin too
+        let mut line = String::new();
+        if !matches!(std::io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+            break;
+        }
+        match shell.command(&line) {
+            Some(output) if output.is_empty() => {}
+            Some(output) => println!("{output}"),
+            None => break,
+        }
+    }
+}
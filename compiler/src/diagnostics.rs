@@ -0,0 +1,294 @@
+use std::fmt::Write;
+
+use crate::{
+    ast::types::Loc,
+    compiler::{codegen::CodegenError, syntaxes::ParseError, typeck::TypeError},
+    source::{LineCol, SourceMap},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+            Severity::Note => "\x1b[1;36m",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub loc: Loc,
+    pub message: Option<String>,
+    /// primary labels point at the problem itself, secondary ones at related code
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { severity, message: message.into(), labels: vec![], help: vec![] }
+    }
+
+    pub fn error(message: impl Into<String>) -> Diagnostic { Diagnostic::new(Severity::Error, message) }
+    pub fn warning(message: impl Into<String>) -> Diagnostic { Diagnostic::new(Severity::Warning, message) }
+    pub fn note(message: impl Into<String>) -> Diagnostic { Diagnostic::new(Severity::Note, message) }
+
+    pub fn with_primary(mut self, loc: Loc, message: Option<String>) -> Diagnostic {
+        self.labels.push(Label { loc, message, primary: true });
+        self
+    }
+
+    pub fn with_secondary(mut self, loc: Loc, message: Option<String>) -> Diagnostic {
+        self.labels.push(Label { loc, message, primary: false });
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Diagnostic {
+        self.help.push(help.into());
+        self
+    }
+
+    /// like `with_primary`, for errors that might not know where they came from
+    fn at(self, loc: Option<Loc>) -> Diagnostic {
+        match loc {
+            Some(loc) => self.with_primary(loc, None),
+            None => self,
+        }
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(e: &TypeError) -> Diagnostic { Diagnostic::error(&e.message).at(e.loc) }
+}
+
+impl From<&CodegenError> for Diagnostic {
+    fn from(e: &CodegenError) -> Diagnostic { Diagnostic::error(&e.message).at(e.loc) }
+}
+
+impl From<&dyn ParseError> for Diagnostic {
+    fn from(e: &dyn ParseError) -> Diagnostic {
+        match e.at().filter(|at| *at != e.loc()) {
+            Some(at) => Diagnostic::error("syntax error")
+                .with_primary(e.loc(), Some("in this declaration".to_string()))
+                .with_secondary(at, Some(e.to_string())),
+            None => Diagnostic::error("syntax error").with_primary(e.loc(), Some(e.to_string())),
+        }
+    }
+}
+
+/// tabs are expanded to the next multiple of this, so carets line up with what's printed above them
+const TAB_WIDTH: usize = 4;
+
+/// where 1-based `column` (in characters) ends up once the tabs before it are expanded
+fn display_column(text: &str, column: usize) -> usize {
+    text.chars().take(column - 1).fold(0, |width, c| if c == '\t' { width + TAB_WIDTH - width % TAB_WIDTH } else { width + 1 }) + 1
+}
+
+fn expand_tabs(text: &str) -> String {
+    let (mut out, mut width) = (String::new(), 0);
+    for c in text.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - width % TAB_WIDTH;
+            out.extend(std::iter::repeat_n(' ', spaces));
+            width += spaces;
+        } else {
+            out.push(c);
+            width += 1;
+        }
+    }
+    out
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// renders a diagnostic for a terminal, with caret-underlined excerpts of the source for each label
+pub fn render(diagnostic: &Diagnostic, sources: &SourceMap, color: bool) -> String {
+    let paint = |style: &'static str| if color { style } else { "" };
+    let reset = paint(RESET);
+    let mut out = String::new();
+
+    let severity = diagnostic.severity;
+    let _ = writeln!(out, "{}{}{reset}{}: {}{reset}", paint(severity.color()), severity.name(), paint(BOLD), diagnostic.message);
+
+    let last_line = diagnostic.labels.iter().map(|l| sources.end(l.loc).line).max().unwrap_or(1);
+    let gutter = " ".repeat(last_line.to_string().len());
+
+    // labels are grouped into one excerpt per file, starting with the file of the first primary label
+    let mut files = vec![];
+    for label in diagnostic.labels.iter().filter(|l| l.primary).chain(&diagnostic.labels) {
+        if !files.contains(&label.loc.file) {
+            files.push(label.loc.file);
+        }
+    }
+
+    for file in files {
+        let source = sources.get(file);
+        let spans: Vec<(LineCol, LineCol, &Label)> = diagnostic.labels.iter()
+            .filter(|l| l.loc.file == file)
+            .map(|l| (sources.start(l.loc), sources.end(l.loc), l))
+            .collect();
+
+        let (start, _, _) = spans.iter().find(|(_, _, l)| l.primary).unwrap_or(&spans[0]);
+        let _ = writeln!(out, "{gutter}{}-->{reset} {}:{}:{}", paint(BLUE), source.path, start.line, start.column);
+
+        let mut lines: Vec<usize> = spans.iter().flat_map(|(start, end, _)| start.line..=end.line).collect();
+        lines.sort();
+        lines.dedup();
+
+        let _ = writeln!(out, "{gutter} {}|{reset}", paint(BLUE));
+        let mut previous = None;
+        for line in lines {
+            if previous.is_some_and(|p| p + 1 < line) {
+                let _ = writeln!(out, "{}...{reset}", paint(BLUE));
+            }
+            previous = Some(line);
+
+            let text = source.line(line);
+            let width = text.chars().count();
+            let _ = writeln!(out, "{}{line:>w$} |{reset} {}", paint(BLUE), expand_tabs(text), w = gutter.len());
+
+            for (start, end, label) in spans.iter().filter(|(start, end, _)| (start.line..=end.line).contains(&line)) {
+                let from = display_column(text, if start.line == line { start.column } else { 1 });
+                let to = display_column(text, if end.line == line { end.column } else { width + 1 });
+                let carets = to.saturating_sub(from).max(1);
+                let (mark, style) = if label.primary { ('^', severity.color()) } else { ('-', BLUE) };
+                let message = match &label.message {
+                    Some(m) if end.line == line => format!(" {m}"),
+                    _ => String::new(),
+                };
+                let _ = writeln!(out, "{gutter} {}|{reset} {}{}{}{message}{reset}",
+                    paint(BLUE), " ".repeat(from - 1), paint(style), mark.to_string().repeat(carets));
+            }
+        }
+        let _ = writeln!(out, "{gutter} {}|{reset}", paint(BLUE));
+    }
+
+    for help in &diagnostic.help {
+        let _ = writeln!(out, "{gutter} {}={reset} {}help{reset}: {help}", paint(BLUE), paint(BOLD));
+    }
+
+    out
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_position(out: &mut String, pos: LineCol) {
+    let _ = write!(out, "{{\"line\":{},\"column\":{},\"offset\":{}}}", pos.line, pos.column, pos.offset);
+}
+
+/// renders a diagnostic as a single line of JSON, for editors and other tools
+pub fn render_json(diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+    let mut out = String::new();
+    out.push_str("{\"severity\":");
+    json_string(&mut out, diagnostic.severity.name());
+    out.push_str(",\"message\":");
+    json_string(&mut out, &diagnostic.message);
+    out.push_str(",\"labels\":[");
+    for (i, label) in diagnostic.labels.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        out.push_str("{\"file\":");
+        json_string(&mut out, &sources.get(label.loc.file).path);
+        out.push_str(",\"start\":");
+        json_position(&mut out, sources.start(label.loc));
+        out.push_str(",\"end\":");
+        json_position(&mut out, sources.end(label.loc));
+        let _ = write!(out, ",\"primary\":{},\"message\":", label.primary);
+        match &label.message {
+            Some(m) => json_string(&mut out, m),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+    }
+    out.push_str("],\"help\":[");
+    for (i, help) in diagnostic.help.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        json_string(&mut out, help);
+    }
+    out.push_str("]}");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_excerpts_with_carets() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("a.ks", "fun main -> i64 () {\n    (return (true))\n}\n");
+        let diagnostic = Diagnostic::error("mismatched types").with_primary(Loc { file, left: 33, right: 39 }, Some("expected i64".to_string()));
+        assert_eq!(render(&diagnostic, &sources, false), "\
+error: mismatched types
+ --> a.ks:2:13
+  |
+2 |     (return (true))
+  |             ^^^^^^ expected i64
+  |
+");
+    }
+
+    #[test]
+    fn carets_line_up_under_tabs() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("a.ks", "\tab\tcd");
+        let diagnostic = Diagnostic::error("here").with_primary(Loc { file, left: 4, right: 6 }, None);
+        let rendered = render(&diagnostic, &sources, false);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[3], "1 |     ab  cd");
+        assert_eq!(lines[4], "  |         ^^");
+    }
+
+    #[test]
+    fn json_has_positions_and_escapes() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("a.ks", "x\ny");
+        let diagnostic = Diagnostic::error("bad \"y\"").with_primary(Loc { file, left: 2, right: 3 }, None);
+        assert_eq!(render_json(&diagnostic, &sources), concat!(
+            r#"{"severity":"error","message":"bad \"y\"","labels":[{"file":"a.ks","start":{"line":2,"column":1,"offset":2},"#,
+            r#""end":{"line":2,"column":2,"offset":3},"primary":true,"message":null}],"help":[]}"#,
+        ));
+    }
+}
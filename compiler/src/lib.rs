@@ -0,0 +1,8 @@
+//! the compiler, bytecode and VM, for the `kitchen-sink` command and for embedding
+
+pub mod ast;
+pub mod compiler;
+pub mod bytecode;
+pub mod vm;
+pub mod diagnostics;
+pub mod source;
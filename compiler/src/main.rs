@@ -1,8 +1,471 @@
-mod ast;
-mod compiler;
-mod bytecode;
-mod vm;
+mod debug_shell;
 
-fn main() {
-    println!("Hello, world!");
+use std::{cell::RefCell, io::IsTerminal, process::ExitCode, rc::Rc, time::{Duration, Instant}};
+
+use kitchen_sink::{
+    bytecode::{asm, module::{self, ModuleError}, verify, Program},
+    compiler::{codegen, syntaxes::{ast_syntax::AstSyntax, Syntax}, typeck},
+    diagnostics::{self, Diagnostic},
+    source::SourceMap,
+    vm::{host::{Access, FdTable, Policy}, replay, snapshot, trace::{Log, Profile, Tracer}, Engine, Fault, VM},
+};
+
+const USAGE: &str = "\
+usage: kitchen-sink <command> [options] <file>
+
+commands:
+    check     parse and type check a source file
+    build     compile a source or assembly file to bytecode (written to <file>.ksb, or -o <path>)
+    run       run a source file, assembly file or compiled bytecode, or resume a snapshot
+    disasm    print a source file, assembly file or compiled bytecode as assembly
+    debug     run a program or snapshot under an interactive debugger (type `help` at its prompt)
+    bench     time a program, or every program in a directory, under each engine, checking they end up in the same
+              state
+
+options:
+    -o <path>           where `build` writes its output
+    --syntax <name>     the syntax source files are written in (default: ast)
+    --overflow <mode>   what integer arithmetic in source files does when it overflows: wrap, check (fault)
+                        or saturate (default: wrap)
+    --heap <bytes>      size of the VM's main memory (default: 65536)
+    --stack <bytes>     maximum size of the VM's stack (default: 1048576)
+    --fuel <n>          stop `run` with a fault after this many instructions (default: no limit)
+    --engine <name>     how `run` executes bytecode: interpreter, fast (the default), which decodes it first and
+                        fuses common sequences of instructions, or jit, which also compiles hot code to machine code
+                        (on x86-64 Linux, without --fuel)
+    --runs <n>          how many times `bench` runs each program under each engine, keeping the fastest (default: 5)
+    --checkpoint <path> if `run` faults or runs out of fuel, save a snapshot (.kss) there to resume or debug later
+    --json              print diagnostics as JSON, one per line
+    --trace             print every instruction `run` executes to stderr
+    --profile           print the most executed instructions to stderr after `run`
+    --folded <path>     write the call stacks `run` spent its time in, for flamegraph tools
+    --record <path>     log everything `run` reads and gets from host functions, to replay the run exactly
+    --replay <path>     run or debug with I/O and host function results taken from a --record log
+    --allow-read <dir>  let `run` open files inside a directory (none are reachable by default)
+    --allow-write <dir> same, but also let it create and modify them
+
+exit codes:
+    0 success, 1 usage or I/O error, 2 parse error, 3 type error, 4 VM fault, 5 invalid bytecode, 6 engines
+    disagreeing in `bench`";
+
+/// the extension for compiled bytecode
+const BYTECODE_EXTENSION: &str = "ksb";
+/// the extension for assembly; anything that isn't this, bytecode or a snapshot is treated as source code
+const ASSEMBLY_EXTENSION: &str = "kasm";
+/// the extension for VM snapshots
+const SNAPSHOT_EXTENSION: &str = "kss";
+
+#[derive(Clone)]
+struct Options {
+    command: String,
+    input: String,
+    output: Option<String>,
+    syntax: String,
+    overflow: codegen::Overflow,
+    heap: usize,
+    stack: usize,
+    fuel: Option<u64>,
+    engine: Engine,
+    runs: u32,
+    checkpoint: Option<String>,
+    json: bool,
+    trace: bool,
+    profile: bool,
+    folded: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    /// directories `run` lets programs open files in
+    allow: Vec<(String, Access)>,
+}
+
+enum Failure {
+    Usage(String),
+    Io(String),
+    Parse,
+    Type,
+    Fault(Fault),
+    /// bytecode the verifier rejected
+    Invalid(String),
+    /// `bench` found engines ending up in different states, which is a bug in one of them
+    Mismatch(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Usage(_) | Failure::Io(_) => 1,
+            Failure::Parse => 2,
+            Failure::Type => 3,
+            Failure::Fault(..) => 4,
+            Failure::Invalid(_) => 5,
+            Failure::Mismatch(_) => 6,
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, Failure> {
+    let command = args.next().ok_or_else(|| Failure::Usage("missing command".to_string()))?;
+    let mut options = Options {
+        command, input: String::new(), output: None, syntax: "ast".to_string(), overflow: codegen::Overflow::Wrap,
+        heap: 0x10000, stack: 0x100000, fuel: None, engine: Engine::Fast, runs: 5,
+        checkpoint: None, json: false,
+        trace: false, profile: false, folded: None, record: None, replay: None, allow: vec![],
+    };
+
+    let value = |flag: &str, args: &mut dyn Iterator<Item = String>| {
+        args.next().ok_or_else(|| Failure::Usage(format!("{flag} needs a value")))
+    };
+    let size = |flag: &str, v: String| v.parse().map_err(|_| Failure::Usage(format!("{flag} needs a size in bytes, got `{v}`")));
+
+    let mut input = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => options.output = Some(value("-o", &mut args)?),
+            "--syntax" => options.syntax = value("--syntax", &mut args)?,
+            "--overflow" => {
+                options.overflow = match value("--overflow", &mut args)?.as_str() {
+                    "wrap" => codegen::Overflow::Wrap,
+                    "check" => codegen::Overflow::Check,
+                    "saturate" => codegen::Overflow::Saturate,
+                    v => return Err(Failure::Usage(format!("--overflow needs wrap, check or saturate, got `{v}`"))),
+                };
+            }
+            "--heap" => options.heap = size("--heap", value("--heap", &mut args)?)?,
+            "--stack" => options.stack = size("--stack", value("--stack", &mut args)?)?,
+            "--fuel" => {
+                let v = value("--fuel", &mut args)?;
+                options.fuel = Some(v.parse().map_err(|_| Failure::Usage(format!("--fuel needs a number of instructions, got `{v}`")))?);
+            }
+            "--engine" => {
+                options.engine = match value("--engine", &mut args)?.as_str() {
+                    "interpreter" => Engine::Interpreter,
+                    "fast" => Engine::Fast,
+                    "jit" => Engine::Jit,
+                    v => return Err(Failure::Usage(format!("--engine needs interpreter, fast or jit, got `{v}`"))),
+                };
+            }
+            "--runs" => {
+                let v = value("--runs", &mut args)?;
+                options.runs = v.parse().ok().filter(|&runs| runs > 0)
+                    .ok_or_else(|| Failure::Usage(format!("--runs needs a number above 0, got `{v}`")))?;
+            }
+            "--checkpoint" => options.checkpoint = Some(value("--checkpoint", &mut args)?),
+            "--json" => options.json = true,
+            "--trace" => options.trace = true,
+            "--profile" => options.profile = true,
+            "--folded" => options.folded = Some(value("--folded", &mut args)?),
+            "--record" => options.record = Some(value("--record", &mut args)?),
+            "--replay" => options.replay = Some(value("--replay", &mut args)?),
+            "--allow-read" => options.allow.push((value("--allow-read", &mut args)?, Access::ReadOnly)),
+            "--allow-write" => options.allow.push((value("--allow-write", &mut args)?, Access::ReadWrite)),
+            flag if flag.starts_with('-') => return Err(Failure::Usage(format!("unknown option `{flag}`"))),
+            _ if input.is_some() => return Err(Failure::Usage(format!("unexpected argument `{arg}`"))),
+            _ => input = Some(arg),
+        }
+    }
+    options.input = input.ok_or_else(|| Failure::Usage("missing input file".to_string()))?;
+    if options.record.is_some() && options.replay.is_some() {
+        return Err(Failure::Usage("--record and --replay can't be used together".to_string()));
+    }
+    Ok(options)
+}
+
+fn report(sources: &SourceMap, diagnostics: impl IntoIterator<Item = Diagnostic>, json: bool) {
+    let color = std::io::stderr().is_terminal();
+    for diagnostic in diagnostics {
+        if json {
+            eprintln!("{}", diagnostics::render_json(&diagnostic, sources));
+        } else {
+            eprint!("{}", diagnostics::render(&diagnostic, sources, color));
+        }
+    }
+}
+
+/// parses, type checks and compiles a source file, reporting any problems along the way
+fn compile(options: &Options) -> Result<Program, Failure> {
+    let mut sources = SourceMap::new();
+    let file = sources.load(&options.input).map_err(|e| Failure::Io(format!("couldn't read {}: {e}", options.input)))?;
+    let text = &sources.get(file).text;
+
+    let parsed = match options.syntax.as_str() {
+        "ast" => AstSyntax::parse(file, text),
+        other => return Err(Failure::Usage(format!("unknown syntax `{other}`"))),
+    };
+    let parsed = parsed.map_err(|errors| {
+        report(&sources, errors.iter().map(|e| Diagnostic::from(&**e)), options.json);
+        Failure::Parse
+    })?;
+
+    let types = typeck::check(&parsed).map_err(|errors| {
+        report(&sources, errors.iter().map(Diagnostic::from), options.json);
+        Failure::Type
+    })?;
+
+    codegen::compile(&parsed, &types, &sources, options.overflow).map_err(|error| {
+        report(&sources, [Diagnostic::from(&error)], options.json);
+        Failure::Type
+    })
+}
+
+fn has_extension(path: &str, extension: &str) -> bool {
+    std::path::Path::new(path).extension().is_some_and(|e| e == extension)
+}
+
+/// loads compiled bytecode, assembles assembly, or compiles source code
+fn load(options: &Options) -> Result<Program, Failure> {
+    let read_error = |e| Failure::Io(format!("couldn't read {}: {e}", options.input));
+    if has_extension(&options.input, SNAPSHOT_EXTENSION) {
+        Ok(restore(options)?.1)
+    } else if has_extension(&options.input, BYTECODE_EXTENSION) {
+        let bytes = std::fs::read(&options.input).map_err(read_error)?;
+        module::read(&bytes).map_err(|e| Failure::Io(format!("{}: {e}", options.input)))
+    } else if has_extension(&options.input, ASSEMBLY_EXTENSION) {
+        let text = std::fs::read_to_string(&options.input).map_err(read_error)?;
+        asm::assemble(&text).map_err(|e| {
+            eprintln!("error: {}:{}: {}", options.input, e.line, e.message);
+            Failure::Parse
+        })
+    } else {
+        compile(options)
+    }
+}
+
+/// loads the input and checks it's safe to run
+fn verified(options: &Options) -> Result<Program, Failure> {
+    let program = load(options)?;
+    verify::verify(&program).map_err(|e| Failure::Invalid(format!("{}: {e}", options.input)))?;
+    Ok(program)
+}
+
+/// loads a snapshot, checking its program the same way `verified` does, since it could have come from anywhere
+fn restore(options: &Options) -> Result<(VM, Program), Failure> {
+    let bytes = std::fs::read(&options.input).map_err(|e| Failure::Io(format!("couldn't read {}: {e}", options.input)))?;
+    let (vm, program) = snapshot::read(&bytes).map_err(|e| Failure::Io(format!("{}: {e}", options.input)))?;
+    verify::verify(&program).map_err(|e| Failure::Invalid(format!("{}: {e}", options.input)))?;
+    Ok((vm, program))
+}
+
+/// a VM that's about to start `program`, with the memory and stack the options ask for
+fn fresh(options: &Options, program: &Program) -> Result<VM, Failure> {
+    VM::load(program, options.heap, options.stack).map_err(|_| {
+        let error = ModuleError::DataTooLarge { data: program.data.len(), memory: options.heap };
+        Failure::Io(format!("{}: {error}", options.input))
+    })
+}
+
+/// sets up a VM to run the input with, either fresh or from a snapshot
+fn start(options: &Options) -> Result<(VM, Program), Failure> {
+    let (vm, program) = if has_extension(&options.input, SNAPSHOT_EXTENSION) {
+        restore(options)?
+    } else {
+        let program = verified(options)?;
+        (fresh(options, &program)?, program)
+    };
+
+    let mut policy = Policy::new();
+    for (dir, access) in &options.allow {
+        policy.allow(dir, *access).map_err(|e| Failure::Io(format!("couldn't allow {dir}: {e}")))?;
+    }
+    let mut vm = vm.with_host(FdTable::stdio().with_policy(policy));
+    vm.set_fuel(options.fuel);
+    vm.set_engine(options.engine);
+    if let Some(path) = &options.replay {
+        let bytes = std::fs::read(path).map_err(|e| Failure::Io(format!("couldn't read {path}: {e}")))?;
+        vm.replay(replay::read(&bytes).map_err(|e| Failure::Io(format!("{path}: {e}")))?);
+    }
+    Ok((vm, program))
+}
+
+/// the fastest time a program ran in, and how its last run ended
+type Timed = (Duration, Result<(), Fault>, VM);
+
+/// runs `program` from the start under `engine` as many times as `bench` was asked to, returning the fastest time
+/// and a snapshot of how the last run ended
+fn time(options: &Options, program: &Program, engine: Engine) -> Result<Timed, Failure> {
+    let mut fastest = Duration::MAX;
+    let mut last = None;
+    for _ in 0..options.runs {
+        let mut vm = fresh(options, program)?;
+        vm.set_fuel(options.fuel);
+        vm.set_engine(engine);
+        let start = Instant::now();
+        let result = vm.run();
+        fastest = fastest.min(start.elapsed());
+        last = Some((result, vm));
+    }
+    let (result, vm) = last.expect("there's always at least one run");
+    Ok((fastest, result, vm))
+}
+
+/// times `program` under the interpreter and then the fast and JIT engines, checking they all end up in the same
+/// state; returns the interpreter's time and how its last run ended, and the other engines' times
+fn compare_engines(options: &Options, program: &Program) -> Result<(Timed, Vec<Duration>), Failure> {
+    let (slow, slow_result, slow_vm) = time(options, program, Engine::Interpreter)?;
+    let expected = snapshot::write(&slow_vm, program);
+    let mut times = vec![];
+    for engine in [Engine::Fast, Engine::Jit] {
+        let (time, result, vm) = time(options, program, engine)?;
+        if result != slow_result || snapshot::write(&vm, program) != expected {
+            return Err(Failure::Mismatch(format!("{}: the {engine:?} engine ended up in a different state from the interpreter", options.input)));
+        }
+        times.push(time);
+    }
+    Ok(((slow, slow_result, slow_vm), times))
+}
+
+fn bench(options: &Options) -> Result<(), Failure> {
+    let inputs = if std::path::Path::new(&options.input).is_dir() {
+        let entries = std::fs::read_dir(&options.input).map_err(|e| Failure::Io(format!("couldn't read {}: {e}", options.input)))?;
+        let mut inputs = entries
+            .map(|entry| entry.map(|entry| entry.path().display().to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Failure::Io(format!("couldn't read {}: {e}", options.input)))?;
+        inputs.retain(|path| !has_extension(path, SNAPSHOT_EXTENSION));
+        inputs.sort();
+        inputs
+    } else {
+        vec![options.input.clone()]
+    };
+
+    println!("{:<28} {:>14} {:>13} {:>22} {:>22}", "program", "instructions", "interpreter", "fast", "jit");
+    for input in inputs {
+        let options = Options { input, ..options.clone() };
+        let program = verified(&options)?;
+        let ((slow, slow_result, slow_vm), times) = compare_engines(&options, &program)?;
+        let mut columns = String::new();
+        for time in times {
+            columns += &format!(" {:>10.1} ms {:>7.2}x", time.as_secs_f64() * 1000.0, slow.as_secs_f64() / time.as_secs_f64());
+        }
+        println!(
+            "{:<28} {:>14} {:>10.1} ms{columns}",
+            options.input, slow_vm.instructions_executed(), slow.as_secs_f64() * 1000.0,
+        );
+        if let Err(fault) = slow_result {
+            println!("    (every engine ended with a VM fault: {fault})");
+        }
+    }
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), Failure> {
+    match options.command.as_str() {
+        "check" => {
+            compile(options)?;
+        }
+        "build" => {
+            let compiled = verified(options)?;
+            let output = options.output.clone().unwrap_or_else(|| {
+                std::path::Path::new(&options.input).with_extension(BYTECODE_EXTENSION).display().to_string()
+            });
+            std::fs::write(&output, module::write(&compiled))
+                .map_err(|e| Failure::Io(format!("couldn't write {output}: {e}")))?;
+        }
+        "run" => {
+            let (mut vm, program) = start(options)?;
+            let profile = Rc::new(RefCell::new(Profile::new()));
+            let profiling = options.profile || options.folded.is_some();
+            let tracer: Option<Box<dyn Tracer>> = match (options.trace, profiling) {
+                (true, true) => Some(Box::new((Log(std::io::stderr()), profile.clone()))),
+                (true, false) => Some(Box::new(Log(std::io::stderr()))),
+                (false, true) => Some(Box::new(profile.clone())),
+                (false, false) => None,
+            };
+            vm.set_tracer(tracer);
+            if options.record.is_some() {
+                vm.record();
+            }
+
+            let result = vm.run();
+            // a profile of a run that faulted is still worth having
+            if options.profile {
+                eprint!("{}", profile.borrow().report(&program, 20));
+            }
+            if let Some(path) = &options.folded {
+                std::fs::write(path, profile.borrow().folded(&program))
+                    .map_err(|e| Failure::Io(format!("couldn't write {path}: {e}")))?;
+            }
+            // so is a recording, since that's how the fault gets reproduced
+            if let (Some(recording), Some(path)) = (vm.take_recording(), &options.record) {
+                std::fs::write(path, replay::write(&recording))
+                    .map_err(|e| Failure::Io(format!("couldn't write {path}: {e}")))?;
+            }
+            if let (Err(_), Some(path)) = (&result, &options.checkpoint) {
+                std::fs::write(path, snapshot::write(&vm, &program))
+                    .map_err(|e| Failure::Io(format!("couldn't write {path}: {e}")))?;
+                eprintln!("note: saved a snapshot to {path}");
+            }
+            result.map_err(Failure::Fault)?;
+        }
+        "debug" => {
+            let (vm, program) = start(options)?;
+            debug_shell::run(&program, vm);
+        }
+        "bench" => bench(options)?,
+        "disasm" => {
+            print!("{}", asm::disassemble(&load(options)?));
+        }
+        other => return Err(Failure::Usage(format!("unknown command `{other}`"))),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).and_then(|options| run(&options));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            match &failure {
+                Failure::Usage(message) => eprintln!("error: {message}\n\n{USAGE}"),
+                Failure::Io(message) => eprintln!("error: {message}"),
+                // already reported as diagnostics
+                Failure::Parse | Failure::Type => {}
+                Failure::Fault(fault) => eprintln!("error: VM fault: {fault}"),
+                Failure::Invalid(message) => eprintln!("error: invalid bytecode: {message}"),
+                Failure::Mismatch(message) => eprintln!("error: engine mismatch: {message}"),
+            }
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// how much of each benchmark to run, since all of it under every engine takes minutes unoptimized
+    const SMALLER: [(&str, &str, &str); 4] = [
+        ("fib.kasm", "PUSHD 25", "PUSHD 12"),
+        ("loop.kasm", "PUSHD 3000000", "PUSHD 3000"),
+        ("newton.kasm", "PUSHD 200000", "PUSHD 500"),
+        ("sieve.kasm", "PUSHD 60000", "PUSHD 600"),
+    ];
+
+    #[test]
+    fn engines_agree_on_the_benchmarks() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+        let scratch = std::env::temp_dir().join(format!("kitchen-sink-benches-{}", std::process::id()));
+        std::fs::create_dir_all(&scratch).unwrap();
+        let mut benches = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if !has_extension(&path.display().to_string(), ASSEMBLY_EXTENSION) {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let (_, size, smaller) = SMALLER.iter().find(|(bench, ..)| *bench == name).unwrap_or_else(|| panic!("no smaller size for {name}"));
+            let source = std::fs::read_to_string(&path).unwrap();
+            assert!(source.contains(size), "{name}");
+            let shrunk = scratch.join(name);
+            std::fs::write(&shrunk, source.replace(size, smaller)).unwrap();
+
+            let args = ["bench", &shrunk.display().to_string(), "--runs", "1"].map(String::from);
+            let options = parse_args(args.into_iter()).map_err(|e| e.exit_code()).unwrap();
+            let program = verified(&options).map_err(|e| e.exit_code()).unwrap();
+            let ((_, result, _), _) = compare_engines(&options, &program).map_err(|e| e.exit_code()).unwrap();
+            assert_eq!(result, Ok(()), "{name}");
+            benches += 1;
+        }
+        std::fs::remove_dir_all(scratch).unwrap();
+        assert!(benches > 0);
+    }
 }
@@ -0,0 +1,151 @@
+use std::{fmt::Display, path::Path};
+
+use crate::ast::types::{Loc, Located};
+
+/// identifies a file registered in a `SourceMap`
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileId(pub u32);
+
+/// a position in a source file, with 1-based line and column (in characters)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCol {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+pub struct SourceFile {
+    pub path: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    fn new(path: String, text: String) -> SourceFile {
+        let line_starts = std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        SourceFile { path, text, line_starts }
+    }
+
+    pub fn line_col(&self, offset: usize) -> LineCol {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let column = self.text[self.line_starts[line]..offset].chars().count() + 1;
+        LineCol { line: line + 1, column, offset }
+    }
+
+    /// the text of a 1-based line, without its newline (`\n` or `\r\n`)
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line - 1];
+        let end = self.line_starts.get(line).map(|e| e - 1).unwrap_or(self.text.len());
+        let text = &self.text[start..end];
+        text.strip_suffix('\r').unwrap_or(text)
+    }
+}
+
+/// every file loaded during a compilation, so a `Loc` from any of them can be traced back to its source
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    pub fn add(&mut self, path: impl Into<String>, text: impl Into<String>) -> FileId {
+        self.files.push(SourceFile::new(path.into(), text.into()));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    pub fn load(&mut self, path: impl AsRef<Path>) -> std::io::Result<FileId> {
+        let text = std::fs::read_to_string(path.as_ref())?;
+        Ok(self.add(path.as_ref().display().to_string(), text))
+    }
+
+    /// paths of every file, in `FileId` order
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| f.path.as_str())
+    }
+
+    pub fn get(&self, id: FileId) -> &SourceFile {
+        &self.files[id.0 as usize]
+    }
+
+    pub fn start(&self, loc: Loc) -> LineCol {
+        self.get(loc.file).line_col(loc.left)
+    }
+
+    pub fn end(&self, loc: Loc) -> LineCol {
+        self.get(loc.file).line_col(loc.right.max(loc.left))
+    }
+
+    /// `path:line:col` for the start of a location
+    pub fn describe(&self, loc: Loc) -> Described<'_> {
+        Described { path: &self.get(loc.file).path, start: self.start(loc) }
+    }
+
+    /// `path:line:col` for the start of a `Tag` or `OpTag`, if it has a location
+    pub fn locate(&self, tagged: &impl Located) -> Option<Described<'_>> {
+        tagged.location().map(|loc| self.describe(loc))
+    }
+}
+
+pub struct Described<'a> {
+    path: &'a str,
+    start: LineCol,
+}
+
+impl Display for Described<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.path, self.start.line, self.start.column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::types::{OpTag, Tag};
+
+    #[test]
+    fn lines_and_columns() {
+        let file = SourceFile::new("a.ks".to_string(), "ab\nλd\n".to_string());
+        assert_eq!(file.line_col(0), LineCol { line: 1, column: 1, offset: 0 });
+        assert_eq!(file.line_col(3), LineCol { line: 2, column: 1, offset: 3 });
+        // columns count characters, not bytes
+        assert_eq!(file.line_col(5), LineCol { line: 2, column: 2, offset: 5 });
+        assert_eq!(file.line_col(100), LineCol { line: 3, column: 1, offset: 7 });
+        assert_eq!((file.line(1), file.line(2), file.line(3)), ("ab", "λd", ""));
+    }
+
+    #[test]
+    fn crlf_lines_leave_out_the_carriage_return() {
+        let file = SourceFile::new("a.ks".to_string(), "ab\r\ncd\r\nef".to_string());
+        assert_eq!((file.line(1), file.line(2), file.line(3)), ("ab", "cd", "ef"));
+        assert_eq!(file.line_col(4), LineCol { line: 2, column: 1, offset: 4 });
+    }
+
+    #[test]
+    fn locs_know_their_file() {
+        let mut sources = SourceMap::new();
+        let a = sources.add("a.ks", "one");
+        let b = sources.add("b.ks", "two\nthree");
+        assert_eq!(sources.paths().collect::<Vec<_>>(), ["a.ks", "b.ks"]);
+        assert_eq!(sources.describe(Loc { file: b, left: 6, right: 8 }).to_string(), "b.ks:2:3");
+        assert_eq!(sources.describe(Loc { file: a, left: 1, right: 2 }).to_string(), "a.ks:1:2");
+        assert_eq!(sources.end(Loc { file: b, left: 6, right: 2 }), sources.start(Loc { file: b, left: 6, right: 6 }));
+    }
+
+    #[test]
+    fn tags_resolve_to_where_they_start() {
+        let mut sources = SourceMap::new();
+        sources.add("a.ks", "one");
+        let b = sources.add("b.ks", "two\nthree");
+        let loc = Loc { file: b, left: 6, right: 8 };
+        let tag = Tag { value: "re", loc };
+        assert_eq!(sources.locate(&tag).map(|d| d.to_string()), Some("b.ks:2:3".to_string()));
+        assert_eq!(sources.locate(&OpTag { value: "re", loc: Some(loc) }).map(|d| d.to_string()), Some("b.ks:2:3".to_string()));
+        assert!(sources.locate(&OpTag { value: "re", loc: None }).is_none());
+        assert_eq!(*tag, "re");
+    }
+}
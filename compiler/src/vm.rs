@@ -1,68 +1,442 @@
-use std::io::{stdin, Read, stdout, Write};
+pub mod debugger;
+mod fast;
+pub mod host;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+pub mod replay;
+pub mod snapshot;
+pub mod trace;
 
-use crate::bytecode::Instruction;
+use crate::bytecode::{Instruction, Program};
 use crate::bytecode::{IntSize, FloatSize};
 
+use std::{collections::HashMap, fmt::Display, io::SeekFrom};
+
+use host::{FdTable, Host, IoError, OpenFlags};
+use replay::{Effects, Entry, Event, Recording};
+use trace::{TraceEvent, Tracer};
+
 pub struct VM {
     program: Vec<Instruction>,
+    /// what `PushConst` pushes
+    constants: Vec<Vec<u8>>,
     main_memory: Vec<u8>,
     stack: Vec<u8>,
     stack_pointer: u64,
+    frame_pointer: u64,
     pub program_counter: u64,
-    max_stack: u64
+    max_stack: u64,
+    host: Box<dyn Host>,
+    host_functions: HashMap<u32, HostFunction>,
+    /// how much more the program can run for, or `None` for no limit
+    fuel: Option<u64>,
+    costs: Option<Box<CostTable>>,
+    tracer: Option<Box<dyn Tracer>>,
+    engine: Engine,
+    /// how many instructions have run successfully since the program started
+    executed: u64,
+    journal: Journal,
 }
 
-#[derive(Debug)]
-pub enum Fault {
-    SegmentationFault, StackOverflow, ProgramEnded
+/// whether I/O and host calls are being recorded or replayed
+enum Journal {
+    Off,
+    Recording(Recording),
+    /// with the index of the next entry to use
+    Replaying(Recording, usize),
+}
+
+impl Journal {
+    /// how far through the journal we are, to `rewind` to if an instruction faults
+    fn position(&self) -> usize {
+        match self {
+            Journal::Off => 0,
+            Journal::Recording(recording) => recording.entries.len(),
+            Journal::Replaying(_, next) => *next,
+        }
+    }
+
+    /// forgets anything recorded, or puts back anything replayed, since `position`
+    fn rewind(&mut self, position: usize) {
+        match self {
+            Journal::Off => {}
+            Journal::Recording(recording) => recording.entries.truncate(position),
+            Journal::Replaying(_, next) => *next = (*next).min(position),
+        }
+    }
+}
+
+/// a Rust function bytecode can call with `CallHost`; it gets the whole VM, so it can pop its arguments, push its
+/// results and read or write memory
+pub type HostFunction = Box<dyn FnMut(&mut VM) -> Result<(), Fault>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    /// an access outside of main memory and the stack
+    SegmentationFault,
+    StackOverflow,
+    /// a pop of more than was on the stack
+    StackUnderflow,
+    DivisionByZero,
+    /// checked arithmetic whose result didn't fit in its size
+    Overflow,
+    /// a jump, call or return to somewhere that isn't an instruction
+    InvalidJump,
+    /// a float comparison where one side was NaN
+    UnorderedComparison,
+    /// an I/O instruction the host doesn't support at all
+    UnsupportedIo,
+    /// `PushConst` used an index past the end of the constant pool
+    UnknownConstant(u32),
+    /// `CallHost` used an ID nothing was registered with
+    UnknownHostFunction(u32),
+    /// a host function failed
+    HostError(String),
+    /// the program counter ran off the end of the program, which is how programs exit
+    ProgramEnded,
+    /// while replaying, the program did I/O or called a host function differently from the recording
+    ReplayDivergence,
+    /// there wasn't enough fuel for the next instruction; it hasn't run, so adding fuel and ticking again resumes
+    OutOfFuel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// the index of the instruction that faulted
+    pub pc: u64,
+    /// the address involved, for memory faults and bad jumps
+    pub address: Option<u64>,
+}
+
+impl Fault {
+    /// a fault at no address in particular; `tick` fills in the program counter
+    pub fn new(kind: FaultKind) -> Fault {
+        Fault { kind, pc: 0, address: None }
+    }
+
+    pub fn at(kind: FaultKind, address: u64) -> Fault {
+        Fault { kind, pc: 0, address: Some(address) }
+    }
+
+    /// for host functions to report their own errors with
+    pub fn host_error(message: impl Into<String>) -> Fault {
+        Fault::new(FaultKind::HostError(message.into()))
+    }
+}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultKind::SegmentationFault => write!(f, "segmentation fault"),
+            FaultKind::StackOverflow => write!(f, "stack overflow"),
+            FaultKind::StackUnderflow => write!(f, "stack underflow"),
+            FaultKind::DivisionByZero => write!(f, "division by zero"),
+            FaultKind::Overflow => write!(f, "arithmetic overflow"),
+            FaultKind::InvalidJump => write!(f, "invalid jump target"),
+            FaultKind::UnorderedComparison => write!(f, "comparison with NaN"),
+            FaultKind::UnsupportedIo => write!(f, "I/O operation not supported by the host"),
+            FaultKind::UnknownConstant(index) => write!(f, "no constant with index {index}"),
+            FaultKind::UnknownHostFunction(id) => write!(f, "no host function registered with ID {id}"),
+            FaultKind::HostError(message) => write!(f, "host function failed: {message}"),
+            FaultKind::ProgramEnded => write!(f, "program ended"),
+            FaultKind::ReplayDivergence => write!(f, "the program diverged from the recording being replayed"),
+            FaultKind::OutOfFuel => write!(f, "out of fuel"),
+        }
+    }
+}
+
+/// how much fuel each instruction uses, by opcode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable([u64; 256]);
+
+impl CostTable {
+    /// every instruction costs the same
+    pub fn uniform(cost: u64) -> CostTable {
+        CostTable([cost; 256])
+    }
+
+    /// sets the cost of every instruction with the given opcode (see `Instruction::opcode`)
+    pub fn set(&mut self, opcode: u8, cost: u64) -> &mut CostTable {
+        self.0[opcode as usize] = cost;
+        self
+    }
+
+    pub fn cost(&self, instruction: &Instruction) -> u64 {
+        self.0[instruction.opcode() as usize]
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> CostTable {
+        CostTable::uniform(1)
+    }
+}
+
+/// how `run` runs the program; they all end up in the same state, faults and all, but some get there faster
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// `tick` in a loop
+    Interpreter,
+    /// decodes the program first and fuses common runs of instructions (see `fast`), falling back to `tick` for
+    /// anything unusual
+    #[default]
+    Fast,
+    /// compiles hot parts of the program to machine code (see `jit`), on x86-64 Linux; anywhere else, or with a fuel
+    /// limit, it's `Fast`
+    Jit,
+}
+
+/// how far `run_for` got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Ended,
+    /// it ran as many instructions as it was allowed, and can carry on from there
+    Paused,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(address) = self.address {
+            write!(f, " at address {address:#x}")?;
+        }
+        write!(f, " (instruction {})", self.pc)
+    }
 }
 
 impl VM {
     pub const STACK_START: u64 = 0x1000000000000000;
 
+    /// doesn't check `program` makes sense, so anything wrong with it faults when it's reached; use
+    /// `bytecode::verify` to find out up front
     pub fn new(program: Vec<Instruction>, main_memory_len: usize, stack_len: usize) -> VM {
         VM {
             program,
+            constants: vec![],
             main_memory: vec![0; main_memory_len],
             stack: vec![],
             stack_pointer: Self::STACK_START,
+            frame_pointer: Self::STACK_START,
             program_counter: 0,
-            max_stack: stack_len as u64
+            max_stack: stack_len as u64,
+            host: Box::new(FdTable::stdio()),
+            host_functions: HashMap::new(),
+            fuel: None,
+            costs: None,
+            tracer: None,
+            engine: Engine::default(),
+            executed: 0,
+            journal: Journal::Off,
+        }
+    }
+
+    /// replaces the host that `Read` and `Write` go through (stdin, stdout and stderr by default)
+    pub fn with_host(mut self, host: impl Host + 'static) -> VM {
+        self.host = Box::new(host);
+        self
+    }
+
+    pub fn host_mut(&mut self) -> &mut dyn Host {
+        &mut *self.host
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.stack_pointer
+    }
+
+    pub fn frame_pointer(&self) -> u64 {
+        self.frame_pointer
+    }
+
+    /// makes `function` callable with `CallHost(id)`, returning whatever was registered with that ID before
+    pub fn register_host_function(
+        &mut self, id: u32, function: impl FnMut(&mut VM) -> Result<(), Fault> + 'static,
+    ) -> Option<HostFunction> {
+        self.host_functions.insert(id, Box::new(function))
+    }
+
+    /// limits how much more the program can run for; each instruction uses its cost (1 unless a cost table says
+    /// otherwise), and `tick` faults with `OutOfFuel` rather than run one it can't afford
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(current) = &mut self.fuel {
+            *current = current.saturating_add(fuel);
         }
     }
 
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn with_costs(mut self, costs: CostTable) -> VM {
+        self.costs = Some(Box::new(costs));
+        self
+    }
+
+    /// calls `tracer` before every instruction runs, or stops tracing if it's `None`
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    /// picks how `run` runs the program; `run_for`, and `run` while there's a tracer, always tick
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// starts logging everything I/O instructions and host functions hand back, dropping any recording or replay in
+    /// progress
+    pub fn record(&mut self) {
+        self.journal = Journal::Recording(Recording::default());
+    }
+
+    /// stops recording, returning what's been recorded so far
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        match std::mem::replace(&mut self.journal, Journal::Off) {
+            Journal::Recording(recording) => Some(recording),
+            journal => { self.journal = journal; None }
+        }
+    }
+
+    /// feeds `recording` back to the program instead of doing real I/O or calling host functions; doing anything it
+    /// doesn't expect faults with `ReplayDivergence`
+    ///
+    /// anything recorded before the instructions this VM has already run is skipped, so a run can be replayed from a
+    /// snapshot of it
+    pub fn replay(&mut self, recording: Recording) {
+        let next = recording.entries.partition_point(|entry| entry.at < self.executed);
+        self.journal = Journal::Replaying(recording, next);
+    }
+
+    /// runs an I/O operation on the host (which also returns any bytes it read), logging its result if we're recording
+    /// or taking it from the recording instead if we're replaying
+    fn host_io(
+        &mut self, opcode: u8, io: impl FnOnce(&mut dyn Host) -> (Result<u64, IoError>, Vec<u8>),
+    ) -> Result<(Result<u64, IoError>, Vec<u8>), Fault> {
+        match &mut self.journal {
+            Journal::Off => Ok(io(&mut *self.host)),
+            Journal::Recording(recording) => {
+                let (result, data) = io(&mut *self.host);
+                let logged = result.as_ref().copied().map_err(IoError::code);
+                recording.entries.push(Entry { at: self.executed, event: Event::Io { opcode, result: logged, data: data.clone() } });
+                Ok((result, data))
+            }
+            Journal::Replaying(recording, next) => match recording.entries.get(*next) {
+                Some(Entry { at, event: Event::Io { opcode: logged, result, data } }) if *at == self.executed && *logged == opcode => {
+                    *next += 1;
+                    // output still goes where it's meant to, but the program has to see what it saw last time
+                    if opcode == Instruction::Write.opcode() {
+                        let _ = io(&mut *self.host);
+                    }
+                    Ok((result.map_err(IoError::from_code), data.clone()))
+                }
+                _ => Err(Fault::new(FaultKind::ReplayDivergence)),
+            },
+        }
+    }
+
+    /// calls a host function, logging what it did to the VM if we're recording, or doing that again without calling it
+    /// if we're replaying
+    fn call_host(&mut self, id: u32) -> Result<(), Fault> {
+        if let Journal::Replaying(recording, next) = &mut self.journal {
+            let Some(Entry { at, event: Event::HostCall { id: logged, effects, error } }) = recording.entries.get(*next) else {
+                return Err(Fault::new(FaultKind::ReplayDivergence));
+            };
+            // the recording could have come from anywhere, so it can't be allowed to leave the VM in a state no
+            // instruction could
+            let fits = effects.stack_len <= self.max_stack && effects.consistent() && effects.program_counter <= self.program.len() as u64;
+            if *at != self.executed || *logged != id || !fits {
+                return Err(Fault::new(FaultKind::ReplayDivergence));
+            }
+            *next += 1;
+            self.stack.resize(effects.stack_len as usize, 0);
+            replay::apply(&mut self.stack, &effects.stack)
+                .and_then(|_| replay::apply(&mut self.main_memory, &effects.memory))
+                .ok_or(Fault::new(FaultKind::ReplayDivergence))?;
+            (self.stack_pointer, self.frame_pointer, self.program_counter) = (effects.stack_pointer, effects.frame_pointer, effects.program_counter);
+            return match error {
+                Some(message) => Err(Fault::host_error(message.clone())),
+                None => Ok(()),
+            };
+        }
+
+        // a host function can touch anything, so compare everything before and after
+        let before = matches!(self.journal, Journal::Recording(_)).then(|| (self.stack.clone(), self.main_memory.clone()));
+        let at = self.executed;
+        // taken out while it runs so it can have the VM; if it registered a replacement for itself, keep that
+        let mut function = self.host_functions.remove(&id).ok_or(Fault::new(FaultKind::UnknownHostFunction(id)))?;
+        let result = function(self);
+        self.host_functions.entry(id).or_insert(function);
+
+        if let (Some((stack, memory)), Journal::Recording(recording)) = (before, &mut self.journal) {
+            let effects = Effects {
+                stack_pointer: self.stack_pointer,
+                frame_pointer: self.frame_pointer,
+                program_counter: self.program_counter,
+                stack_len: self.stack.len() as u64,
+                stack: replay::diff(&stack, &self.stack),
+                memory: replay::diff(&memory, &self.main_memory),
+            };
+            let error = result.as_ref().err().map(|fault| match &fault.kind {
+                FaultKind::HostError(message) => message.clone(),
+                kind => kind.to_string(),
+            });
+            recording.entries.push(Entry { at, event: Event::HostCall { id, effects, error } });
+        }
+        result
+    }
+
+    /// sets up a VM for a program, with its data copied to the start of main memory
+    pub fn load(program: &Program, main_memory_len: usize, stack_len: usize) -> Result<VM, Fault> {
+        let mut vm = VM::new(program.instructions.clone(), main_memory_len, stack_len);
+        vm.constants = program.constants.clone();
+        vm.set_bytes(0, &program.data)?;
+        Ok(vm)
+    }
+
     // boilerplate garbage
 
     // we might have to change this to return a vec for borrow checker reasons
     pub fn get_bytes(&self, addr: u64, count: u64) -> Result<&[u8], Fault> {
         let end = match addr.checked_add(count) {
             Some(v) => v,
-            None => return Err(Fault::SegmentationFault),
+            None => return Err(Fault::at(FaultKind::SegmentationFault, addr)),
         };
-        if end < self.main_memory.len() as u64 {
+        if end <= self.main_memory.len() as u64 {
             Ok(&self.main_memory[addr as usize..end as usize])
-        } else if addr >= Self::STACK_START && end < Self::STACK_START + self.stack.len() as u64 {
+        } else if addr >= Self::STACK_START && end <= Self::STACK_START + self.stack.len() as u64 {
             Ok(&self.stack[(addr - Self::STACK_START) as usize..(end - Self::STACK_START) as usize])
         } else {
-            Err(Fault::SegmentationFault)
+            Err(Fault::at(FaultKind::SegmentationFault, addr))
         }
     }
 
     pub fn set_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), Fault> {
         let end = match addr.checked_add(bytes.len() as u64) {
             Some(v) => v,
-            None => return Err(Fault::SegmentationFault),
+            None => return Err(Fault::at(FaultKind::SegmentationFault, addr)),
         };
-        if end < self.main_memory.len() as u64 {
+        if end <= self.main_memory.len() as u64 {
             let slice = &mut self.main_memory[addr as usize..end as usize];
             slice.copy_from_slice(bytes);
             Ok(())
-        } else if addr > Self::STACK_START && end < Self::STACK_START + self.stack.len() as u64 {
-            let slice = &mut self.main_memory[(addr - Self::STACK_START) as usize..(end - Self::STACK_START) as usize];
+        } else if addr >= Self::STACK_START && end <= Self::STACK_START + self.stack.len() as u64 {
+            let slice = &mut self.stack[(addr - Self::STACK_START) as usize..(end - Self::STACK_START) as usize];
             slice.copy_from_slice(bytes);
             Ok(())
         } else {
-            Err(Fault::SegmentationFault)
+            Err(Fault::at(FaultKind::SegmentationFault, addr))
         }
     }
 
@@ -82,16 +456,14 @@ impl VM {
 
     // we might have to change this to return a vec for borrow checker reasons
     pub fn pop_bytes(&mut self, count: u64) -> Result<&[u8], Fault> {
-        // println!("sp {} sp - count {} sp - count - start {}", self.stack_pointer, self.stack_pointer - count, (self.stack_pointer - count) as i64 - Self::STACK_START as i64);
-        let from = self.stack_pointer.checked_sub(count).ok_or(Fault::SegmentationFault)?.checked_sub(Self::STACK_START).ok_or(Fault::SegmentationFault)?;
-        let to = self.stack_pointer.checked_sub(Self::STACK_START).ok_or(Fault::SegmentationFault)?;
+        let underflow = Fault::at(FaultKind::StackUnderflow, self.stack_pointer);
+        let from = self.stack_pointer.checked_sub(count).ok_or(underflow.clone())?.checked_sub(Self::STACK_START).ok_or(underflow.clone())?;
+        let to = self.stack_pointer.checked_sub(Self::STACK_START).ok_or(underflow.clone())?;
         if from > to {
-            // println!("a");
-            return Err(Fault::SegmentationFault)
+            return Err(underflow)
         }
         if to as usize > self.stack.len() {
-            // println!("b {to} {}", self.stack.len());
-            return Err(Fault::SegmentationFault);
+            return Err(Fault::at(FaultKind::SegmentationFault, self.stack_pointer));
         }
         let bytes = &self.stack[from as usize..to as usize];
 
@@ -107,6 +479,34 @@ impl VM {
     pub fn pop_f32(&mut self) -> Result<f32, Fault> { Ok(f32::from_bits(self.pop_u32()?)) }
     pub fn pop_f64(&mut self) -> Result<f64, Fault> { Ok(f64::from_bits(self.pop_u64()?)) }
 
+    /// pops an integer of any size, zero extended
+    pub fn pop_uint(&mut self, size: IntSize) -> Result<u64, Fault> {
+        Ok(match size {
+            IntSize::I8 => self.pop_u8()? as u64,
+            IntSize::I16 => self.pop_u16()? as u64,
+            IntSize::I32 => self.pop_u32()? as u64,
+            IntSize::I64 => self.pop_u64()?,
+        })
+    }
+
+    /// pops an integer of any size, sign extended
+    pub fn pop_int(&mut self, size: IntSize) -> Result<i64, Fault> {
+        Ok(match size {
+            IntSize::I8 => self.pop_u8()? as i8 as i64,
+            IntSize::I16 => self.pop_u16()? as i16 as i64,
+            IntSize::I32 => self.pop_u32()? as i32 as i64,
+            IntSize::I64 => self.pop_u64()? as i64,
+        })
+    }
+
+    /// pops a float of any size; widening an `f32` is exact, so nothing's lost
+    pub fn pop_float(&mut self, size: FloatSize) -> Result<f64, Fault> {
+        match size {
+            FloatSize::F32 => Ok(self.pop_f32()? as f64),
+            FloatSize::F64 => self.pop_f64(),
+        }
+    }
+
     pub fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), Fault> {
         self.ensure_stack(bytes.len() as u64)?;
 
@@ -128,30 +528,135 @@ impl VM {
 
     pub fn push_i64(&mut self, value: i64) -> Result<(), Fault> { self.push_u64(value as u64) }
 
+    /// pushes the low `size` bytes of `value`
+    pub fn push_int(&mut self, size: IntSize, value: u64) -> Result<(), Fault> {
+        self.push_bytes(&value.to_le_bytes()[..size.bytes() as usize])
+    }
+
+    /// where the top `len` bytes of the stack are in `stack`
+    fn top_of_stack(&self, len: u64) -> Result<std::ops::Range<usize>, Fault> {
+        let underflow = Fault::at(FaultKind::StackUnderflow, self.stack_pointer);
+        let to = self.stack_pointer.checked_sub(Self::STACK_START).ok_or(underflow.clone())?;
+        let from = to.checked_sub(len).ok_or(underflow)?;
+        if to as usize > self.stack.len() {
+            return Err(Fault::at(FaultKind::SegmentationFault, self.stack_pointer));
+        }
+        Ok(from as usize..to as usize)
+    }
+
+    /// pushes a copy of the `width` bytes that end `offset` bytes below the top, so an offset of 0 duplicates the top
+    pub fn pick(&mut self, width: u64, offset: u64) -> Result<(), Fault> {
+        let region = self.top_of_stack(offset.saturating_add(width))?;
+        self.ensure_stack(width)?;
+        self.stack.copy_within(region.start..region.start + width as usize, region.end);
+        self.stack_pointer += width;
+        Ok(())
+    }
+
+    /// moves the `width` byte item `items - 1` items below the top up to the top, shifting the ones above it down
+    pub fn rotate(&mut self, width: u64, items: u64) -> Result<(), Fault> {
+        let region = self.top_of_stack(items.saturating_mul(width))?;
+        if !region.is_empty() {
+            self.stack[region].rotate_left(width as usize);
+        }
+        Ok(())
+    }
+
     pub fn ensure_stack(&mut self, required_size: u64) -> Result<(), Fault> {
-        // println!("ensuring stack for {required_size} bytes");
         let current_usage = self.stack_pointer - Self::STACK_START;
-        // println!("currently using {current_usage} bytes");
-        if current_usage + required_size >= self.max_stack {
-            // println!("too big, stack overflow");
-            return Err(Fault::StackOverflow);
+        let required_usage = current_usage.checked_add(required_size).filter(|&usage| usage < self.max_stack);
+        let Some(required_usage) = required_usage else {
+            return Err(Fault::at(FaultKind::StackOverflow, self.stack_pointer));
+        };
+        if (self.stack.len() as u64) < required_usage {
+            self.stack.resize(required_usage as usize, 0);
+        }
+        Ok(())
+    }
+
+    pub fn frame_address(&self, offset: i64) -> Result<u64, Fault> {
+        self.frame_pointer.checked_add_signed(offset).ok_or(Fault::at(FaultKind::SegmentationFault, self.frame_pointer))
+    }
+
+    /// jumps somewhere in the program, or just past the end of it to exit
+    fn jump(&mut self, target: u64) -> Result<(), Fault> {
+        if target > self.program.len() as u64 {
+            return Err(Fault::at(FaultKind::InvalidJump, target));
         }
-        if (self.stack.len() as u64) >= current_usage + required_size {
-            // println!("current backing array len: {} needed: {}", self.stack.len(), current_usage + required_size);
-            return Ok(());
+        self.program_counter = target;
+        Ok(())
+    }
+
+    /// runs one instruction; any fault it causes is reported at that instruction, with the program counter, stack
+    /// pointer and frame pointer put back to how they were before it
+    ///
+    /// an instruction that faults doesn't use any fuel or leave anything in the journal either, so ticking it again
+    /// (after adding fuel, say) runs and records it once
+    pub fn tick(&mut self) -> Result<(), Fault> {
+        let (pc, sp, fp) = (self.program_counter, self.stack_pointer, self.frame_pointer);
+        let mut cost = 0;
+        if let (Some(fuel), Some(instruction)) = (self.fuel, self.program.get(pc as usize)) {
+            cost = self.costs.as_ref().map_or(1, |costs| costs.cost(instruction));
+            if cost > fuel {
+                return Err(Fault { kind: FaultKind::OutOfFuel, pc, address: None });
+            }
         }
-        for _ in 0..(current_usage + required_size) - self.stack.len() as u64 {
-            self.stack.push(0);
+        if let (Some(tracer), Some(&instruction)) = (&mut self.tracer, self.program.get(pc as usize)) {
+            tracer.trace(&TraceEvent { pc, instruction, stack_pointer: self.stack_pointer, frame_pointer: self.frame_pointer });
         }
+        let journaled = self.journal.position();
+        self.step().map_err(|fault| {
+            (self.program_counter, self.stack_pointer, self.frame_pointer) = (pc, sp, fp);
+            self.journal.rewind(journaled);
+            Fault { pc, ..fault }
+        })?;
+        // a host function can change the fuel while the instruction runs
+        if let Some(fuel) = &mut self.fuel {
+            *fuel = fuel.saturating_sub(cost);
+        }
+        self.executed += 1;
         Ok(())
     }
 
+    /// runs until the program ends or faults (including running out of fuel)
+    pub fn run(&mut self) -> Result<(), Fault> {
+        if self.tracer.is_none() {
+            match self.engine {
+                Engine::Interpreter => {}
+                Engine::Fast => return fast::run(self),
+                #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+                Engine::Jit => return jit::run(self),
+                #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+                Engine::Jit => return fast::run(self),
+            }
+        }
+        loop {
+            match self.tick() {
+                Ok(()) => {}
+                Err(Fault { kind: FaultKind::ProgramEnded, .. }) => return Ok(()),
+                Err(fault) => return Err(fault),
+            }
+        }
+    }
+
+    /// like `run`, but stops after at most `instructions` instructions
+    pub fn run_for(&mut self, instructions: u64) -> Result<Progress, Fault> {
+        for _ in 0..instructions {
+            match self.tick() {
+                Ok(()) => {}
+                Err(Fault { kind: FaultKind::ProgramEnded, .. }) => return Ok(Progress::Ended),
+                Err(fault) => return Err(fault),
+            }
+        }
+        Ok(Progress::Paused)
+    }
+
     // actual code
-    pub fn tick(&mut self) -> Result<(), Fault> {
+    fn step(&mut self) -> Result<(), Fault> {
         let pc = self.program_counter;
         let instruction = match self.program.get(pc as usize) {
             Some(v) => v,
-            None => return Err(Fault::ProgramEnded),
+            None => return Err(Fault::new(FaultKind::ProgramEnded)),
         };
         self.program_counter += 1;
 
@@ -180,6 +685,14 @@ impl VM {
                     IntSize::I64 => { let $b = self.pop_u64()? as i64; let $a = self.pop_u64()? as i64; self.push_u64(($action) as u64)?; }
                 }
             };
+            (signed int biop $size:expr; $a:ident, $b:ident => $ret:ident $action:expr) => {
+                match $size {
+                    IntSize::I8 => { let $b = self.pop_u8()? as i8; let $a = self.pop_u8()? as i8; self.$ret($action)?; }
+                    IntSize::I16 => { let $b = self.pop_u16()? as i16; let $a = self.pop_u16()? as i16; self.$ret($action)?; }
+                    IntSize::I32 => { let $b = self.pop_u32()? as i32; let $a = self.pop_u32()? as i32; self.$ret($action)?; }
+                    IntSize::I64 => { let $b = self.pop_u64()? as i64; let $a = self.pop_u64()? as i64; self.$ret($action)?; }
+                }
+            };
             (float biop $size:expr; $a:ident, $b:ident => $action:expr) => {
                 match $size {
                     FloatSize::F32 => { let $b = self.pop_f32()?; let $a = self.pop_f32()?; self.push_f32($action)?; }
@@ -195,7 +708,7 @@ impl VM {
         }
 
         match instruction {
-            Instruction::And(size) => sizes!(int biop size; a, b => a + b),
+            Instruction::And(size) => sizes!(int biop size; a, b => a & b),
             Instruction::Or(size) => sizes!(int biop size; a, b => a | b),
             Instruction::Xor(size) => sizes!(int biop size; a, b => a ^ b),
             Instruction::Not(size) => match size {
@@ -204,85 +717,263 @@ impl VM {
                 IntSize::I32 => { let v = self.pop_u32()?; self.push_u32(!v)?; }
                 IntSize::I64 => { let v = self.pop_u64()?; self.push_u64(!v)?; }
             },
-            Instruction::Shl(size) => sizes!(int biop size; a, b => a << b),
-            Instruction::Shr(size) => sizes!(signed int biop size; a, b => a >> b),
-            Instruction::UShr(size) => sizes!(int biop size; a, b => a >> b),
-            Instruction::Add(size) => sizes!(int biop size; a, b => a + b),
+            // shifting by the width or more shifts everything out, rather than wrapping the amount
+            Instruction::Shl(size) => sizes!(int biop size; a, b => shift_amount(b as u64).and_then(|b| a.checked_shl(b)).unwrap_or(0)),
+            Instruction::Shr(size) => sizes!(signed int biop size; a, b => shift_amount(b as u64).and_then(|b| a.checked_shr(b)).unwrap_or(if a < 0 { -1 } else { 0 })),
+            Instruction::UShr(size) => sizes!(int biop size; a, b => shift_amount(b as u64).and_then(|b| a.checked_shr(b)).unwrap_or(0)),
+            Instruction::ZeroExtend { from, to } | Instruction::Truncate { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_uint(from)?;
+                self.push_int(to, v)?;
+            }
+            Instruction::SignExtend { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_int(from)?;
+                self.push_int(to, v as u64)?;
+            }
+            // converting straight to the target size, since going through f64 could round twice
+            Instruction::IntToFloat { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_int(from)?;
+                match to {
+                    FloatSize::F32 => self.push_f32(v as f32)?,
+                    FloatSize::F64 => self.push_f64(v as f64)?,
+                }
+            }
+            Instruction::UIntToFloat { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_uint(from)?;
+                match to {
+                    FloatSize::F32 => self.push_f32(v as f32)?,
+                    FloatSize::F64 => self.push_f64(v as f64)?,
+                }
+            }
+            // `as` saturates and turns NaN into 0, which is what these are documented to do
+            Instruction::FloatToInt { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_float(from)?;
+                let int = match to {
+                    IntSize::I8 => v as i8 as u64,
+                    IntSize::I16 => v as i16 as u64,
+                    IntSize::I32 => v as i32 as u64,
+                    IntSize::I64 => v as i64 as u64,
+                };
+                self.push_int(to, int)?;
+            }
+            Instruction::FloatToUInt { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_float(from)?;
+                let int = match to {
+                    IntSize::I8 => v as u8 as u64,
+                    IntSize::I16 => v as u16 as u64,
+                    IntSize::I32 => v as u32 as u64,
+                    IntSize::I64 => v as u64,
+                };
+                self.push_int(to, int)?;
+            }
+            Instruction::FloatConvert { from, to } => {
+                let (from, to) = (*from, *to);
+                let v = self.pop_float(from)?;
+                match to {
+                    FloatSize::F32 => self.push_f32(v as f32)?,
+                    FloatSize::F64 => self.push_f64(v)?,
+                }
+            }
+            Instruction::Add(size) => sizes!(int biop size; a, b => a.wrapping_add(b)),
             Instruction::Addf(size) => sizes!(float biop size; a, b => a + b),
-            Instruction::Sub(size) => sizes!(int biop size; a, b => a - b),
+            Instruction::Sub(size) => sizes!(int biop size; a, b => a.wrapping_sub(b)),
             Instruction::Subf(size) => sizes!(float biop size; a, b => a - b),
-            Instruction::Mul(size) => sizes!(int biop size; a, b => a * b),
+            Instruction::Mul(size) => sizes!(int biop size; a, b => a.wrapping_mul(b)),
             Instruction::Mulf(size) => sizes!(float biop size; a, b => a * b),
-            Instruction::Div(size) => sizes!(int biop size; a, b => a / b),
+            Instruction::Div(size) => sizes!(int biop size; a, b => a.checked_div(b).ok_or(Fault::new(FaultKind::DivisionByZero))?),
             Instruction::Divf(size) => sizes!(float biop size; a, b => a / b),
-            Instruction::Mod(size) => sizes!(int biop size; a, b => a % b),
+            Instruction::Mod(size) => sizes!(int biop size; a, b => a.checked_rem(b).ok_or(Fault::new(FaultKind::DivisionByZero))?),
             Instruction::Modf(size) => sizes!(float biop size; a, b => a % b),
+            Instruction::SDiv(size) => sizes!(signed int biop size; a, b => if b == 0 { return Err(Fault::new(FaultKind::DivisionByZero)) } else { a.wrapping_div(b) }),
+            Instruction::SMod(size) => sizes!(signed int biop size; a, b => if b == 0 { return Err(Fault::new(FaultKind::DivisionByZero)) } else { a.wrapping_rem(b) }),
+            Instruction::AddChecked(size) => sizes!(int biop size; a, b => a.checked_add(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::SAddChecked(size) => sizes!(signed int biop size; a, b => a.checked_add(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::SubChecked(size) => sizes!(int biop size; a, b => a.checked_sub(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::SSubChecked(size) => sizes!(signed int biop size; a, b => a.checked_sub(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::MulChecked(size) => sizes!(int biop size; a, b => a.checked_mul(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::SMulChecked(size) => sizes!(signed int biop size; a, b => a.checked_mul(b).ok_or(Fault::new(FaultKind::Overflow))?),
+            Instruction::SDivChecked(size) => sizes!(signed int biop size; a, b => if b == 0 { return Err(Fault::new(FaultKind::DivisionByZero)) } else { a.checked_div(b).ok_or(Fault::new(FaultKind::Overflow))? }),
+            Instruction::AddSat(size) => sizes!(int biop size; a, b => a.saturating_add(b)),
+            Instruction::SAddSat(size) => sizes!(signed int biop size; a, b => a.saturating_add(b)),
+            Instruction::SubSat(size) => sizes!(int biop size; a, b => a.saturating_sub(b)),
+            Instruction::SSubSat(size) => sizes!(signed int biop size; a, b => a.saturating_sub(b)),
+            Instruction::MulSat(size) => sizes!(int biop size; a, b => a.saturating_mul(b)),
+            Instruction::SMulSat(size) => sizes!(signed int biop size; a, b => a.saturating_mul(b)),
+            Instruction::SDivSat(size) => sizes!(signed int biop size; a, b => if b == 0 { return Err(Fault::new(FaultKind::DivisionByZero)) } else { a.saturating_div(b) }),
             Instruction::Cmp(size) => {
-                sizes!(int biop size; a, b => push_i64 match &a.cmp(&b) {  // TODO: i64?
+                sizes!(int biop size; a, b => push_i64 match &a.cmp(&b) {
+                    std::cmp::Ordering::Less => -1,
+                    std::cmp::Ordering::Equal => 0,
+                    std::cmp::Ordering::Greater => 1,
+                });
+            },
+            Instruction::SCmp(size) => {
+                sizes!(signed int biop size; a, b => push_i64 match &a.cmp(&b) {
                     std::cmp::Ordering::Less => -1,
                     std::cmp::Ordering::Equal => 0,
                     std::cmp::Ordering::Greater => 1,
                 });
             },
             Instruction::Cmpf(size) => {
-                sizes!(float biop size; a, b => push_i64 match &a.partial_cmp(&b) {  // TODO: i64?
+                sizes!(float biop size; a, b => push_i64 match &a.partial_cmp(&b) {
                     Some(std::cmp::Ordering::Less) => -1,
                     Some(std::cmp::Ordering::Equal) => 0,
                     Some(std::cmp::Ordering::Greater) => 1,
-                    None => todo!("float compare failed, TODO: figure out return for this, or fault?")
+                    // there's no ordering to push that makes every comparison false
+                    None => return Err(Fault::new(FaultKind::UnorderedComparison)),
                 });
             },
-            Instruction::Jmp(addr) => { self.program_counter = *addr as u64; }
+            Instruction::Jmp(addr) => { self.jump(*addr as u64)?; }
             Instruction::Jz(size) => {
                 let v = match size {
                     IntSize::I8 => self.pop_u8()? as u64,
                     IntSize::I16 => self.pop_u16()? as u64,
                     IntSize::I32 => self.pop_u32()? as u64,
-                    IntSize::I64 => self.pop_u64()? as u64,
+                    IntSize::I64 => self.pop_u64()?,
                 };
                 let addr = self.pop_u64()?;
                 if v == 0 {
-                    self.program_counter = addr;
+                    self.jump(addr)?;
                 }
             }
+            Instruction::Call(addr) => {
+                let addr = *addr as u64;
+                self.push_u64(self.program_counter)?;
+                self.jump(addr)?;
+            }
+            Instruction::CallHost(id) => { let id = *id; self.call_host(id)?; }
+            Instruction::Ret => { let addr = self.pop_u64()?; self.jump(addr)?; }
+            Instruction::Enter(size) => {
+                let size = *size;
+                self.push_u64(self.frame_pointer)?;
+                self.frame_pointer = self.stack_pointer;
+                self.ensure_stack(size)?;
+                let start = (self.stack_pointer - Self::STACK_START) as usize;
+                self.stack[start..start + size as usize].fill(0);
+                self.stack_pointer += size;
+            }
+            Instruction::Leave => {
+                // the frame pointer could be anything by now, and the stack pointer always has to be inside the stack
+                if !(Self::STACK_START..=Self::STACK_START + self.stack.len() as u64).contains(&self.frame_pointer) {
+                    return Err(Fault::at(FaultKind::StackUnderflow, self.frame_pointer));
+                }
+                self.stack_pointer = self.frame_pointer;
+                self.frame_pointer = self.pop_u64()?;
+            }
+            Instruction::LoadLocal { offset, size } => { let s = *size; let addr = self.frame_address(*offset)?; match s {
+                IntSize::I8 => self.push_u8(self.get_u8(addr)?)?,
+                IntSize::I16 => self.push_u16(self.get_u16(addr)?)?,
+                IntSize::I32 => self.push_u32(self.get_u32(addr)?)?,
+                IntSize::I64 => self.push_u64(self.get_u64(addr)?)?,
+            } }
+            Instruction::StoreLocal { offset, size } => { let s = *size; let addr = self.frame_address(*offset)?; match s {
+                IntSize::I8 => { let v = self.pop_u8()?; self.set_u8(addr, v)? }
+                IntSize::I16 => { let v = self.pop_u16()?; self.set_u16(addr, v)? }
+                IntSize::I32 => { let v = self.pop_u32()?; self.set_u32(addr, v)? }
+                IntSize::I64 => { let v = self.pop_u64()?; self.set_u64(addr, v)? }
+            } }
             Instruction::Read => {
                 let fd = self.pop_u32()?;
                 let dst_start = self.pop_u64()?;
                 let max_len = self.pop_u16()?;
-                let mut buf = vec![0; max_len as usize];
-
-                match fd {
-                    0 => {
-                        // TODO: test harness
-                        let read = match stdin().read(buf.as_mut_slice()) {
-                            Ok(v) => { self.set_bytes(dst_start, &buf[0..v])?; v as u16 }
-                            Err(_) => { u16::MAX }
-                        };
-                        self.push_u16(read)?;
-                    }
-                    _ => todo!("reading from file descriptors other than 0 (stdin) is not yet supported")
-                }
+
+                let (result, buf) = self.host_io(Instruction::Read.opcode(), |host| {
+                    let mut buf = vec![0; max_len as usize];
+                    let result = host.read(fd, &mut buf);
+                    buf.truncate(*result.as_ref().unwrap_or(&0));
+                    (result.map(|v| v as u64), buf)
+                })?;
+                let read = match result {
+                    Ok(v) if v <= max_len as u64 => { self.set_bytes(dst_start, &buf)?; v as u16 }
+                    Ok(_) => return Err(Fault::host_error("read more bytes than were asked for")),
+                    Err(IoError::Unsupported) => return Err(Fault::new(FaultKind::UnsupportedIo)),
+                    Err(_) => u16::MAX,
+                };
+                self.push_u16(read)?;
             }
             Instruction::Write => {
                 let fd = self.pop_u32()?;
                 let src_start = self.pop_u64()?;
                 let max_len = self.pop_u16()?;
-                let buf = self.get_bytes(src_start, max_len as u64)?;
-
-                match fd {
-                    1 => {
-                        // TODO: test harness
-                        let written = match stdout().write(&buf) {
-                            Ok(v) => v as u16,
-                            Err(_) => u16::MAX,
-                        };
-                        self.push_u16(written)?;
-                    }
-                    _ => todo!("writing to file descriptors other than 1 (stdout) is not yet supported")
-                }
+                let buf = self.get_bytes(src_start, max_len as u64)?.to_vec();
+
+                let (result, _) = self.host_io(Instruction::Write.opcode(), |host| (host.write(fd, &buf).map(|v| v as u64), vec![]))?;
+                let written = match result {
+                    Ok(v) if v <= max_len as u64 => v as u16,
+                    Ok(_) => return Err(Fault::host_error("wrote more bytes than it was given")),
+                    Err(IoError::Unsupported) => return Err(Fault::new(FaultKind::UnsupportedIo)),
+                    Err(_) => u16::MAX,
+                };
+                self.push_u16(written)?;
+            }
+            Instruction::Open => {
+                let flags = self.pop_u8()?;
+                let path_start = self.pop_u64()?;
+                let path_len = self.pop_u16()?;
+                let path = self.get_bytes(path_start, path_len as u64)?.to_vec();
+
+                let (result, _) = self.host_io(Instruction::Open.opcode(), |host| {
+                    let result = match (String::from_utf8(path), OpenFlags::from_bits(flags)) {
+                        (Ok(path), Some(flags)) => host.open(&path, flags).map(|fd| fd as u64),
+                        (Err(_), _) => Err(IoError::InvalidPath),
+                        (_, None) => Err(IoError::InvalidArgument),
+                    };
+                    (result, vec![])
+                })?;
+                self.push_u32(io_result(result)? as u32)?;
+            }
+            Instruction::Close => {
+                let fd = self.pop_u32()?;
+                let (result, _) = self.host_io(Instruction::Close.opcode(), |host| (host.close(fd).map(|_| 0), vec![]))?;
+                self.push_u32(io_result(result)? as u32)?;
+            }
+            Instruction::Seek => {
+                let fd = self.pop_u32()?;
+                let origin = self.pop_u8()?;
+                let offset = self.pop_u64()? as i64;
+
+                let pos = match origin {
+                    0 => u64::try_from(offset).map(SeekFrom::Start).map_err(|_| IoError::InvalidArgument),
+                    1 => Ok(SeekFrom::Current(offset)),
+                    2 => Ok(SeekFrom::End(offset)),
+                    _ => Err(IoError::InvalidArgument),
+                };
+                let (result, _) = self.host_io(Instruction::Seek.opcode(), |host| (pos.and_then(|pos| host.seek(fd, pos)), vec![]))?;
+                self.push_i64(io_result(result)?)?;
+            }
+            Instruction::Stat => {
+                let fd = self.pop_u32()?;
+                let (result, _) = self.host_io(Instruction::Stat.opcode(), |host| (host.size(fd), vec![]))?;
+                self.push_i64(io_result(result)?)?;
             }
             Instruction::Push(v) => self.push_u8(*v)?,
+            Instruction::PushImm { size, value } => { let bytes = value.to_le_bytes(); self.push_bytes(&bytes[..size.bytes() as usize])? }
+            Instruction::PushImmf { size, bits } => { let bytes = bits.to_le_bytes(); self.push_bytes(&bytes[..size.bytes() as usize])? }
+            Instruction::PushConst(index) => {
+                let index = *index;
+                let len = self.constants.get(index as usize).ok_or(Fault::new(FaultKind::UnknownConstant(index)))?.len();
+                // like `push_bytes`, but copying straight out of the pool
+                self.ensure_stack(len as u64)?;
+                let start = (self.stack_pointer - Self::STACK_START) as usize;
+                self.stack[start..start + len].copy_from_slice(&self.constants[index as usize]);
+                self.stack_pointer += len as u64;
+            }
             Instruction::Pop(n) => { self.pop_bytes(*n as u64)?; }
+            Instruction::Dup(size) => self.pick(size.bytes(), 0)?,
+            Instruction::Swap(size) => self.rotate(size.bytes(), 2)?,
+            Instruction::Over(size) => self.pick(size.bytes(), size.bytes())?,
+            Instruction::Rot(size) => self.rotate(size.bytes(), 3)?,
+            Instruction::Pick { size, depth } => self.pick(size.bytes(), depth.saturating_mul(size.bytes()))?,
+            Instruction::DupBytes(width) => self.pick(*width, 0)?,
+            Instruction::SwapBytes(width) => self.rotate(*width, 2)?,
+            Instruction::OverBytes(width) => self.pick(*width, *width)?,
+            Instruction::RotBytes(width) => self.rotate(*width, 3)?,
+            Instruction::PickBytes { width, offset } => self.pick(*width, *offset)?,
             Instruction::Load { size } => { let s = *size; let addr = self.pop_u64()?; match s {
                 IntSize::I8 => self.push_u8(self.get_u8(addr)?)?,
                 IntSize::I16 => self.push_u16(self.get_u16(addr)?)?,
@@ -297,9 +988,335 @@ impl VM {
             } }
             Instruction::PushSP => { self.push_u64(self.stack_pointer)? }
             Instruction::PushIP => { self.push_u64(self.program_counter)? }
+            Instruction::PushFP => { self.push_u64(self.frame_pointer)? }
             Instruction::PushMaxHeapSize => { self.push_u64(self.main_memory.len() as u64)? }
         }
 
         Ok(())
     }
 }
+
+/// a shift amount as `checked_shl` and `checked_shr` take it; negative amounts come out too big, like any other
+/// amount of at least the width
+fn shift_amount(amount: u64) -> Option<u32> {
+    u32::try_from(amount).ok()
+}
+
+/// what an I/O instruction pushes: the result, or a negative error code
+fn io_result(result: Result<u64, IoError>) -> Result<i64, Fault> {
+    match result {
+        Ok(v) => Ok(v as i64),
+        Err(IoError::Unsupported) => Err(Fault::new(FaultKind::UnsupportedIo)),
+        Err(e) => Ok(-e.code()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::asm::assemble;
+    use host::SharedBuffer;
+
+    fn vm(text: &str, input: &str) -> VM {
+        VM::load(&assemble(text).unwrap(), 16, 256).unwrap().with_host(FdTable::buffered(input, SharedBuffer::new()))
+    }
+
+    /// reads up to 3 bytes from stdin to `dst`
+    fn read_to(dst: u64) -> String {
+        format!("PUSHH 3\nPUSHD {dst}\nPUSHW 0\nREAD\n")
+    }
+
+    #[test]
+    fn faults_are_reported_with_the_registers_put_back() {
+        let mut vm = vm("PUSHB 1\nPUSHB 0\nDIVB\n", "");
+        let fault = vm.run().unwrap_err();
+        assert_eq!(fault, Fault { kind: FaultKind::DivisionByZero, pc: 2, address: None });
+        assert_eq!((vm.program_counter, vm.stack_pointer(), vm.instructions_executed()), (2, VM::STACK_START + 2, 2));
+        assert_eq!(vm.tick().unwrap_err(), fault);
+
+        let mut vm = self::vm("JMP 5\n", "");
+        assert_eq!(vm.run().unwrap_err(), Fault { kind: FaultKind::InvalidJump, pc: 0, address: Some(5) });
+        let mut vm = self::vm("POP 1\n", "");
+        assert_eq!(vm.run().unwrap_err().kind, FaultKind::StackUnderflow);
+        let mut vm = self::vm("PUSHD 64\nLOADB\n", "");
+        assert_eq!(vm.run().unwrap_err(), Fault { kind: FaultKind::SegmentationFault, pc: 1, address: Some(64) });
+    }
+
+    #[test]
+    fn fuel_is_only_spent_on_instructions_that_run() {
+        let mut vm = vm("PUSHB 1\nPUSHB 0\nDIVB\n", "");
+        vm.set_fuel(Some(10));
+        assert_eq!(vm.run().unwrap_err().kind, FaultKind::DivisionByZero);
+        assert_eq!(vm.fuel(), Some(8));
+
+        let mut costs = CostTable::uniform(1);
+        costs.set(Instruction::Add(IntSize::I8).opcode(), 5);
+        let mut vm = self::vm("PUSHB 1\nPUSHB 2\nADDB\n", "").with_costs(costs);
+        vm.set_fuel(Some(6));
+        assert_eq!(vm.run().unwrap_err(), Fault { kind: FaultKind::OutOfFuel, pc: 2, address: None });
+        assert_eq!((vm.fuel(), vm.instructions_executed()), (Some(4), 2));
+        vm.add_fuel(1);
+        assert!(matches!(vm.run_for(10), Ok(Progress::Ended)));
+        assert_eq!((vm.fuel(), vm.pop_u8().unwrap()), (Some(0), 3));
+    }
+
+    #[test]
+    fn faulting_io_leaves_nothing_in_the_recording() {
+        // the read itself works, but there's nowhere to put what it read
+        let mut vm = vm(&read_to(64), "abc");
+        vm.record();
+        assert_eq!(vm.run().unwrap_err().kind, FaultKind::SegmentationFault);
+        assert_eq!(vm.take_recording(), Some(Recording::default()));
+
+        let mut vm = self::vm(&read_to(0), "abc");
+        vm.record();
+        vm.run().unwrap();
+        let recording = vm.take_recording().unwrap();
+        assert_eq!(recording.entries, [Entry { at: 3, event: Event::Io { opcode: Instruction::Read.opcode(), result: Ok(3), data: b"abc".to_vec() } }]);
+
+        // replaying it into the broken program faults the same way every time, rather than using up the entry
+        let mut vm = self::vm(&read_to(64), "");
+        vm.replay(recording.clone());
+        vm.run_for(3).unwrap();
+        assert_eq!(vm.tick().unwrap_err().kind, FaultKind::SegmentationFault);
+        assert_eq!(vm.tick().unwrap_err().kind, FaultKind::SegmentationFault);
+
+        let mut vm = self::vm(&read_to(0), "");
+        vm.replay(recording);
+        vm.run().unwrap();
+        assert_eq!(vm.get_bytes(0, 3).unwrap(), b"abc");
+        assert_eq!(vm.pop_u16().unwrap(), 3);
+    }
+
+    /// pushes a byte, then calls a host function that writes it to address 0 and pushes another
+    fn host_call_program() -> VM {
+        let mut vm = vm("PUSHB 5\nCALLHOST 1\nPOP 1\n", "");
+        vm.register_host_function(1, |vm| {
+            let v = vm.pop_u8()?;
+            vm.set_u8(0, v)?;
+            vm.push_u16(0x102)
+        });
+        vm
+    }
+
+    #[test]
+    fn host_calls_replay_without_being_called() {
+        let mut vm = host_call_program();
+        vm.record();
+        vm.run().unwrap();
+        let recording = vm.take_recording().unwrap();
+
+        let mut replayed = self::vm("PUSHB 5\nCALLHOST 1\nPOP 1\n", "");
+        replayed.replay(recording);
+        replayed.run().unwrap();
+        assert_eq!(replayed.stack_pointer(), vm.stack_pointer());
+        assert_eq!((replayed.get_u8(0).unwrap(), replayed.pop_u8().unwrap()), (5, 2));
+    }
+
+    #[test]
+    fn replays_cant_leave_the_registers_anywhere() {
+        let mut vm = host_call_program();
+        vm.record();
+        vm.run().unwrap();
+        let recording = vm.take_recording().unwrap();
+
+        let tampered = |change: fn(&mut Effects)| {
+            let mut recording = recording.clone();
+            let Event::HostCall { effects, .. } = &mut recording.entries[0].event else { unreachable!() };
+            change(effects);
+            let mut vm = self::vm("PUSHB 5\nCALLHOST 1\nPOP 1\n", "");
+            vm.replay(recording);
+            vm.run().map_err(|fault| fault.kind)
+        };
+        assert_eq!(tampered(|effects| effects.stack_pointer = VM::STACK_START + 3), Err(FaultKind::ReplayDivergence));
+        assert_eq!(tampered(|effects| effects.stack_pointer = 0), Err(FaultKind::ReplayDivergence));
+        assert_eq!(tampered(|effects| effects.frame_pointer = VM::STACK_START + 3), Err(FaultKind::ReplayDivergence));
+        assert_eq!(tampered(|effects| effects.program_counter = 4), Err(FaultKind::ReplayDivergence));
+        assert_eq!(tampered(|effects| effects.stack_len = 1 << 20), Err(FaultKind::ReplayDivergence));
+        // anywhere in the program, including just past the end, is fine
+        assert_eq!(tampered(|effects| effects.program_counter = 3), Ok(()));
+    }
+
+    /// runs `program` with `run` and with the interpreter, and checks they fault the same way and leave the VM in
+    /// exactly the same state
+    pub(super) fn same_as_interpreter(program: &Program, stack_len: usize, run: impl Fn(&mut VM) -> Result<(), Fault>) {
+        let load = || VM::load(program, 64, stack_len).unwrap().with_host(FdTable::new());
+        let (mut expected, mut vm) = (load(), load());
+        expected.set_engine(Engine::Interpreter);
+        let listing: String = program.instructions.iter().map(|i| format!("{i}\n")).collect();
+        assert_eq!(run(&mut vm), expected.run(), "result of\n{listing}");
+        assert_eq!(
+            (vm.program_counter, vm.stack_pointer, vm.frame_pointer, vm.executed),
+            (expected.program_counter, expected.stack_pointer, expected.frame_pointer, expected.executed),
+            "pc, sp, fp and instructions executed after\n{listing}",
+        );
+        assert_eq!(vm.stack, expected.stack, "stack after\n{listing}");
+        assert_eq!(vm.main_memory, expected.main_memory, "main memory after\n{listing}");
+    }
+
+    /// xorshift, so the programs are the same every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len() as u64) as usize]
+        }
+    }
+
+    /// the same instruction with random immediates, which stay small enough to land near the stack
+    fn vary(instruction: Instruction, rng: &mut Rng) -> Instruction {
+        let small = |rng: &mut Rng| rng.below(10);
+        match instruction {
+            Instruction::Push(_) => Instruction::Push(rng.next() as u8),
+            Instruction::Pop(_) => Instruction::Pop(small(rng) as usize),
+            Instruction::Enter(_) => Instruction::Enter(rng.below(40)),
+            Instruction::LoadLocal { size, .. } => Instruction::LoadLocal { offset: rng.below(40) as i64 - 20, size },
+            Instruction::StoreLocal { size, .. } => Instruction::StoreLocal { offset: rng.below(40) as i64 - 20, size },
+            Instruction::Pick { size, .. } => Instruction::Pick { size, depth: small(rng) },
+            Instruction::DupBytes(_) => Instruction::DupBytes(small(rng)),
+            Instruction::SwapBytes(_) => Instruction::SwapBytes(small(rng)),
+            Instruction::OverBytes(_) => Instruction::OverBytes(small(rng)),
+            Instruction::RotBytes(_) => Instruction::RotBytes(small(rng)),
+            Instruction::PickBytes { .. } => Instruction::PickBytes { width: small(rng), offset: rng.below(20) },
+            other => other,
+        }
+    }
+
+    /// short programs that push a few awkward values of one size (limits, shift widths, addresses, infinities and
+    /// the odd NaN) and run one or two instructions on them, covering every instruction but control flow, I/O and
+    /// constants
+    pub(super) fn fuzz_programs() -> Vec<Program> {
+        let mut ops: Vec<Instruction> = crate::bytecode::tests::every_instruction().into_iter()
+            .filter(|i| !matches!(i,
+                Instruction::Jmp(_) | Instruction::Jz(_) | Instruction::Call(_) | Instruction::Ret | Instruction::CallHost(_)
+                | Instruction::Read | Instruction::Write | Instruction::Open | Instruction::Close | Instruction::Seek | Instruction::Stat | Instruction::PushConst(_)))
+            .collect();
+        // `every_instruction` has the same instruction with a few different immediates
+        ops.dedup_by_key(|op| vary(*op, &mut Rng(1)));
+        // arithmetic on two NaNs is allowed to differ, and plenty of integers are NaNs as floats, so float arithmetic
+        // only ever gets floats, with at most one NaN, and never comes second
+        let arithmetic = |op: &Instruction| match op {
+            Instruction::Addf(size) | Instruction::Subf(size) | Instruction::Mulf(size) | Instruction::Divf(size) | Instruction::Modf(size) => Some(*size),
+            _ => None,
+        };
+        let seconds: Vec<Instruction> = ops.iter().copied().filter(|op| arithmetic(op).is_none()).collect();
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let mut programs = vec![];
+        for _ in 0..40 {
+            for &op in &ops {
+                let size = match arithmetic(&op) {
+                    Some(FloatSize::F32) => IntSize::I32,
+                    Some(FloatSize::F64) => IntSize::I64,
+                    None => rng.pick(&[IntSize::I8, IntSize::I16, IntSize::I32, IntSize::I64]),
+                };
+                let bits = size.bytes() * 8;
+                let min = 1u64 << (bits - 1);
+                let ints = [0, 1, 2, 3, 5, bits - 1, bits, bits + 1, u64::MAX, min, min - 1, 8, 60, VM::STACK_START, VM::STACK_START + 8];
+                let floats = [0.0, -0.0, 1.5, -2.25, 3e9, 1e19, -1e19, 2e19, f64::INFINITY, f64::NEG_INFINITY];
+                let float_size = match size {
+                    IntSize::I32 => Some(FloatSize::F32),
+                    IntSize::I64 => Some(FloatSize::F64),
+                    _ => None,
+                };
+
+                let mut instructions = vec![];
+                if rng.below(4) == 0 {
+                    instructions.push(Instruction::Enter(rng.below(16)));
+                }
+                let mut nan = false;
+                for _ in 0..1 + rng.below(6) {
+                    instructions.push(match float_size {
+                        Some(size) if arithmetic(&op).is_some() || rng.below(2) == 0 => {
+                            let value = if !nan && rng.below(8) == 0 { nan = true; f64::NAN } else { rng.pick(&floats) };
+                            let bits = if size == FloatSize::F32 { (value as f32).to_bits() as u64 } else { value.to_bits() };
+                            Instruction::PushImmf { size, bits }
+                        }
+                        _ => Instruction::PushImm { size, value: rng.pick(&ints) & size.mask() },
+                    });
+                }
+                instructions.push(vary(op, &mut rng));
+                if rng.below(2) == 0 {
+                    instructions.push(vary(rng.pick(&seconds), &mut rng));
+                }
+                programs.push(Program { instructions, ..Program::default() });
+            }
+        }
+        programs
+    }
+
+    /// programs for the faults and control flow that `fuzz_programs` doesn't reach, with `{s}` standing for each
+    /// integer size, `{bytes}` and `{bits}` for its width and `{min}` for its smallest signed value
+    const EDGE_CASES: &[&str] = &[
+        "PUSH{s} 7\nPUSH{s} 0\nDIV{s}\n",
+        "PUSH{s} 7\nPUSH{s} 0\nMOD{s}\n",
+        "PUSH{s} 7\nPUSH{s} 0\nSDIV{s}\n",
+        "PUSH{s} 7\nPUSH{s} 0\nSMOD{s}\n",
+        "PUSH{s} {min}\nPUSH{s} -1\nSDIV{s}\n",
+        "PUSH{s} {min}\nPUSH{s} -1\nSMOD{s}\n",
+        "PUSH{s} {min}\nPUSH{s} -1\nSDIVCHK{s}\n",
+        "PUSH{s} {min}\nPUSH{s} -1\nSMULCHK{s}\n",
+        "PUSH{s} -1\nPUSH{s} 1\nADDCHK{s}\n",
+        "PUSH{s} 0\nPUSH{s} 1\nSUBCHK{s}\n",
+        "PUSH{s} {min}\nPUSH{s} 1\nSSUBCHK{s}\n",
+        "PUSH{s} -5\nPUSH{s} {bits}\nSHL{s}\n",
+        "PUSH{s} -5\nPUSH{s} {bits}\nSHR{s}\n",
+        "PUSH{s} 5\nPUSH{s} {bits}\nSHR{s}\n",
+        "PUSH{s} -5\nPUSH{s} {bits}\nUSHR{s}\n",
+        "PUSH{s} -5\nPUSH{s} -1\nSHL{s}\n",
+        "PUSHFD 1e30\nFTOID{s}\n",
+        "PUSHFD -1e30\nFTOID{s}\n",
+        "PUSHFD -1\nFTOUD{s}\n",
+        "PUSHD 0x7ff8000000000000\nFTOID{s}\n",
+        "PUSHD 0x7ff8000000000000\nFTOUD{s}\n",
+        "PUSHFS 1e30\nFTOIS{s}\n",
+        "PUSH{s} {min}\nITOF{s}D\nPUSH{s} -1\nUTOF{s}D\n",
+        "PUSHD 0x7ff8000000000000\nPUSHFD 1\nCMPFD\n",
+        "PUSHFD 1\nPUSHD 0x7ff8000000000000\nCMPFD\n",
+        "PUSHFS 1\nPUSHW 0x7fc00000\nCMPFS\n",
+        // a frame whose saved frame pointer has been overwritten
+        "ENTER 8\nPUSHD 12345\nSTLOCD -8\nLEAVE\nLEAVE\n",
+        "ENTER 8\nPUSHD 0\nSTLOCD -8\nLEAVE\nLEAVE\n",
+        "ENTER 0\nPUSHD 8\nPUSHFP\nADDD\nSTLOCD -8\nLEAVE\nLEAVE\n",
+        // growing the stack until it overflows, a byte or a frame at a time
+        ".Lloop:\nPUSH{s} 1\nJMP .Lloop\n",
+        ".Lloop:\nENTER 3\nJMP .Lloop\n",
+        "PUSHD 0\n.Lloop:\nCALL .Lloop\n",
+        // counting down in a loop, with a call each time round
+        "PUSH{s} 40\n.Lloop:\nCALL .Lf\nPUSH{s} 1\nSUB{s}\nPUSHADDR .Lend\nPICKBYTES {bytes}, 8\nJZ{s}\nJMP .Lloop\n\
+         .Lf:\nENTER 8\nPUSH{s} 3\nSTLOC{s} 0\nLDLOC{s} 0\nPUSHD 8\nSTORE{s}\nLEAVE\nRET\n.Lend:\nPUSHSP\nPUSHFP\nPUSHIP\nPUSHMAXHEAP\n",
+        // jumping and returning out of the program
+        "PUSHADDR .Lend\nPUSHB 0\nJZB\nPUSHB 1\n.Lend:\n",
+        "PUSHD 100\nPUSHB 0\nJZB\n",
+        "PUSHD 3\nRET\nPUSHB 1\n",
+        "PUSHD 100\nRET\n",
+        "JMP -1\n",
+        "RET\n",
+    ];
+
+    /// `EDGE_CASES` at every integer size
+    pub(super) fn edge_cases() -> Vec<Program> {
+        let mut programs = vec![];
+        for text in EDGE_CASES {
+            for (suffix, bits) in [("B", 8), ("H", 16), ("W", 32), ("D", 64)] {
+                let text = text.replace("{s}", suffix).replace("{bytes}", &(bits / 8).to_string()).replace("{bits}", &bits.to_string()).replace("{min}", &(i64::MIN >> (64 - bits)).to_string());
+                let program = assemble(&text).unwrap_or_else(|e| panic!("{e} in\n{text}"));
+                if !programs.contains(&program) {
+                    programs.push(program);
+                }
+            }
+        }
+        programs
+    }
+
+}
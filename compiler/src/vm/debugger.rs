@@ -0,0 +1,215 @@
+use std::collections::BTreeSet;
+
+use crate::{bytecode::DebugInfo, source::{FileId, SourceMap}};
+
+use super::{Fault, FaultKind, VM};
+
+/// a range of memory to stop on changes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u64,
+    pub len: u64,
+    /// what was there last time we looked, or `None` if it wasn't readable
+    value: Option<Vec<u8>>,
+}
+
+/// why the debugger handed control back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// about to run the instruction at a breakpoint
+    Breakpoint(u64),
+    /// the instruction at `pc` changed watched memory, at the first of the watchpoints it changed
+    Watchpoint { pc: u64, address: u64, old: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    /// finished the requested number of steps
+    Stepped,
+    Ended,
+    Fault(Fault),
+}
+
+/// breakpoints and watchpoints over a VM, which it runs with `tick`
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    /// the breakpoint we last stopped at, if nothing's run since, so resuming doesn't stop there again
+    stopped_at: Option<u64>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// returns whether there wasn't one there already
+    pub fn add_breakpoint(&mut self, index: u64) -> bool {
+        self.breakpoints.insert(index)
+    }
+
+    pub fn remove_breakpoint(&mut self, index: u64) -> bool {
+        self.breakpoints.remove(&index)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u64> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, vm: &VM, address: u64, len: u64) {
+        let value = vm.get_bytes(address, len).ok().map(|b| b.to_vec());
+        self.watchpoints.push(Watchpoint { address, len, value });
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u64) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.address != address);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// runs one instruction, stopping early only if it faults or changes watched memory
+    fn tick(&mut self, vm: &mut VM) -> Option<Stop> {
+        let pc = vm.program_counter;
+        self.stopped_at = None;
+        match vm.tick() {
+            Ok(()) => {}
+            Err(Fault { kind: FaultKind::ProgramEnded, .. }) => return Some(Stop::Ended),
+            Err(fault) => return Some(Stop::Fault(fault)),
+        }
+        // every watchpoint is brought up to date, so one reported later doesn't stop on a change it saw now
+        let mut stop = None;
+        for watchpoint in &mut self.watchpoints {
+            let new = vm.get_bytes(watchpoint.address, watchpoint.len).ok();
+            if new != watchpoint.value.as_deref() {
+                let new = new.map(|b| b.to_vec());
+                let old = std::mem::replace(&mut watchpoint.value, new.clone());
+                stop = stop.or(Some(Stop::Watchpoint { pc, address: watchpoint.address, old, new }));
+            }
+        }
+        stop
+    }
+
+    /// runs `count` instructions, ignoring breakpoints
+    pub fn step(&mut self, vm: &mut VM, count: u64) -> Stop {
+        for _ in 0..count {
+            if let Some(stop) = self.tick(vm) {
+                return stop;
+            }
+        }
+        Stop::Stepped
+    }
+
+    /// runs until something stops it, carrying on past the breakpoint it just stopped at if there was one
+    pub fn resume(&mut self, vm: &mut VM) -> Stop {
+        loop {
+            let pc = vm.program_counter;
+            if self.breakpoints.contains(&pc) && self.stopped_at != Some(pc) {
+                self.stopped_at = Some(pc);
+                return Stop::Breakpoint(pc);
+            }
+            if let Some(stop) = self.tick(vm) {
+                return stop;
+            }
+        }
+    }
+}
+
+/// the instructions where execution enters a source line, for putting breakpoints on it
+pub fn line_indices(debug: &DebugInfo, sources: &SourceMap, file: FileId, line: usize) -> Vec<u64> {
+    let line_of = |index: usize| {
+        let (_, loc) = debug.locs[index];
+        (loc.file == file).then(|| sources.start(loc).line)
+    };
+    (0..debug.locs.len())
+        .filter(|&i| line_of(i) == Some(line) && (i == 0 || line_of(i - 1) != Some(line)))
+        .map(|i| debug.locs[i].0 as u64)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ast::types::Loc, bytecode::asm::assemble};
+
+    /// stores 1 and then 2 to address 0
+    fn vm() -> VM {
+        let program = assemble("PUSHB 1\nPUSHD 0\nSTOREB\nPUSHB 2\nPUSHD 0\nSTOREB\n").unwrap();
+        VM::load(&program, 16, 256).unwrap()
+    }
+
+    #[test]
+    fn breakpoints_stop_once_each_time_they_are_reached() {
+        let (mut vm, mut debugger) = (vm(), Debugger::new());
+        assert!(debugger.add_breakpoint(3));
+        assert!(!debugger.add_breakpoint(3));
+        assert!(debugger.add_breakpoint(0));
+        assert_eq!(debugger.resume(&mut vm), Stop::Breakpoint(0));
+        assert_eq!(debugger.resume(&mut vm), Stop::Breakpoint(3));
+        assert_eq!(vm.get_u8(0).unwrap(), 1);
+        assert!(debugger.remove_breakpoint(0));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [3]);
+        assert_eq!(debugger.resume(&mut vm), Stop::Ended);
+        assert_eq!(vm.get_u8(0).unwrap(), 2);
+    }
+
+    #[test]
+    fn stepping_ignores_breakpoints() {
+        let (mut vm, mut debugger) = (vm(), Debugger::new());
+        debugger.add_breakpoint(1);
+        assert_eq!(debugger.step(&mut vm, 2), Stop::Stepped);
+        assert_eq!(vm.program_counter, 2);
+        assert_eq!(debugger.step(&mut vm, 10), Stop::Ended);
+        assert_eq!(vm.instructions_executed(), 6);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_instruction_that_changed_them() {
+        let (mut vm, mut debugger) = (vm(), Debugger::new());
+        debugger.add_watchpoint(&vm, 0, 1);
+        // unreadable, so it never changes
+        debugger.add_watchpoint(&vm, 16, 1);
+        assert_eq!(debugger.resume(&mut vm), Stop::Watchpoint { pc: 2, address: 0, old: Some(vec![0]), new: Some(vec![1]) });
+        assert_eq!(debugger.resume(&mut vm), Stop::Watchpoint { pc: 5, address: 0, old: Some(vec![1]), new: Some(vec![2]) });
+        assert_eq!(debugger.resume(&mut vm), Stop::Ended);
+        assert!(debugger.remove_watchpoint(0));
+        assert!(!debugger.remove_watchpoint(0));
+        assert_eq!(debugger.watchpoints().len(), 1);
+    }
+
+    #[test]
+    fn watchpoints_changed_together_stop_once() {
+        let (mut vm, mut debugger) = (vm(), Debugger::new());
+        debugger.add_watchpoint(&vm, 0, 1);
+        debugger.add_watchpoint(&vm, 0, 2);
+        assert_eq!(debugger.resume(&mut vm), Stop::Watchpoint { pc: 2, address: 0, old: Some(vec![0]), new: Some(vec![1]) });
+        assert_eq!(debugger.watchpoints()[1].value, Some(vec![1, 0]));
+        assert_eq!(debugger.resume(&mut vm), Stop::Watchpoint { pc: 5, address: 0, old: Some(vec![1]), new: Some(vec![2]) });
+        assert_eq!(debugger.watchpoints()[1].value, Some(vec![2, 0]));
+        assert_eq!(debugger.resume(&mut vm), Stop::Ended);
+    }
+
+    #[test]
+    fn faults_stop_the_debugger_where_they_happened() {
+        let mut vm = VM::load(&assemble("PUSHB 1\nPOP 2\n").unwrap(), 16, 256).unwrap();
+        let stop = Debugger::new().resume(&mut vm);
+        assert!(matches!(stop, Stop::Fault(Fault { kind: FaultKind::StackUnderflow, pc: 1, .. })), "{stop:?}");
+        assert_eq!(vm.program_counter, 1);
+    }
+
+    #[test]
+    fn line_indices_are_where_lines_are_entered() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("a.ks", "one\ntwo\n");
+        let other = sources.add("b.ks", "one\n");
+        let loc = |file, left| Loc { file, left, right: left + 1 };
+        let debug = DebugInfo {
+            files: vec!["a.ks".to_string(), "b.ks".to_string()],
+            locs: vec![(0, loc(file, 0)), (2, loc(file, 1)), (3, loc(file, 4)), (5, loc(other, 0)), (6, loc(file, 0))],
+        };
+        assert_eq!(line_indices(&debug, &sources, file, 1), [0, 6]);
+        assert_eq!(line_indices(&debug, &sources, file, 2), [3]);
+        assert_eq!(line_indices(&debug, &sources, other, 1), [5]);
+        assert!(line_indices(&debug, &sources, file, 3).is_empty());
+    }
+}
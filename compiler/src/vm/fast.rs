@@ -0,0 +1,723 @@
+//! a faster way to `run` a program, which ends up in exactly the same state as ticking through it would
+//!
+//! the program is decoded once, with sizes and jump targets worked out up front, and the registers live in locals
+//! instead of on the VM. the runs of instructions the code generator emits most are fused into single ops, which
+//! leave the same bytes behind (even the ones above the stack pointer) as the instructions they stand for
+//!
+//! every op checks it can finish before it changes anything, and anything that would fault, or that isn't worth
+//! handling here (I/O, host calls and the like), is handed to `VM::tick` instead. so faults, fuel, recordings and
+//! host functions all behave exactly as they do when ticking. the one thing that can differ is which NaN float
+//! arithmetic on NaNs gives back, which Rust leaves up to the compiler
+
+use std::cmp::Ordering;
+
+use crate::bytecode::{FloatSize, Instruction, IntSize};
+
+use super::{Fault, FaultKind, VM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntOp { Add, Sub, Mul, Div, Mod, SDiv, SMod, And, Or, Xor, Shl, Shr, UShr }
+
+/// the operations with checked and saturating versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arith { Add, Sub, Mul, Div }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FloatOp { Add, Sub, Mul, Div, Mod }
+
+/// an instruction, or a run of them, ready to run; widths are in bytes
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    /// left to `VM::tick`
+    Slow,
+    Int { op: IntOp, width: usize },
+    /// the checked (or saturating) instructions
+    Overflowing { op: Arith, signed: bool, saturate: bool, width: usize },
+    Not(usize),
+    Cmp { signed: bool, width: usize },
+    Float { op: FloatOp, double: bool },
+    Cmpf { double: bool },
+    /// `ZeroExtend`, `SignExtend` and `Truncate`
+    Resize { from: usize, to: usize, signed: bool },
+    IntToFloat { from: usize, signed: bool, double: bool },
+    FloatToInt { double: bool, to: usize, signed: bool },
+    FloatConvert { from: bool, to: bool },
+    Jmp(usize),
+    Jz(usize),
+    Call(usize),
+    Ret,
+    Enter(u64),
+    Leave,
+    LoadLocal { offset: i64, width: usize },
+    StoreLocal { offset: i64, width: usize },
+    /// `Push`, `PushImm` and `PushImmf`, which all push the low `width` bytes of `value`
+    Push { value: u64, width: usize },
+    PushConst(usize),
+    Pop(usize),
+    Pick { width: u64, offset: u64 },
+    Rotate { width: u64, items: u64 },
+    Load(usize),
+    Store(usize),
+    PushSP,
+    PushIP,
+    PushFP,
+    PushMaxHeapSize,
+
+    // the fused ops, which are all on 64 bit values
+    /// `PUSHD value`, `ADDD` (or `SUBD`)
+    AddImm { value: u64, sub: bool },
+    /// `LDLOCD offset`, `PUSHD value`, `ADDD` (or `SUBD`)
+    LocalAddImm { offset: i64, value: u64, sub: bool },
+    /// `LDLOCD from`, `STLOCD to`
+    CopyLocal { from: i64, to: i64 },
+    /// `CMPD` (or `SCMPD`), `PUSHD 63`, `USHRD`, `TRUNCDB`, which is how `<` comes out
+    Less { signed: bool },
+    /// `Less` and then `JZB`
+    JumpUnlessLess { signed: bool },
+    /// `LEAVE`, `RET`
+    LeaveRet,
+}
+
+/// where an instruction starts running from
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// what to run, which might be a fused op standing for `len` instructions
+    op: Op,
+    len: usize,
+    /// the fuel `op` uses
+    cost: u64,
+    /// the instruction on its own, for when `op` can't run
+    single: Op,
+    single_cost: u64,
+}
+
+/// decodes one instruction
+fn single(instruction: &Instruction, program_len: usize, constants: usize) -> Op {
+    use Instruction as I;
+    let width = |size: &IntSize| size.bytes() as usize;
+    let double = |size: &FloatSize| *size == FloatSize::F64;
+    // jumping just past the end is how a program exits
+    let target = |target: i64| usize::try_from(target).ok().filter(|&target| target <= program_len);
+    let int = |op, size| Op::Int { op, width: width(size) };
+    let checked = |op, signed, size| Op::Overflowing { op, signed, saturate: false, width: width(size) };
+    let saturating = |op, signed, size| Op::Overflowing { op, signed, saturate: true, width: width(size) };
+    let float = |op, size| Op::Float { op, double: double(size) };
+
+    match instruction {
+        I::And(size) => int(IntOp::And, size),
+        I::Or(size) => int(IntOp::Or, size),
+        I::Xor(size) => int(IntOp::Xor, size),
+        I::Not(size) => Op::Not(width(size)),
+        I::Shl(size) => int(IntOp::Shl, size),
+        I::Shr(size) => int(IntOp::Shr, size),
+        I::UShr(size) => int(IntOp::UShr, size),
+        I::ZeroExtend { from, to } | I::Truncate { from, to } => Op::Resize { from: width(from), to: width(to), signed: false },
+        I::SignExtend { from, to } => Op::Resize { from: width(from), to: width(to), signed: true },
+        I::IntToFloat { from, to } => Op::IntToFloat { from: width(from), signed: true, double: double(to) },
+        I::UIntToFloat { from, to } => Op::IntToFloat { from: width(from), signed: false, double: double(to) },
+        I::FloatToInt { from, to } => Op::FloatToInt { double: double(from), to: width(to), signed: true },
+        I::FloatToUInt { from, to } => Op::FloatToInt { double: double(from), to: width(to), signed: false },
+        I::FloatConvert { from, to } => Op::FloatConvert { from: double(from), to: double(to) },
+        I::Add(size) => int(IntOp::Add, size),
+        I::Sub(size) => int(IntOp::Sub, size),
+        I::Mul(size) => int(IntOp::Mul, size),
+        I::Div(size) => int(IntOp::Div, size),
+        I::Mod(size) => int(IntOp::Mod, size),
+        I::SDiv(size) => int(IntOp::SDiv, size),
+        I::SMod(size) => int(IntOp::SMod, size),
+        I::Addf(size) => float(FloatOp::Add, size),
+        I::Subf(size) => float(FloatOp::Sub, size),
+        I::Mulf(size) => float(FloatOp::Mul, size),
+        I::Divf(size) => float(FloatOp::Div, size),
+        I::Modf(size) => float(FloatOp::Mod, size),
+        I::AddChecked(size) => checked(Arith::Add, false, size),
+        I::SAddChecked(size) => checked(Arith::Add, true, size),
+        I::SubChecked(size) => checked(Arith::Sub, false, size),
+        I::SSubChecked(size) => checked(Arith::Sub, true, size),
+        I::MulChecked(size) => checked(Arith::Mul, false, size),
+        I::SMulChecked(size) => checked(Arith::Mul, true, size),
+        I::SDivChecked(size) => checked(Arith::Div, true, size),
+        I::AddSat(size) => saturating(Arith::Add, false, size),
+        I::SAddSat(size) => saturating(Arith::Add, true, size),
+        I::SubSat(size) => saturating(Arith::Sub, false, size),
+        I::SSubSat(size) => saturating(Arith::Sub, true, size),
+        I::MulSat(size) => saturating(Arith::Mul, false, size),
+        I::SMulSat(size) => saturating(Arith::Mul, true, size),
+        I::SDivSat(size) => saturating(Arith::Div, true, size),
+        I::Cmp(size) => Op::Cmp { signed: false, width: width(size) },
+        I::SCmp(size) => Op::Cmp { signed: true, width: width(size) },
+        I::Cmpf(size) => Op::Cmpf { double: double(size) },
+        I::Jmp(to) => target(*to).map_or(Op::Slow, Op::Jmp),
+        I::Jz(size) => Op::Jz(width(size)),
+        I::Call(to) => target(*to).map_or(Op::Slow, Op::Call),
+        I::Ret => Op::Ret,
+        I::Enter(size) => Op::Enter(*size),
+        I::Leave => Op::Leave,
+        I::LoadLocal { offset, size } => Op::LoadLocal { offset: *offset, width: width(size) },
+        I::StoreLocal { offset, size } => Op::StoreLocal { offset: *offset, width: width(size) },
+        I::Push(value) => Op::Push { value: *value as u64, width: 1 },
+        I::PushImm { size, value } => Op::Push { value: *value, width: width(size) },
+        I::PushImmf { size, bits } => Op::Push { value: *bits, width: size.bytes() as usize },
+        I::PushConst(index) if (*index as usize) < constants => Op::PushConst(*index as usize),
+        I::Pop(count) => Op::Pop(*count),
+        I::Dup(size) => Op::Pick { width: size.bytes(), offset: 0 },
+        I::Swap(size) => Op::Rotate { width: size.bytes(), items: 2 },
+        I::Over(size) => Op::Pick { width: size.bytes(), offset: size.bytes() },
+        I::Rot(size) => Op::Rotate { width: size.bytes(), items: 3 },
+        I::Pick { size, depth } => Op::Pick { width: size.bytes(), offset: depth.saturating_mul(size.bytes()) },
+        I::DupBytes(width) => Op::Pick { width: *width, offset: 0 },
+        I::SwapBytes(width) => Op::Rotate { width: *width, items: 2 },
+        I::OverBytes(width) => Op::Pick { width: *width, offset: *width },
+        I::RotBytes(width) => Op::Rotate { width: *width, items: 3 },
+        I::PickBytes { width, offset } => Op::Pick { width: *width, offset: *offset },
+        I::Load { size } => Op::Load(width(size)),
+        I::Store { size } => Op::Store(width(size)),
+        I::PushSP => Op::PushSP,
+        I::PushIP => Op::PushIP,
+        I::PushFP => Op::PushFP,
+        I::PushMaxHeapSize => Op::PushMaxHeapSize,
+        _ => Op::Slow,
+    }
+}
+
+/// the fused op starting at the first of `instructions`, and how many it stands for
+fn fused(instructions: &[Instruction]) -> Option<(Op, usize)> {
+    use Instruction as I;
+    use IntSize::{I64, I8};
+    Some(match instructions {
+        [I::Cmp(I64) | I::SCmp(I64), I::PushImm { size: I64, value: 63 }, I::UShr(I64), I::Truncate { from: I64, to: I8 }, I::Jz(I8), ..] =>
+            (Op::JumpUnlessLess { signed: matches!(instructions[0], I::SCmp(_)) }, 5),
+        [I::Cmp(I64) | I::SCmp(I64), I::PushImm { size: I64, value: 63 }, I::UShr(I64), I::Truncate { from: I64, to: I8 }, ..] =>
+            (Op::Less { signed: matches!(instructions[0], I::SCmp(_)) }, 4),
+        [I::LoadLocal { offset, size: I64 }, I::PushImm { size: I64, value }, I::Add(I64) | I::Sub(I64), ..] =>
+            (Op::LocalAddImm { offset: *offset, value: *value, sub: matches!(instructions[2], I::Sub(_)) }, 3),
+        [I::PushImm { size: I64, value }, I::Add(I64) | I::Sub(I64), ..] =>
+            (Op::AddImm { value: *value, sub: matches!(instructions[1], I::Sub(_)) }, 2),
+        [I::LoadLocal { offset: from, size: I64 }, I::StoreLocal { offset: to, size: I64 }, ..] =>
+            (Op::CopyLocal { from: *from, to: *to }, 2),
+        [I::Leave, I::Ret, ..] => (Op::LeaveRet, 2),
+        _ => return None,
+    })
+}
+
+fn decode(vm: &VM) -> Vec<Entry> {
+    let program = &vm.program;
+    let cost = |instruction: &Instruction| vm.costs.as_ref().map_or(1, |costs| costs.cost(instruction));
+    (0..program.len()).map(|pc| {
+        let single = single(&program[pc], program.len(), vm.constants.len());
+        let single_cost = cost(&program[pc]);
+        match fused(&program[pc..]) {
+            Some((op, len)) => {
+                let cost = program[pc..pc + len].iter().fold(0, |total: u64, instruction| total.saturating_add(cost(instruction)));
+                Entry { op, len, cost, single, single_cost }
+            }
+            None => Entry { op: single, len: 1, cost: single_cost, single, single_cost },
+        }
+    }).collect()
+}
+
+/// the parts of the VM ops use, with the stack pointer as an offset into `stack` (which it never goes past)
+///
+/// the stack is moved in here while the machine runs, since otherwise every byte written to it could be the `Vec`'s
+/// own pointer as far as the compiler knows, and it'd have to read that again after every write
+struct Machine<'a> {
+    stack: Vec<u8>,
+    memory: &'a mut [u8],
+    constants: &'a [Vec<u8>],
+    max_stack: u64,
+    program_len: usize,
+    sp: usize,
+    fp: u64,
+    pc: usize,
+}
+
+// one case per width, so each is a single load or store rather than a call to `memcpy`
+fn get(bytes: &[u8], at: usize, width: usize) -> u64 {
+    match width {
+        1 => bytes[at] as u64,
+        2 => u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap()) as u64,
+        4 => u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as u64,
+        _ => u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()),
+    }
+}
+
+fn put(bytes: &mut [u8], at: usize, width: usize, value: u64) {
+    match width {
+        1 => bytes[at] = value as u8,
+        2 => bytes[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+        4 => bytes[at..at + 4].copy_from_slice(&(value as u32).to_le_bytes()),
+        _ => bytes[at..at + 8].copy_from_slice(&value.to_le_bytes()),
+    }
+}
+
+/// sign extends the low `width` bytes of `value`
+fn sext(value: u64, width: usize) -> i64 {
+    let shift = 64 - width as u32 * 8;
+    ((value << shift) as i64) >> shift
+}
+
+fn ordering(ordering: Ordering) -> u64 {
+    match ordering {
+        Ordering::Less => -1i64 as u64,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+/// where some bytes of memory are
+enum Place {
+    Memory(usize),
+    Stack(usize),
+}
+
+impl Machine<'_> {
+    /// where the top `len` bytes of the stack start, if there are that many
+    fn top(&self, len: usize) -> Option<usize> {
+        self.sp.checked_sub(len)
+    }
+
+    /// makes sure the stack has room for `len` more bytes, like `VM::ensure_stack`
+    fn reserve(&mut self, len: usize) -> Option<()> {
+        let end = self.sp.checked_add(len).filter(|&end| (end as u64) < self.max_stack)?;
+        if end > self.stack.len() {
+            self.stack.resize(end, 0);
+        }
+        Some(())
+    }
+
+    fn get(&self, at: usize, width: usize) -> u64 {
+        get(&self.stack, at, width)
+    }
+
+    fn put(&mut self, at: usize, width: usize, value: u64) {
+        put(&mut self.stack, at, width, value)
+    }
+
+    /// pushes the low `width` bytes of `value`
+    fn push(&mut self, width: usize, value: u64) -> Option<()> {
+        self.reserve(width)?;
+        self.put(self.sp, width, value);
+        self.sp += width;
+        Some(())
+    }
+
+    /// replaces the top `popped` bytes of the stack with the low `width` bytes of `value`
+    fn replace(&mut self, popped: usize, width: usize, value: u64) -> Option<()> {
+        self.sp -= popped;
+        self.push(width, value)
+    }
+
+    /// finds `width` bytes at `address` like `VM::get_bytes` would if the stack were `stack_len` bytes long
+    fn locate(&self, address: u64, width: usize, stack_len: usize) -> Option<Place> {
+        let end = address.checked_add(width as u64)?;
+        if end <= self.memory.len() as u64 {
+            Some(Place::Memory(address as usize))
+        } else if address >= VM::STACK_START && end <= VM::STACK_START + stack_len as u64 {
+            Some(Place::Stack((address - VM::STACK_START) as usize))
+        } else {
+            None
+        }
+    }
+
+    fn load(&self, address: u64, width: usize) -> Option<u64> {
+        Some(match self.locate(address, width, self.stack.len())? {
+            Place::Memory(at) => get(self.memory, at, width),
+            Place::Stack(at) => self.get(at, width),
+        })
+    }
+
+    fn store(&mut self, address: u64, width: usize, value: u64) -> Option<()> {
+        match self.locate(address, width, self.stack.len())? {
+            Place::Memory(at) => put(self.memory, at, width, value),
+            Place::Stack(at) => self.put(at, width, value),
+        }
+        Some(())
+    }
+
+    /// runs `op`, which stands for `len` instructions, or does nothing and returns `None` if it can't (though the
+    /// registers might have moved, so they have to be put back)
+    fn run(&mut self, op: Op, len: usize) -> Option<()> {
+        let next = self.pc + 1;
+        self.pc += len;
+        match op {
+            Op::Slow => return None,
+            Op::Int { op, width } => {
+                let at = self.top(2 * width)?;
+                let (a, b) = (self.get(at, width), self.get(at + width, width));
+                let bits = width as u64 * 8;
+                let result = match op {
+                    IntOp::Add => a.wrapping_add(b),
+                    IntOp::Sub => a.wrapping_sub(b),
+                    IntOp::Mul => a.wrapping_mul(b),
+                    IntOp::Div => a.checked_div(b)?,
+                    IntOp::Mod => a.checked_rem(b)?,
+                    IntOp::SDiv if b != 0 => sext(a, width).wrapping_div(sext(b, width)) as u64,
+                    IntOp::SMod if b != 0 => sext(a, width).wrapping_rem(sext(b, width)) as u64,
+                    IntOp::SDiv | IntOp::SMod => return None,
+                    IntOp::And => a & b,
+                    IntOp::Or => a | b,
+                    IntOp::Xor => a ^ b,
+                    IntOp::Shl if b < bits => a << b,
+                    IntOp::UShr if b < bits => a >> b,
+                    IntOp::Shl | IntOp::UShr => 0,
+                    IntOp::Shr => (sext(a, width) >> b.min(63)) as u64,
+                };
+                self.put(at, width, result);
+                self.sp = at + width;
+            }
+            Op::Overflowing { op, signed, saturate, width } => {
+                let at = self.top(2 * width)?;
+                let (a, b) = (self.get(at, width), self.get(at + width, width));
+                let bits = width as u32 * 8;
+                let ((a, b), min, max) = if signed {
+                    ((sext(a, width) as i128, sext(b, width) as i128), -(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+                } else {
+                    ((a as i128, b as i128), 0, (1i128 << bits) - 1)
+                };
+                let exact = match op {
+                    Arith::Add => a + b,
+                    Arith::Sub => a - b,
+                    Arith::Mul => a.saturating_mul(b),
+                    Arith::Div if b != 0 => a / b,
+                    Arith::Div => return None,
+                };
+                let result = match exact {
+                    exact if (min..=max).contains(&exact) => exact,
+                    exact if saturate => exact.clamp(min, max),
+                    _ => return None,
+                };
+                self.put(at, width, result as u64);
+                self.sp = at + width;
+            }
+            Op::Not(width) => {
+                let at = self.top(width)?;
+                self.put(at, width, !self.get(at, width));
+            }
+            Op::Cmp { signed, width } => {
+                let at = self.top(2 * width)?;
+                let (a, b) = (self.get(at, width), self.get(at + width, width));
+                let result = if signed { sext(a, width).cmp(&sext(b, width)) } else { a.cmp(&b) };
+                self.replace(2 * width, 8, ordering(result))?;
+            }
+            Op::Float { op, double } => {
+                let width = if double { 8 } else { 4 };
+                let at = self.top(2 * width)?;
+                let (a, b) = (self.get(at, width), self.get(at + width, width));
+                let result = if double {
+                    let (a, b) = (f64::from_bits(a), f64::from_bits(b));
+                    match op {
+                        FloatOp::Add => a + b,
+                        FloatOp::Sub => a - b,
+                        FloatOp::Mul => a * b,
+                        FloatOp::Div => a / b,
+                        FloatOp::Mod => a % b,
+                    }.to_bits()
+                } else {
+                    let (a, b) = (f32::from_bits(a as u32), f32::from_bits(b as u32));
+                    match op {
+                        FloatOp::Add => a + b,
+                        FloatOp::Sub => a - b,
+                        FloatOp::Mul => a * b,
+                        FloatOp::Div => a / b,
+                        FloatOp::Mod => a % b,
+                    }.to_bits() as u64
+                };
+                self.put(at, width, result);
+                self.sp = at + width;
+            }
+            Op::Cmpf { double } => {
+                let width = if double { 8 } else { 4 };
+                let at = self.top(2 * width)?;
+                let (a, b) = (self.get(at, width), self.get(at + width, width));
+                let result = if double {
+                    f64::from_bits(a).partial_cmp(&f64::from_bits(b))
+                } else {
+                    f32::from_bits(a as u32).partial_cmp(&f32::from_bits(b as u32))
+                };
+                self.replace(2 * width, 8, ordering(result?))?;
+            }
+            Op::Resize { from, to, signed } => {
+                let at = self.top(from)?;
+                let value = self.get(at, from);
+                self.replace(from, to, if signed { sext(value, from) as u64 } else { value })?;
+            }
+            Op::IntToFloat { from, signed, double } => {
+                let at = self.top(from)?;
+                let value = self.get(at, from);
+                let result = match (signed, double) {
+                    (true, true) => (sext(value, from) as f64).to_bits(),
+                    (true, false) => (sext(value, from) as f32).to_bits() as u64,
+                    (false, true) => (value as f64).to_bits(),
+                    (false, false) => (value as f32).to_bits() as u64,
+                };
+                self.replace(from, if double { 8 } else { 4 }, result)?;
+            }
+            Op::FloatToInt { double, to, signed } => {
+                let from = if double { 8 } else { 4 };
+                let at = self.top(from)?;
+                let value = self.get(at, from);
+                let value = if double { f64::from_bits(value) } else { f32::from_bits(value as u32) as f64 };
+                let result = match (signed, to) {
+                    (true, 1) => value as i8 as u64,
+                    (true, 2) => value as i16 as u64,
+                    (true, 4) => value as i32 as u64,
+                    (true, _) => value as i64 as u64,
+                    (false, 1) => value as u8 as u64,
+                    (false, 2) => value as u16 as u64,
+                    (false, 4) => value as u32 as u64,
+                    (false, _) => value as u64,
+                };
+                self.replace(from, to, result)?;
+            }
+            // through an f64 like `VM::pop_float`, even from an f32 to an f32
+            Op::FloatConvert { from, to } => {
+                let width = if from { 8 } else { 4 };
+                let at = self.top(width)?;
+                let value = self.get(at, width);
+                let value = if from { f64::from_bits(value) } else { f32::from_bits(value as u32) as f64 };
+                let (to, result) = if to { (8, value.to_bits()) } else { (4, (value as f32).to_bits() as u64) };
+                self.replace(width, to, result)?;
+            }
+            Op::Jmp(target) => self.pc = target,
+            Op::Jz(width) => {
+                let at = self.top(8 + width)?;
+                if self.get(at + 8, width) == 0 {
+                    self.pc = usize::try_from(self.get(at, 8)).ok().filter(|&target| target <= self.program_len)?;
+                }
+                self.sp = at;
+            }
+            Op::Call(target) => {
+                self.push(8, next as u64)?;
+                self.pc = target;
+            }
+            Op::Ret => {
+                let at = self.top(8)?;
+                self.pc = usize::try_from(self.get(at, 8)).ok().filter(|&target| target <= self.program_len)?;
+                self.sp = at;
+            }
+            Op::Enter(size) => {
+                // both the saved frame pointer and the frame have to fit, before either is written
+                let base = self.sp + 8;
+                let end = (base as u64).checked_add(size).filter(|&end| (base as u64) < self.max_stack && end < self.max_stack)?;
+                let end = usize::try_from(end).ok()?;
+                if end > self.stack.len() {
+                    self.stack.resize(end, 0);
+                }
+                self.put(self.sp, 8, self.fp);
+                self.stack[base..end].fill(0);
+                self.fp = VM::STACK_START + base as u64;
+                self.sp = end;
+            }
+            Op::Leave => self.leave()?,
+            Op::LoadLocal { offset, width } => {
+                let value = self.load(self.fp.checked_add_signed(offset)?, width)?;
+                self.push(width, value)?;
+            }
+            Op::StoreLocal { offset, width } => {
+                let address = self.fp.checked_add_signed(offset)?;
+                let at = self.top(width)?;
+                let value = self.get(at, width);
+                self.store(address, width, value)?;
+                self.sp = at;
+            }
+            Op::Push { value, width } => self.push(width, value)?,
+            Op::PushConst(index) => {
+                let constants = self.constants;
+                let constant = &constants[index];
+                self.reserve(constant.len())?;
+                self.stack[self.sp..self.sp + constant.len()].copy_from_slice(constant);
+                self.sp += constant.len();
+            }
+            Op::Pop(count) => self.sp = self.top(count)?,
+            Op::Pick { width, offset } => {
+                let at = self.top(usize::try_from(offset.saturating_add(width)).ok()?)?;
+                let width = width as usize;
+                self.reserve(width)?;
+                if matches!(width, 1 | 2 | 4 | 8) {
+                    self.put(self.sp, width, self.get(at, width));
+                } else {
+                    self.stack.copy_within(at..at + width, self.sp);
+                }
+                self.sp += width;
+            }
+            Op::Rotate { width, items } => {
+                let at = self.top(usize::try_from(items.saturating_mul(width)).ok()?)?;
+                let width = width as usize;
+                match (items, width) {
+                    (2, 1 | 2 | 4 | 8) => {
+                        let (a, b) = (self.get(at, width), self.get(at + width, width));
+                        self.put(at, width, b);
+                        self.put(at + width, width, a);
+                    }
+                    (3, 1 | 2 | 4 | 8) => {
+                        let (a, b, c) = (self.get(at, width), self.get(at + width, width), self.get(at + 2 * width, width));
+                        self.put(at, width, b);
+                        self.put(at + width, width, c);
+                        self.put(at + 2 * width, width, a);
+                    }
+                    _ if at < self.sp => self.stack[at..self.sp].rotate_left(width),
+                    _ => {}
+                }
+            }
+            Op::Load(width) => {
+                let at = self.top(8)?;
+                let value = self.load(self.get(at, 8), width)?;
+                self.replace(8, width, value)?;
+            }
+            Op::Store(width) => {
+                let at = self.top(8 + width)?;
+                let (value, address) = (self.get(at, width), self.get(at + width, 8));
+                self.store(address, width, value)?;
+                self.sp = at;
+            }
+            Op::PushSP => self.push(8, VM::STACK_START + self.sp as u64)?,
+            Op::PushIP => self.push(8, next as u64)?,
+            Op::PushFP => self.push(8, self.fp)?,
+            Op::PushMaxHeapSize => self.push(8, self.memory.len() as u64)?,
+
+            Op::AddImm { value, sub } => {
+                let at = self.top(8)?;
+                // the immediate is left above the stack pointer
+                self.push(8, value)?;
+                let a = self.get(at, 8);
+                self.put(at, 8, if sub { a.wrapping_sub(value) } else { a.wrapping_add(value) });
+                self.sp = at + 8;
+            }
+            Op::LocalAddImm { offset, value, sub } => {
+                let a = self.load(self.fp.checked_add_signed(offset)?, 8)?;
+                self.reserve(16)?;
+                self.put(self.sp, 8, if sub { a.wrapping_sub(value) } else { a.wrapping_add(value) });
+                self.put(self.sp + 8, 8, value);
+                self.sp += 8;
+            }
+            Op::CopyLocal { from, to } => {
+                let value = self.load(self.fp.checked_add_signed(from)?, 8)?;
+                // the store has to be checked against the stack as it'll be after the load pushes
+                let grown = self.stack.len().max(self.sp + 8);
+                let to = self.fp.checked_add_signed(to)?;
+                self.locate(to, 8, grown)?;
+                self.reserve(8)?;
+                self.put(self.sp, 8, value);
+                self.store(to, 8, value)?;
+            }
+            Op::Less { signed } => {
+                let at = self.top(16)?;
+                let less = self.less(at, signed);
+                self.put(at, 8, less);
+                self.put(at + 8, 8, 63);
+                self.sp = at + 1;
+            }
+            Op::JumpUnlessLess { signed } => {
+                let at = self.top(24)?;
+                let less = self.less(at + 8, signed);
+                if less == 0 {
+                    self.pc = usize::try_from(self.get(at, 8)).ok().filter(|&target| target <= self.program_len)?;
+                }
+                self.put(at + 8, 8, less);
+                self.put(at + 16, 8, 63);
+                self.sp = at;
+            }
+            Op::LeaveRet => {
+                self.leave()?;
+                let at = self.top(8)?;
+                self.pc = usize::try_from(self.get(at, 8)).ok().filter(|&target| target <= self.program_len)?;
+                self.sp = at;
+            }
+        }
+        Some(())
+    }
+
+    /// whether the two 64 bit values at `at` compare less, as 1 or 0
+    fn less(&self, at: usize, signed: bool) -> u64 {
+        let (a, b) = (self.get(at, 8), self.get(at + 8, 8));
+        (if signed { (a as i64) < (b as i64) } else { a < b }) as u64
+    }
+
+    fn leave(&mut self) -> Option<()> {
+        let frame = usize::try_from(self.fp.checked_sub(VM::STACK_START)?).ok().filter(|&frame| frame <= self.stack.len())?;
+        let at = frame.checked_sub(8)?;
+        self.fp = self.get(at, 8);
+        self.sp = at;
+        Some(())
+    }
+}
+
+/// runs `vm` until something has to go through `tick`, and leaves its registers where that is
+fn run_fast(vm: &mut VM, code: &[Entry]) {
+    let sp = vm.stack_pointer.checked_sub(VM::STACK_START).and_then(|sp| usize::try_from(sp).ok());
+    let Some(sp) = sp.filter(|&sp| sp <= vm.stack.len()) else { return };
+    let mut machine = Machine {
+        stack: std::mem::take(&mut vm.stack),
+        memory: &mut vm.main_memory,
+        constants: &vm.constants,
+        max_stack: vm.max_stack,
+        program_len: code.len(),
+        sp,
+        fp: vm.frame_pointer,
+        pc: usize::try_from(vm.program_counter).unwrap_or(usize::MAX),
+    };
+    let (mut executed, mut fuel) = (vm.executed, vm.fuel);
+    let affordable = |cost: u64, fuel: Option<u64>| fuel.is_none_or(|fuel| cost <= fuel);
+    while let Some(entry) = code.get(machine.pc) {
+        let (pc, sp, fp) = (machine.pc, machine.sp, machine.fp);
+        let mut ran = affordable(entry.cost, fuel) && machine.run(entry.op, entry.len).is_some();
+        let (mut len, mut cost) = (entry.len, entry.cost);
+        if !ran && entry.len > 1 {
+            (machine.pc, machine.sp, machine.fp) = (pc, sp, fp);
+            ran = affordable(entry.single_cost, fuel) && machine.run(entry.single, 1).is_some();
+            (len, cost) = (1, entry.single_cost);
+        }
+        if !ran {
+            (machine.pc, machine.sp, machine.fp) = (pc, sp, fp);
+            break;
+        }
+        executed += len as u64;
+        if let Some(fuel) = &mut fuel {
+            *fuel -= cost;
+        }
+    }
+    let (sp, fp, pc) = (machine.sp, machine.fp, machine.pc);
+    vm.stack = machine.stack;
+    vm.stack_pointer = VM::STACK_START + sp as u64;
+    vm.frame_pointer = fp;
+    vm.program_counter = pc as u64;
+    vm.executed = executed;
+    vm.fuel = fuel;
+}
+
+/// runs until the program ends or faults, like `VM::run`
+pub(super) fn run(vm: &mut VM) -> Result<(), Fault> {
+    let code = decode(vm);
+    loop {
+        run_fast(vm, &code);
+        match vm.tick() {
+            Ok(()) => {}
+            Err(Fault { kind: FaultKind::ProgramEnded, .. }) => return Ok(()),
+            Err(fault) => return Err(fault),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::{edge_cases, fuzz_programs, same_as_interpreter};
+
+    #[test]
+    fn every_instruction_runs_like_the_interpreter() {
+        for program in fuzz_programs() {
+            for stack_len in [256, 33] {
+                same_as_interpreter(&program, stack_len, run);
+            }
+        }
+    }
+
+    #[test]
+    fn faults_and_control_flow_are_the_same_as_the_interpreter() {
+        for program in edge_cases() {
+            for stack_len in [256, 33] {
+                same_as_interpreter(&program, stack_len, run);
+            }
+        }
+    }
+}
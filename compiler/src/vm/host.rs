@@ -0,0 +1,553 @@
+use std::{
+    cell::RefCell, collections::HashMap, fs::File, io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf}, rc::Rc,
+};
+
+/// why an I/O operation failed
+#[derive(Debug)]
+pub enum IoError {
+    BadDescriptor,
+    NotReadable,
+    NotWritable,
+    NotFound,
+    PermissionDenied,
+    InvalidPath,
+    InvalidArgument,
+    /// the host doesn't do this kind of I/O at all, which faults the VM instead of returning an error code
+    Unsupported,
+    Io(std::io::Error),
+    /// the descriptor refers to something without a position or size, like a pipe
+    NotSeekable,
+}
+
+impl IoError {
+    /// the code programs see for this error; instructions that can fail push its negation
+    pub fn code(&self) -> i64 {
+        match self {
+            IoError::BadDescriptor => 1,
+            IoError::NotReadable => 2,
+            IoError::NotWritable => 3,
+            IoError::NotFound => 4,
+            IoError::PermissionDenied => 5,
+            IoError::InvalidPath => 6,
+            IoError::InvalidArgument => 7,
+            IoError::Unsupported => 8,
+            IoError::Io(_) => 9,
+            IoError::NotSeekable => 10,
+        }
+    }
+
+    /// the error with a code from `code`, for replaying recorded results; the details of `Io` errors are lost
+    pub fn from_code(code: i64) -> IoError {
+        match code {
+            1 => IoError::BadDescriptor,
+            2 => IoError::NotReadable,
+            3 => IoError::NotWritable,
+            4 => IoError::NotFound,
+            5 => IoError::PermissionDenied,
+            6 => IoError::InvalidPath,
+            7 => IoError::InvalidArgument,
+            8 => IoError::Unsupported,
+            10 => IoError::NotSeekable,
+            _ => IoError::Io(std::io::Error::other("recorded I/O error")),
+        }
+    }
+}
+
+impl From<std::io::Error> for IoError {
+    fn from(e: std::io::Error) -> IoError {
+        match e.kind() {
+            ErrorKind::NotFound => IoError::NotFound,
+            ErrorKind::PermissionDenied => IoError::PermissionDenied,
+            ErrorKind::InvalidInput => IoError::InvalidArgument,
+            _ => IoError::Io(e),
+        }
+    }
+}
+
+/// the flags byte `Open` takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+impl OpenFlags {
+    pub const READ: u8 = 1;
+    pub const WRITE: u8 = 2;
+    pub const CREATE: u8 = 4;
+    pub const TRUNCATE: u8 = 8;
+    pub const APPEND: u8 = 16;
+
+    pub fn from_bits(bits: u8) -> Option<OpenFlags> {
+        if bits & !(Self::READ | Self::WRITE | Self::CREATE | Self::TRUNCATE | Self::APPEND) != 0 {
+            return None;
+        }
+        Some(OpenFlags {
+            read: bits & Self::READ != 0,
+            write: bits & Self::WRITE != 0,
+            create: bits & Self::CREATE != 0,
+            truncate: bits & Self::TRUNCATE != 0,
+            append: bits & Self::APPEND != 0,
+        })
+    }
+
+    /// whether opening with these flags can change the file system
+    pub fn modifies(&self) -> bool {
+        self.write || self.create || self.truncate || self.append
+    }
+}
+
+/// everything a VM does outside of its own memory goes through this, so embedders can decide what programs can touch
+///
+/// only `read` and `write` are required; a host without a file system can leave the rest unsupported
+pub trait Host {
+    fn read(&mut self, fd: u32, buf: &mut [u8]) -> Result<usize, IoError>;
+    fn write(&mut self, fd: u32, buf: &[u8]) -> Result<usize, IoError>;
+    /// opens a path, returning its new descriptor
+    fn open(&mut self, _path: &str, _flags: OpenFlags) -> Result<u32, IoError> { Err(IoError::Unsupported) }
+    fn close(&mut self, _fd: u32) -> Result<(), IoError> { Err(IoError::Unsupported) }
+    /// moves a descriptor's position, returning the new one
+    fn seek(&mut self, _fd: u32, _pos: SeekFrom) -> Result<u64, IoError> { Err(IoError::Unsupported) }
+    /// the size in bytes of whatever a descriptor refers to
+    fn size(&mut self, _fd: u32) -> Result<u64, IoError> { Err(IoError::Unsupported) }
+}
+
+/// something a file descriptor can refer to
+pub trait Stream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, IoError> { Err(IoError::NotReadable) }
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, IoError> { Err(IoError::NotWritable) }
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, IoError> { Err(IoError::NotSeekable) }
+    fn size(&mut self) -> Result<u64, IoError> { Err(IoError::NotSeekable) }
+}
+
+/// a read-only stream
+pub struct Input<R: Read>(pub R);
+
+impl<R: Read> Stream for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> { Ok(self.0.read(buf)?) }
+}
+
+/// a write-only stream
+pub struct Output<W: Write>(pub W);
+
+impl<W: Write> Stream for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(n)
+    }
+}
+
+impl Stream for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> { Ok(Read::read(self, buf)?) }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> { Ok(Write::write(self, buf)?) }
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, IoError> { Ok(Seek::seek(self, pos)?) }
+    fn size(&mut self) -> Result<u64, IoError> { Ok(self.metadata()?.len()) }
+}
+
+/// an in-memory buffer that can be handed to a VM as output and read back afterwards
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Stream for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// which directories programs may open files in; nothing is reachable by default
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    dirs: Vec<(PathBuf, Access)>,
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    /// lets programs open anything inside `dir`, including subdirectories
+    pub fn allow(&mut self, dir: impl AsRef<Path>, access: Access) -> std::io::Result<()> {
+        self.dirs.push((dir.as_ref().canonicalize()?, access));
+        Ok(())
+    }
+
+    /// resolves `path` (following symlinks and `..`) and checks the result is somewhere it's allowed to be
+    pub fn check(&self, path: &str, flags: OpenFlags) -> Result<PathBuf, IoError> {
+        if path.is_empty() || path.contains('\0') {
+            return Err(IoError::InvalidPath);
+        }
+        let path = Path::new(path);
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            // it might be about to be created, so only its directory has to exist
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // unless it's a symlink to something that doesn't exist, which opening would create wherever it points
+                if path.symlink_metadata().is_ok() {
+                    return Err(IoError::PermissionDenied);
+                }
+                let name = path.file_name().ok_or(IoError::InvalidPath)?;
+                let parent = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                parent.canonicalize()?.join(name)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if self.permits(&resolved, flags) { Ok(resolved) } else { Err(IoError::PermissionDenied) }
+    }
+
+    /// whether an already resolved path is somewhere it's allowed to be
+    fn permits(&self, resolved: &Path, flags: OpenFlags) -> bool {
+        self.root(resolved, flags).is_some()
+    }
+
+    /// the allowed directory an already resolved path is in
+    fn root(&self, resolved: &Path, flags: OpenFlags) -> Option<&Path> {
+        self.dirs.iter()
+            .find(|(dir, access)| resolved.starts_with(dir) && (*access == Access::ReadWrite || !flags.modifies()))
+            .map(|(dir, _)| dir.as_path())
+    }
+}
+
+/// what opening files needs from <fcntl.h>, <errno.h> and <unistd.h>, some of which differs between architectures, since
+/// there's no libc dependency to get it from
+#[cfg(target_os = "linux")]
+mod sys {
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64"))]
+    pub const O_NOFOLLOW: i32 = 0o100000;
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64")))]
+    pub const O_NOFOLLOW: i32 = 0o400000;
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64"))]
+    pub const O_DIRECTORY: i32 = 0o40000;
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc", target_arch = "powerpc64")))]
+    pub const O_DIRECTORY: i32 = 0o200000;
+    pub const O_WRONLY: i32 = 0o1;
+    pub const O_RDWR: i32 = 0o2;
+    pub const O_CREAT: i32 = 0o100;
+    pub const O_EXCL: i32 = 0o200;
+    pub const O_APPEND: i32 = 0o2000;
+    pub const O_CLOEXEC: i32 = 0o2000000;
+    pub const O_PATH: i32 = 0o10000000;
+    pub const AT_FDCWD: i32 = -100;
+    pub const ENOTDIR: i32 = 20;
+    pub const EEXIST: i32 = 17;
+    pub const ELOOP: i32 = 40;
+
+    extern "C" {
+        pub fn openat(dirfd: i32, path: *const std::ffi::c_char, flags: i32, ...) -> i32;
+        pub fn unlinkat(dirfd: i32, path: *const std::ffi::c_char, flags: i32) -> i32;
+    }
+}
+#[cfg(target_os = "linux")]
+use sys::*;
+
+/// where an open file actually is, which can differ from the path it was opened with if something was swapped for a
+/// symlink in between
+#[cfg(target_os = "linux")]
+fn opened_path(file: &File) -> std::io::Result<PathBuf> {
+    use std::os::fd::AsRawFd;
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+/// opens `name` in the directory `dir`, without following it if it's a symlink
+#[cfg(target_os = "linux")]
+fn open_at(dir: i32, name: &std::ffi::CStr, flags: i32) -> std::io::Result<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    let fd = unsafe { openat(dir, name.as_ptr(), flags | O_NOFOLLOW | O_CLOEXEC, 0o666) };
+    if fd < 0 { Err(std::io::Error::last_os_error()) } else { Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) }) }
+}
+
+/// opens `path`, which `Policy::check` resolved, one component at a time from the allowed directory it's in, so that
+/// swapping something on the way for a symlink after the check can't lead out of it
+#[cfg(target_os = "linux")]
+fn open_beneath(policy: &Policy, path: &Path, flags: OpenFlags) -> Result<File, IoError> {
+    use std::{ffi::CString, os::{fd::AsRawFd, unix::ffi::OsStrExt}};
+    // a symlink where the check found none means something was swapped
+    let swapped = |e: std::io::Error| match e.raw_os_error() {
+        Some(ELOOP | ENOTDIR) => IoError::PermissionDenied,
+        _ => e.into(),
+    };
+    let access = match (flags.read, flags.write || flags.append) {
+        (true, true) => O_RDWR,
+        (false, true) => O_WRONLY,
+        (true, false) if !flags.create => 0,
+        _ => return Err(IoError::InvalidArgument),
+    } | if flags.append { O_APPEND } else { 0 };
+
+    let root = policy.root(path, flags).ok_or(IoError::PermissionDenied)?;
+    let root_name = CString::new(root.as_os_str().as_bytes()).map_err(|_| IoError::InvalidPath)?;
+    let mut dir = open_at(AT_FDCWD, &root_name, O_PATH | O_DIRECTORY).map_err(swapped)?;
+    let mut names = path.strip_prefix(root).map_err(|_| IoError::PermissionDenied)?.iter()
+        .map(|name| CString::new(name.as_bytes()).map_err(|_| IoError::InvalidPath))
+        .collect::<Result<Vec<_>, _>>()?;
+    let name = names.pop().unwrap_or_else(|| c".".into());
+    for component in &names {
+        dir = open_at(dir.as_raw_fd(), component, O_PATH | O_DIRECTORY).map_err(swapped)?;
+    }
+
+    // creating exclusively first, to know whether this call made the file and has to clean it up
+    let (file, created) = match open_at(dir.as_raw_fd(), &name, access | if flags.create { O_CREAT | O_EXCL } else { 0 }) {
+        Ok(file) => (file, flags.create),
+        Err(e) if flags.create && e.raw_os_error() == Some(EEXIST) => (open_at(dir.as_raw_fd(), &name, access).map_err(swapped)?, false),
+        Err(e) => return Err(swapped(e)),
+    };
+    let file = File::from(file);
+    // a directory on the way can still be moved out from under the policy while it's open
+    if !policy.permits(&opened_path(&file)?, flags) {
+        if created {
+            unsafe { unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) };
+        }
+        return Err(IoError::PermissionDenied);
+    }
+    Ok(file)
+}
+
+/// the usual host: a table of open file descriptors
+#[derive(Default)]
+pub struct FdTable {
+    fds: HashMap<u32, Box<dyn Stream>>,
+    policy: Policy,
+}
+
+impl FdTable {
+    /// a table with nothing open, so programs can't do any I/O
+    pub fn new() -> FdTable {
+        FdTable::default()
+    }
+
+    /// stdin, stdout and stderr on descriptors 0, 1 and 2
+    pub fn stdio() -> FdTable {
+        let mut table = FdTable::new();
+        table.insert(0, Input(std::io::stdin()));
+        table.insert(1, Output(std::io::stdout()));
+        table.insert(2, Output(std::io::stderr()));
+        table
+    }
+
+    /// in-memory stdin, with stdout and stderr both going to `output`
+    pub fn buffered(input: impl Into<Vec<u8>>, output: SharedBuffer) -> FdTable {
+        let mut table = FdTable::new();
+        table.insert(0, Input(Cursor::new(input.into())));
+        table.insert(1, output.clone());
+        table.insert(2, output);
+        table
+    }
+
+    /// opens `stream` on `fd`, returning whatever was there before
+    pub fn insert(&mut self, fd: u32, stream: impl Stream + 'static) -> Option<Box<dyn Stream>> {
+        self.fds.insert(fd, Box::new(stream))
+    }
+
+    pub fn remove(&mut self, fd: u32) -> Option<Box<dyn Stream>> {
+        self.fds.remove(&fd)
+    }
+
+    /// sets which directories `open` can reach
+    pub fn with_policy(mut self, policy: Policy) -> FdTable {
+        self.policy = policy;
+        self
+    }
+
+    fn get(&mut self, fd: u32) -> Result<&mut Box<dyn Stream>, IoError> {
+        self.fds.get_mut(&fd).ok_or(IoError::BadDescriptor)
+    }
+}
+
+impl Host for FdTable {
+    fn read(&mut self, fd: u32, buf: &mut [u8]) -> Result<usize, IoError> { self.get(fd)?.read(buf) }
+    fn write(&mut self, fd: u32, buf: &[u8]) -> Result<usize, IoError> { self.get(fd)?.write(buf) }
+
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<u32, IoError> {
+        let path = self.policy.check(path, flags)?;
+        // the path can change between checking and opening it, so the file is checked again once it's open, and only
+        // truncated after that. this is the same rule `OpenOptions` has for truncating
+        if flags.truncate && (!flags.write || flags.append) {
+            return Err(IoError::InvalidArgument);
+        }
+        #[cfg(target_os = "linux")]
+        let file = open_beneath(&self.policy, &path, flags)?;
+        #[cfg(not(target_os = "linux"))]
+        let file = std::fs::OpenOptions::new().read(flags.read).write(flags.write).create(flags.create).append(flags.append).open(path)?;
+        if flags.truncate {
+            file.set_len(0)?;
+        }
+        // like POSIX, the lowest descriptor that isn't in use
+        let fd = (0..=u32::MAX).find(|fd| !self.fds.contains_key(fd)).ok_or_else(|| IoError::Io(std::io::Error::other("out of file descriptors")))?;
+        self.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: u32) -> Result<(), IoError> {
+        self.remove(fd).map(|_| ()).ok_or(IoError::BadDescriptor)
+    }
+
+    fn seek(&mut self, fd: u32, pos: SeekFrom) -> Result<u64, IoError> { self.get(fd)?.seek(pos) }
+    fn size(&mut self, fd: u32) -> Result<u64, IoError> { self.get(fd)?.size() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh directory for one test, with `allowed` and `outside` directories in it
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kitchen-sink-host-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("allowed")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        dir
+    }
+
+    fn table(dir: &Path, access: Access) -> FdTable {
+        let mut policy = Policy::new();
+        policy.allow(dir.join("allowed"), access).unwrap();
+        FdTable::new().with_policy(policy)
+    }
+
+    fn flags(bits: u8) -> OpenFlags {
+        OpenFlags::from_bits(bits).unwrap()
+    }
+
+    fn path(dir: &Path, rest: &str) -> String {
+        dir.join(rest).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn open_flags_reject_unknown_bits() {
+        assert_eq!(flags(OpenFlags::READ | OpenFlags::APPEND), OpenFlags { read: true, append: true, ..OpenFlags::default() });
+        assert!(OpenFlags::from_bits(32).is_none());
+    }
+
+    #[test]
+    fn files_inside_allowed_dirs_can_be_used() {
+        let dir = scratch("inside");
+        let mut host = table(&dir, Access::ReadWrite);
+        let file = path(&dir, "allowed/file");
+        let fd = host.open(&file, flags(OpenFlags::WRITE | OpenFlags::CREATE)).unwrap();
+        assert_eq!(fd, 0);
+        assert_eq!(host.write(fd, b"hello").unwrap(), 5);
+        assert_eq!(host.open(&file, flags(OpenFlags::READ)).unwrap(), 1);
+        let mut buf = [0; 8];
+        assert_eq!(host.read(1, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(host.seek(1, SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(host.size(1).unwrap(), 5);
+        host.close(0).unwrap();
+        assert!(matches!(host.close(0), Err(IoError::BadDescriptor)));
+        // the lowest free descriptor gets reused
+        assert_eq!(host.open(&file, flags(OpenFlags::WRITE | OpenFlags::TRUNCATE)).unwrap(), 0);
+        assert_eq!(host.size(0).unwrap(), 0);
+        assert!(matches!(host.open(&file, flags(OpenFlags::READ | OpenFlags::TRUNCATE)), Err(IoError::InvalidArgument)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_allowed_dirs_are_refused() {
+        let dir = scratch("outside");
+        std::fs::write(dir.join("outside/secret"), "secret").unwrap();
+        let mut host = table(&dir, Access::ReadWrite);
+        for file in ["outside/secret", "allowed/../outside/secret", "allowed/../outside/new"] {
+            assert!(matches!(host.open(&path(&dir, file), flags(OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE)), Err(IoError::PermissionDenied)), "{file}");
+        }
+        assert!(!dir.join("outside/new").exists());
+        assert!(matches!(host.open("", flags(OpenFlags::READ)), Err(IoError::InvalidPath)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_dirs_refuse_anything_that_modifies() {
+        let dir = scratch("read-only");
+        std::fs::write(dir.join("allowed/file"), "contents").unwrap();
+        let mut host = table(&dir, Access::ReadOnly);
+        let file = path(&dir, "allowed/file");
+        assert_eq!(host.open(&file, flags(OpenFlags::READ)).unwrap(), 0);
+        for bits in [OpenFlags::WRITE, OpenFlags::APPEND, OpenFlags::READ | OpenFlags::CREATE, OpenFlags::WRITE | OpenFlags::TRUNCATE] {
+            assert!(matches!(host.open(&file, flags(bits)), Err(IoError::PermissionDenied)), "{bits}");
+        }
+        assert_eq!(std::fs::read_to_string(dir.join("allowed/file")).unwrap(), "contents");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_allowed_dirs_are_refused() {
+        let dir = scratch("symlinks");
+        std::fs::write(dir.join("outside/secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside/secret"), dir.join("allowed/link")).unwrap();
+        // dangling, so creating through it would make a file wherever it points
+        std::os::unix::fs::symlink(dir.join("outside/new"), dir.join("allowed/dangling")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("allowed/dir")).unwrap();
+        let mut host = table(&dir, Access::ReadWrite);
+        for file in ["allowed/link", "allowed/dangling", "allowed/dir/secret", "allowed/dir/new"] {
+            let opened = host.open(&path(&dir, file), flags(OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE));
+            assert!(matches!(opened, Err(IoError::PermissionDenied)), "{file}");
+        }
+        assert!(!dir.join("outside/new").exists());
+        assert_eq!(std::fs::read_to_string(dir.join("outside/secret")).unwrap(), "secret");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn directories_swapped_for_symlinks_after_checking_are_refused() {
+        let dir = scratch("swapped");
+        std::fs::create_dir(dir.join("allowed/sub")).unwrap();
+        let mut policy = Policy::new();
+        policy.allow(dir.join("allowed"), Access::ReadWrite).unwrap();
+        let flags = flags(OpenFlags::WRITE | OpenFlags::CREATE);
+        let resolved = policy.check(&path(&dir, "allowed/sub/new"), flags).unwrap();
+        // what `open` does between checking and opening
+        std::fs::remove_dir(dir.join("allowed/sub")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("allowed/sub")).unwrap();
+        assert!(matches!(open_beneath(&policy, &resolved, flags), Err(IoError::PermissionDenied)));
+        assert!(!dir.join("outside/new").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn opened_files_are_checked_where_they_really_are() {
+        let dir = scratch("opened");
+        std::fs::write(dir.join("allowed/file"), "").unwrap();
+        let file = File::open(dir.join("allowed/file")).unwrap();
+        let real = opened_path(&file).unwrap();
+        assert_eq!(real, dir.join("allowed/file").canonicalize().unwrap());
+        // moved out from under the policy after opening
+        std::fs::rename(dir.join("allowed/file"), dir.join("outside/file")).unwrap();
+        let mut policy = Policy::new();
+        policy.allow(dir.join("allowed"), Access::ReadOnly).unwrap();
+        assert!(!policy.permits(&opened_path(&file).unwrap(), OpenFlags::default()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in 1..=10 {
+            assert_eq!(IoError::from_code(code).code(), code);
+        }
+    }
+}
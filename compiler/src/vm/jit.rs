@@ -0,0 +1,1122 @@
+//! compiling hot stretches of bytecode to x86-64 machine code, for `Engine::Jit`
+//!
+//! `run` interprets the program with `tick`, counting how often each instruction is reached. once one gets hot, a
+//! region starting there (up to the end of the program, or `REGION_LEN` instructions) is compiled and run natively
+//! from then on whenever the program counter is inside it. jumps, calls and returns to anywhere in the region stay in
+//! native code; anything else leaves it
+//!
+//! native code works on the VM's own main memory and stack, with the same layout, and keeps the registers in machine
+//! registers. like the ops in `fast`, each compiled instruction checks everything that could make it fault before it
+//! changes anything, and hands itself back to `tick` instead if it would, so faults (and anything the compiler doesn't
+//! handle, like I/O or f32s) happen exactly as they do in the interpreter. as with `fast`, which NaN arithmetic on two
+//! NaNs gives back can differ
+//!
+//! native code can't grow the stack's `Vec`, so the stack gets some spare capacity before native code runs, and
+//! pushes past that go through `tick` too. there's no fuel counting in native code, so with a fuel limit `run` does
+//! what `Engine::Fast` does
+
+use std::ffi::c_void;
+
+use crate::bytecode::{FloatSize, Instruction, IntSize};
+
+use super::{Fault, FaultKind, VM};
+
+/// how many times an instruction has to be reached before a region starting at it is compiled
+const HOT: u32 = 50;
+/// the most instructions a region covers
+const REGION_LEN: usize = 4096;
+/// how much spare capacity the stack gets before running native code
+const STACK_HEADROOM: usize = 0x10000;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+/// what native code gets and hands back; the field offsets are baked into the code
+#[repr(C)]
+struct Context {
+    stack: *mut u8,
+    /// the stack pointer as an offset into `stack`
+    sp: u64,
+    fp: u64,
+    /// the furthest into `stack` a push can reach, which is inside both its capacity and the stack limit
+    limit: u64,
+    /// how much of `stack` is in use, which native code can grow up to `limit`
+    len: u64,
+    memory: *mut u8,
+    memory_len: u64,
+    executed: u64,
+    /// where the program carries on from, set by native code on the way out
+    pc: u64,
+}
+
+const SP: i32 = 8;
+const FP: i32 = 16;
+const LIMIT: i32 = 24;
+const LEN: i32 = 32;
+const MEMORY: i32 = 40;
+const MEMORY_LEN: i32 = 48;
+const EXECUTED: i32 = 56;
+const PC: i32 = 64;
+
+/// what native code returns: it jumped somewhere outside its region...
+const LEFT: u32 = 0;
+/// ...or it stopped at an instruction for `tick` to run
+const TICK: u32 = 1;
+
+/// the entry point of a region, which starts running it at the instruction its second argument points at
+type Entry = unsafe extern "sysv64" fn(*mut Context, u64) -> u64;
+
+/// the registers native code names, each numbered as it's encoded (it never names rsp, which only calls, pushes and
+/// pops move, and has no use for r8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg { Rax = 0, Rcx = 1, Rdx = 2, Rbx = 3, Rbp = 5, Rsi = 6, Rdi = 7, R9 = 9, R10 = 10, R11 = 11, R12 = 12, R13 = 13, R14 = 14, R15 = 15 }
+
+use Reg::*;
+
+// what the registers are for in native code: the rest are scratch
+const CONTEXT: Reg = Rbp;
+const STACK: Reg = Rbx;
+const STACK_POINTER: Reg = R12;
+const FRAME_POINTER: Reg = R13;
+const STACK_LIMIT: Reg = R14;
+const STACK_LEN: Reg = R15;
+const EXECUTED_COUNT: Reg = R11;
+const MEMORY_BASE: Reg = R10;
+const MEMORY_SIZE: Reg = R9;
+
+/// the registers native code has to give back as it found them
+const CALLEE_SAVED: [Reg; 6] = [Rbx, Rbp, R12, R13, R14, R15];
+
+/// `[base + index * 2^scale + disp]`
+#[derive(Debug, Clone, Copy)]
+struct Mem {
+    base: Reg,
+    index: Option<(Reg, u8)>,
+    disp: i32,
+}
+
+fn at(base: Reg, disp: i32) -> Mem {
+    Mem { base, index: None, disp }
+}
+
+fn indexed(base: Reg, index: Reg, disp: i32) -> Mem {
+    Mem { base, index: Some((index, 0)), disp }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Alu { Add, Or, And, Sub, Xor, Cmp }
+
+impl Alu {
+    /// the opcode extension for the immediate forms, which is also where the opcodes of the others come from
+    fn extension(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Cond { O = 0, B = 2, AE = 3, E = 4, NE = 5, A = 7, S = 8, P = 0xa, L = 0xc, G = 0xf }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Label(usize);
+
+/// just enough of an x86-64 assembler for the code below
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// where a rel32 to a label has to be filled in
+    jumps: Vec<(usize, Label)>,
+    /// where the address of the jump table has to be filled in, once it's known
+    table_addresses: Vec<usize>,
+}
+
+impl Assembler {
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.jumps.push((self.code.len(), label));
+        self.bytes(&[0; 4]);
+    }
+
+    /// an instruction with a ModRM byte: any mandatory prefix, a REX prefix if it's needed, the opcode, and then
+    /// `reg` (a register or opcode extension) and `rm` as ModRM and SIB bytes and a displacement
+    fn modrm(&mut self, prefix: &[u8], wide: bool, opcode: &[u8], reg: u8, rm: Operand) {
+        self.bytes(prefix);
+        let (b, x) = match rm {
+            Operand::Reg(r) => (r as u8 >> 3, 0),
+            Operand::Mem(mem) => (mem.base as u8 >> 3, mem.index.map_or(0, |(index, _)| index as u8 >> 3)),
+        };
+        let rex = (wide as u8) << 3 | (reg >> 3) << 2 | x << 1 | b;
+        if rex != 0 {
+            self.bytes(&[0x40 | rex]);
+        }
+        self.bytes(opcode);
+        let reg = (reg & 7) << 3;
+        match rm {
+            Operand::Reg(r) => self.bytes(&[0xc0 | reg | (r as u8 & 7)]),
+            // always with a 32 bit displacement, which also covers the bases that can't go without one
+            Operand::Mem(Mem { base, index, disp }) => {
+                match index {
+                    None if base as u8 & 7 != 4 => self.bytes(&[0x80 | reg | (base as u8 & 7)]),
+                    None => self.bytes(&[0x80 | reg | 4, 0x24]),
+                    Some((index, scale)) => self.bytes(&[0x80 | reg | 4, scale << 6 | (index as u8 & 7) << 3 | (base as u8 & 7)]),
+                }
+                self.bytes(&disp.to_le_bytes());
+            }
+        }
+    }
+
+    fn mov(&mut self, dst: Reg, src: Reg) {
+        self.modrm(&[], true, &[0x89], src as u8, Operand::Reg(dst));
+    }
+
+    fn mov_imm(&mut self, dst: Reg, value: u64) {
+        if let Ok(value) = u32::try_from(value) {
+            // writing the low half zeroes the rest
+            if dst as u8 >= 8 {
+                self.bytes(&[0x41]);
+            }
+            self.bytes(&[0xb8 + (dst as u8 & 7)]);
+            self.bytes(&value.to_le_bytes());
+        } else {
+            self.bytes(&[0x48 | (dst as u8 >> 3), 0xb8 + (dst as u8 & 7)]);
+            self.bytes(&value.to_le_bytes());
+        }
+    }
+
+    /// `movabs` with the address of the jump table, which gets filled in later
+    fn mov_table_address(&mut self, dst: Reg) {
+        self.bytes(&[0x48 | (dst as u8 >> 3), 0xb8 + (dst as u8 & 7)]);
+        self.table_addresses.push(self.code.len());
+        self.bytes(&[0; 8]);
+    }
+
+    /// stores a sign extended 32 bit immediate
+    fn store_imm(&mut self, dst: Mem, value: i32) {
+        self.modrm(&[], true, &[0xc7], 0, Operand::Mem(dst));
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// loads `width` bytes, zero extended
+    fn load(&mut self, dst: Reg, src: Mem, width: u64) {
+        let src = Operand::Mem(src);
+        match width {
+            1 => self.modrm(&[], false, &[0x0f, 0xb6], dst as u8, src),
+            2 => self.modrm(&[], false, &[0x0f, 0xb7], dst as u8, src),
+            4 => self.modrm(&[], false, &[0x8b], dst as u8, src),
+            _ => self.modrm(&[], true, &[0x8b], dst as u8, src),
+        }
+    }
+
+    /// loads `width` bytes, sign extended
+    fn load_signed(&mut self, dst: Reg, src: Mem, width: u64) {
+        let src = Operand::Mem(src);
+        match width {
+            1 => self.modrm(&[], true, &[0x0f, 0xbe], dst as u8, src),
+            2 => self.modrm(&[], true, &[0x0f, 0xbf], dst as u8, src),
+            4 => self.modrm(&[], true, &[0x63], dst as u8, src),
+            _ => self.modrm(&[], true, &[0x8b], dst as u8, src),
+        }
+    }
+
+    /// stores the low `width` bytes of `src`, which has to be one of the first four registers for single bytes
+    fn store(&mut self, dst: Mem, src: Reg, width: u64) {
+        let dst = Operand::Mem(dst);
+        match width {
+            1 => self.modrm(&[], false, &[0x88], src as u8, dst),
+            2 => self.modrm(&[0x66], false, &[0x89], src as u8, dst),
+            4 => self.modrm(&[], false, &[0x89], src as u8, dst),
+            _ => self.modrm(&[], true, &[0x89], src as u8, dst),
+        }
+    }
+
+    fn lea(&mut self, dst: Reg, src: Mem) {
+        self.modrm(&[], true, &[0x8d], dst as u8, Operand::Mem(src));
+    }
+
+    fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.modrm(&[], true, &[op.extension() << 3 | 1], src as u8, Operand::Reg(dst));
+    }
+
+    fn alu_imm(&mut self, op: Alu, dst: Reg, value: i32) {
+        self.modrm(&[], true, &[0x81], op.extension(), Operand::Reg(dst));
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn test(&mut self, a: Reg, b: Reg) {
+        self.modrm(&[], true, &[0x85], b as u8, Operand::Reg(a));
+    }
+
+    fn imul(&mut self, dst: Reg, src: Reg) {
+        self.modrm(&[], true, &[0x0f, 0xaf], dst as u8, Operand::Reg(src));
+    }
+
+    /// one of the `F7` group: `not`, `neg`, `mul`, `div` and `idiv` are 2, 3, 4, 6 and 7
+    fn unary(&mut self, extension: u8, operand: Reg) {
+        self.modrm(&[], true, &[0xf7], extension, Operand::Reg(operand));
+    }
+
+    /// sign extends `rax` into `rdx`
+    fn cqo(&mut self) {
+        self.bytes(&[0x48, 0x99]);
+    }
+
+    /// shifts by `cl`: `shl`, `shr` and `sar` are 4, 5 and 7
+    fn shift(&mut self, extension: u8, dst: Reg) {
+        self.modrm(&[], true, &[0xd3], extension, Operand::Reg(dst));
+    }
+
+    fn shift_imm(&mut self, extension: u8, dst: Reg, amount: u8) {
+        self.modrm(&[], true, &[0xc1], extension, Operand::Reg(dst));
+        self.bytes(&[amount]);
+    }
+
+    fn cmov(&mut self, cond: Cond, dst: Reg, src: Reg) {
+        self.modrm(&[], true, &[0x0f, 0x40 + cond as u8], dst as u8, Operand::Reg(src));
+    }
+
+    fn jcc(&mut self, cond: Cond, label: Label) {
+        self.bytes(&[0x0f, 0x80 + cond as u8]);
+        self.rel32(label);
+    }
+
+    fn jmp(&mut self, label: Label) {
+        self.bytes(&[0xe9]);
+        self.rel32(label);
+    }
+
+    /// `jmp [table + index * 8]`
+    fn jmp_table(&mut self, table: Reg, index: Reg) {
+        self.modrm(&[], false, &[0xff], 4, Operand::Mem(Mem { base: table, index: Some((index, 3)), disp: 0 }));
+    }
+
+    fn push(&mut self, reg: Reg) {
+        if reg as u8 >= 8 {
+            self.bytes(&[0x41]);
+        }
+        self.bytes(&[0x50 + (reg as u8 & 7)]);
+    }
+
+    fn pop(&mut self, reg: Reg) {
+        if reg as u8 >= 8 {
+            self.bytes(&[0x41]);
+        }
+        self.bytes(&[0x58 + (reg as u8 & 7)]);
+    }
+
+    fn ret(&mut self) {
+        self.bytes(&[0xc3]);
+    }
+
+    fn movsd_load(&mut self, dst: u8, src: Mem) {
+        self.modrm(&[0xf2], false, &[0x0f, 0x10], dst, Operand::Mem(src));
+    }
+
+    fn movsd_store(&mut self, dst: Mem, src: u8) {
+        self.modrm(&[0xf2], false, &[0x0f, 0x11], src, Operand::Mem(dst));
+    }
+
+    /// a scalar double operation with a memory operand: `add`, `mul`, `sub` and `div` are `58`, `59`, `5C` and `5E`
+    fn sse(&mut self, opcode: u8, dst: u8, src: Mem) {
+        self.modrm(&[0xf2], false, &[0x0f, opcode], dst, Operand::Mem(src));
+    }
+
+    fn ucomisd(&mut self, a: u8, b: Mem) {
+        self.modrm(&[0x66], false, &[0x0f, 0x2e], a, Operand::Mem(b));
+    }
+
+    fn cvtsi2sd(&mut self, dst: u8, src: Reg) {
+        self.modrm(&[0xf2], true, &[0x0f, 0x2a], dst, Operand::Reg(src));
+    }
+
+    fn cvttsd2si(&mut self, dst: Reg, src: Mem) {
+        self.modrm(&[0xf2], true, &[0x0f, 0x2c], dst as u8, Operand::Mem(src));
+    }
+
+    /// moves the low 64 bits of an SSE register to a general one
+    fn movq_from_xmm(&mut self, dst: Reg, src: u8) {
+        self.modrm(&[0x66], true, &[0x0f, 0x7e], src, Operand::Reg(dst));
+    }
+
+    fn finish(&mut self) {
+        for &(at, label) in &self.jumps {
+            let target = self.labels[label.0].expect("every label used is bound");
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+    }
+}
+
+/// where the top `depth` bytes of the stack start
+fn slot(depth: u64) -> Mem {
+    indexed(STACK, STACK_POINTER, -(depth as i32))
+}
+
+/// compiles the instructions of `program` from `start` to `end` into a region
+struct Compiler<'a> {
+    asm: Assembler,
+    program: &'a [Instruction],
+    start: usize,
+    end: usize,
+    /// where each instruction's code starts
+    pcs: Vec<Label>,
+    dispatch: Label,
+    exit: Label,
+    /// the code that hands an instruction to `tick`, emitted after everything else
+    ticks: Vec<(Label, usize)>,
+}
+
+impl Compiler<'_> {
+    fn fail(&mut self, pc: usize) -> Label {
+        let label = self.asm.label();
+        self.ticks.push((label, pc));
+        label
+    }
+
+    /// leaves the region at `pc`, which has to be a valid place to be
+    fn leave(&mut self, pc: usize, status: u32) {
+        self.asm.store_imm(at(CONTEXT, PC), pc as i32);
+        self.asm.mov_imm(Rax, status as u64);
+        self.asm.jmp(self.exit);
+    }
+
+    /// carries on at `target`, which is at most the program's length
+    fn goto(&mut self, target: usize) {
+        if (self.start..self.end).contains(&target) {
+            self.asm.jmp(self.pcs[target - self.start]);
+        } else {
+            self.leave(target, LEFT);
+        }
+    }
+
+    /// fails unless there are at least `depth` bytes on the stack
+    fn check_depth(&mut self, depth: u64, fail: Label) {
+        self.asm.alu_imm(Alu::Cmp, STACK_POINTER, depth as i32);
+        self.asm.jcc(Cond::B, fail);
+    }
+
+    /// puts the stack pointer moved by `delta` in `dst`, failing if that's past the limit and growing the stack to
+    /// reach it if it isn't
+    fn reserve(&mut self, dst: Reg, delta: i64, fail: Label) {
+        self.asm.lea(dst, at(STACK_POINTER, delta as i32));
+        self.asm.alu(Alu::Cmp, dst, STACK_LIMIT);
+        self.asm.jcc(Cond::A, fail);
+        self.asm.alu(Alu::Cmp, STACK_LEN, dst);
+        self.asm.cmov(Cond::B, STACK_LEN, dst);
+    }
+
+    /// puts the frame pointer plus `offset` in `rax`, failing if it overflows
+    fn frame_address(&mut self, offset: i64, fail: Label) {
+        self.asm.mov(Rax, FRAME_POINTER);
+        if offset >= 0 {
+            self.asm.alu_imm(Alu::Add, Rax, offset as i32);
+        } else {
+            self.asm.alu_imm(Alu::Sub, Rax, -offset as i32);
+        }
+        self.asm.jcc(Cond::B, fail);
+    }
+
+    /// turns the VM address in `rax` into a pointer to `width` bytes in `rdx`, like `VM::get_bytes`, failing if
+    /// they're not all in main memory or the stack; uses `rcx` and `rax`
+    fn locate(&mut self, width: u64, fail: Label) {
+        let (stack, done) = (self.asm.label(), self.asm.label());
+        self.asm.mov(Rcx, Rax);
+        self.asm.alu_imm(Alu::Add, Rcx, width as i32);
+        self.asm.jcc(Cond::B, fail);
+        self.asm.alu(Alu::Cmp, Rcx, MEMORY_SIZE);
+        self.asm.jcc(Cond::A, stack);
+        self.asm.lea(Rdx, indexed(MEMORY_BASE, Rax, 0));
+        self.asm.jmp(done);
+        self.asm.bind(stack);
+        self.asm.mov_imm(Rdx, VM::STACK_START);
+        self.asm.alu(Alu::Sub, Rax, Rdx);
+        self.asm.jcc(Cond::B, fail);
+        self.asm.lea(Rcx, at(Rax, width as i32));
+        self.asm.alu(Alu::Cmp, Rcx, STACK_LEN);
+        self.asm.jcc(Cond::A, fail);
+        self.asm.lea(Rdx, indexed(STACK, Rax, 0));
+        self.asm.bind(done);
+    }
+
+    /// pushes `value`'s low `width` bytes
+    fn push(&mut self, value: Reg, width: u64, fail: Label) {
+        self.reserve(Rax, width as i64, fail);
+        self.asm.store(slot(0), value, width);
+        self.asm.mov(STACK_POINTER, Rax);
+    }
+
+    /// replaces the top `popped` bytes with `value`'s low `width` bytes, where `value` isn't `rax`
+    fn replace(&mut self, popped: u64, value: Reg, width: u64, fail: Label) {
+        self.reserve(Rax, width as i64 - popped as i64, fail);
+        self.asm.store(slot(popped), value, width);
+        self.asm.mov(STACK_POINTER, Rax);
+    }
+
+    /// compiles one instruction, or returns false without emitting anything if it has to go through `tick`
+    fn instruction(&mut self, pc: usize) -> bool {
+        use Instruction as I;
+        let instruction = self.program[pc];
+        let len = self.program.len();
+        let fits = |value: u64| value <= 0x1000;
+        let fail = self.fail(pc);
+        match instruction {
+            I::Push(value) => {
+                self.asm.mov_imm(Rcx, value as u64);
+                self.push(Rcx, 1, fail);
+            }
+            I::PushImm { size, value } => {
+                self.asm.mov_imm(Rcx, value);
+                self.push(Rcx, size.bytes(), fail);
+            }
+            I::PushImmf { size, bits } => {
+                self.asm.mov_imm(Rcx, bits);
+                self.push(Rcx, size.bytes(), fail);
+            }
+            I::Pop(count) if fits(count as u64) => {
+                self.check_depth(count as u64, fail);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, count as i32);
+            }
+            I::LoadLocal { offset, size } if offset.unsigned_abs() < 1 << 31 => {
+                let width = size.bytes();
+                self.frame_address(offset, fail);
+                self.locate(width, fail);
+                self.asm.load(Rcx, at(Rdx, 0), width);
+                self.push(Rcx, width, fail);
+            }
+            I::StoreLocal { offset, size } if offset.unsigned_abs() < 1 << 31 => {
+                let width = size.bytes();
+                self.frame_address(offset, fail);
+                self.check_depth(width, fail);
+                self.locate(width, fail);
+                self.asm.load(Rcx, slot(width), width);
+                self.asm.store(at(Rdx, 0), Rcx, width);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, width as i32);
+            }
+            I::Load { size } => {
+                let width = size.bytes();
+                self.check_depth(8, fail);
+                self.asm.load(Rax, slot(8), 8);
+                self.locate(width, fail);
+                self.asm.load(Rcx, at(Rdx, 0), width);
+                self.replace(8, Rcx, width, fail);
+            }
+            I::Store { size } => {
+                let width = size.bytes();
+                self.check_depth(8 + width, fail);
+                self.asm.load(Rax, slot(8), 8);
+                self.locate(width, fail);
+                self.asm.load(Rcx, slot(8 + width), width);
+                self.asm.store(at(Rdx, 0), Rcx, width);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, (8 + width) as i32);
+            }
+            I::Add(size) | I::Sub(size) | I::Mul(size) | I::And(size) | I::Or(size) | I::Xor(size) => {
+                let width = size.bytes();
+                self.check_depth(2 * width, fail);
+                self.asm.load(Rax, slot(2 * width), width);
+                self.asm.load(Rcx, slot(width), width);
+                match instruction {
+                    I::Add(_) => self.asm.alu(Alu::Add, Rax, Rcx),
+                    I::Sub(_) => self.asm.alu(Alu::Sub, Rax, Rcx),
+                    I::And(_) => self.asm.alu(Alu::And, Rax, Rcx),
+                    I::Or(_) => self.asm.alu(Alu::Or, Rax, Rcx),
+                    I::Xor(_) => self.asm.alu(Alu::Xor, Rax, Rcx),
+                    _ => self.asm.imul(Rax, Rcx),
+                }
+                self.asm.store(slot(2 * width), Rax, width);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, width as i32);
+            }
+            I::AddChecked(IntSize::I64) | I::SAddChecked(IntSize::I64) | I::SubChecked(IntSize::I64)
+            | I::SSubChecked(IntSize::I64) | I::MulChecked(IntSize::I64) | I::SMulChecked(IntSize::I64) => {
+                self.check_depth(16, fail);
+                self.asm.load(Rax, slot(16), 8);
+                self.asm.load(Rcx, slot(8), 8);
+                match instruction {
+                    I::AddChecked(_) => { self.asm.alu(Alu::Add, Rax, Rcx); self.asm.jcc(Cond::B, fail) }
+                    I::SAddChecked(_) => { self.asm.alu(Alu::Add, Rax, Rcx); self.asm.jcc(Cond::O, fail) }
+                    I::SubChecked(_) => { self.asm.alu(Alu::Sub, Rax, Rcx); self.asm.jcc(Cond::B, fail) }
+                    I::SSubChecked(_) => { self.asm.alu(Alu::Sub, Rax, Rcx); self.asm.jcc(Cond::O, fail) }
+                    // `mul` sets the overflow flag when the high half isn't zero
+                    I::MulChecked(_) => { self.asm.unary(4, Rcx); self.asm.jcc(Cond::O, fail) }
+                    _ => { self.asm.imul(Rax, Rcx); self.asm.jcc(Cond::O, fail) }
+                }
+                self.asm.store(slot(16), Rax, 8);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, 8);
+            }
+            I::Not(size) => {
+                let width = size.bytes();
+                self.check_depth(width, fail);
+                self.asm.load(Rax, slot(width), width);
+                self.asm.unary(2, Rax);
+                self.asm.store(slot(width), Rax, width);
+            }
+            I::Cmp(size) | I::SCmp(size) => {
+                let (width, signed) = (size.bytes(), matches!(instruction, I::SCmp(_)));
+                self.check_depth(2 * width, fail);
+                if signed {
+                    self.asm.load_signed(Rax, slot(2 * width), width);
+                    self.asm.load_signed(Rcx, slot(width), width);
+                } else {
+                    self.asm.load(Rax, slot(2 * width), width);
+                    self.asm.load(Rcx, slot(width), width);
+                }
+                self.asm.mov_imm(Rdx, 0);
+                self.asm.mov_imm(Rsi, 1);
+                self.asm.mov_imm(Rdi, u64::MAX);
+                self.asm.alu(Alu::Cmp, Rax, Rcx);
+                self.asm.cmov(if signed { Cond::G } else { Cond::A }, Rdx, Rsi);
+                self.asm.cmov(if signed { Cond::L } else { Cond::B }, Rdx, Rdi);
+                self.replace(2 * width, Rdx, 8, fail);
+            }
+            I::Shl(size) | I::Shr(size) | I::UShr(size) => {
+                let width = size.bytes();
+                let (big, done) = (self.asm.label(), self.asm.label());
+                self.check_depth(2 * width, fail);
+                if let I::Shr(_) = instruction {
+                    self.asm.load_signed(Rax, slot(2 * width), width);
+                } else {
+                    self.asm.load(Rax, slot(2 * width), width);
+                }
+                self.asm.load(Rcx, slot(width), width);
+                // shifting by the width or more shifts everything out, where x86 would wrap the amount
+                self.asm.alu_imm(Alu::Cmp, Rcx, (width * 8) as i32);
+                self.asm.jcc(Cond::AE, big);
+                let extension = match instruction { I::Shl(_) => 4, I::UShr(_) => 5, _ => 7 };
+                self.asm.shift(extension, Rax);
+                self.asm.jmp(done);
+                self.asm.bind(big);
+                if let I::Shr(_) = instruction {
+                    self.asm.shift_imm(7, Rax, 63);
+                } else {
+                    self.asm.mov_imm(Rax, 0);
+                }
+                self.asm.bind(done);
+                self.asm.store(slot(2 * width), Rax, width);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, width as i32);
+            }
+            I::Div(size) | I::Mod(size) | I::SDiv(size) | I::SMod(size) => {
+                let width = size.bytes();
+                let signed = matches!(instruction, I::SDiv(_) | I::SMod(_));
+                let remainder = matches!(instruction, I::Mod(_) | I::SMod(_));
+                self.check_depth(2 * width, fail);
+                if signed {
+                    self.asm.load_signed(Rax, slot(2 * width), width);
+                    self.asm.load_signed(Rcx, slot(width), width);
+                } else {
+                    self.asm.load(Rax, slot(2 * width), width);
+                    self.asm.load(Rcx, slot(width), width);
+                }
+                self.asm.test(Rcx, Rcx);
+                self.asm.jcc(Cond::E, fail);
+                if signed {
+                    // `idiv` faults on the most negative number over -1, which wraps instead
+                    let (divide, done) = (self.asm.label(), self.asm.label());
+                    self.asm.alu_imm(Alu::Cmp, Rcx, -1);
+                    self.asm.jcc(Cond::NE, divide);
+                    if remainder {
+                        self.asm.mov_imm(Rax, 0);
+                    } else {
+                        self.asm.unary(3, Rax);
+                    }
+                    self.asm.jmp(done);
+                    self.asm.bind(divide);
+                    self.asm.cqo();
+                    self.asm.unary(7, Rcx);
+                    if remainder {
+                        self.asm.mov(Rax, Rdx);
+                    }
+                    self.asm.bind(done);
+                } else {
+                    self.asm.mov_imm(Rdx, 0);
+                    self.asm.unary(6, Rcx);
+                    if remainder {
+                        self.asm.mov(Rax, Rdx);
+                    }
+                }
+                self.asm.store(slot(2 * width), Rax, width);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, width as i32);
+            }
+            I::ZeroExtend { from, to } | I::SignExtend { from, to } | I::Truncate { from, to } => {
+                let (from, to) = (from.bytes(), to.bytes());
+                self.check_depth(from, fail);
+                if to <= from {
+                    // the low bytes are already where they need to be
+                    self.asm.alu_imm(Alu::Sub, STACK_POINTER, (from - to) as i32);
+                } else {
+                    if let I::SignExtend { .. } = instruction {
+                        self.asm.load_signed(Rcx, slot(from), from);
+                    } else {
+                        self.asm.load(Rcx, slot(from), from);
+                    }
+                    self.replace(from, Rcx, to, fail);
+                }
+            }
+            I::Jmp(target) if (0..=len as i64).contains(&target) => {
+                self.asm.alu_imm(Alu::Add, EXECUTED_COUNT, 1);
+                self.goto(target as usize);
+                return true;
+            }
+            I::Jz(size) => {
+                let width = size.bytes();
+                let skip = self.asm.label();
+                self.check_depth(8 + width, fail);
+                self.asm.load(Rcx, slot(width), width);
+                self.asm.load(Rax, slot(8 + width), 8);
+                self.asm.test(Rcx, Rcx);
+                self.asm.jcc(Cond::NE, skip);
+                self.asm.alu_imm(Alu::Cmp, Rax, len as i32);
+                self.asm.jcc(Cond::A, fail);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, (8 + width) as i32);
+                self.asm.alu_imm(Alu::Add, EXECUTED_COUNT, 1);
+                self.asm.jmp(self.dispatch);
+                self.asm.bind(skip);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, (8 + width) as i32);
+            }
+            I::Call(target) if (0..=len as i64).contains(&target) => {
+                self.asm.mov_imm(Rcx, pc as u64 + 1);
+                self.push(Rcx, 8, fail);
+                self.asm.alu_imm(Alu::Add, EXECUTED_COUNT, 1);
+                self.goto(target as usize);
+                return true;
+            }
+            I::Ret => {
+                self.check_depth(8, fail);
+                self.asm.load(Rax, slot(8), 8);
+                self.asm.alu_imm(Alu::Cmp, Rax, len as i32);
+                self.asm.jcc(Cond::A, fail);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, 8);
+                self.asm.alu_imm(Alu::Add, EXECUTED_COUNT, 1);
+                self.asm.jmp(self.dispatch);
+                return true;
+            }
+            I::Enter(size) if fits(size) => {
+                self.reserve(Rax, 8 + size as i64, fail);
+                self.asm.store(slot(0), FRAME_POINTER, 8);
+                self.asm.mov_imm(Rcx, 0);
+                let mut zeroed = 0;
+                for width in [8, 4, 2, 1] {
+                    while size - zeroed >= width {
+                        self.asm.store(indexed(STACK, STACK_POINTER, (8 + zeroed) as i32), Rcx, width);
+                        zeroed += width;
+                    }
+                }
+                self.asm.mov_imm(Rcx, VM::STACK_START);
+                self.asm.lea(FRAME_POINTER, indexed(Rcx, STACK_POINTER, 8));
+                self.asm.mov(STACK_POINTER, Rax);
+            }
+            I::Leave => {
+                self.asm.mov_imm(Rcx, VM::STACK_START);
+                self.asm.mov(Rax, FRAME_POINTER);
+                self.asm.alu(Alu::Sub, Rax, Rcx);
+                self.asm.jcc(Cond::B, fail);
+                self.asm.alu(Alu::Cmp, Rax, STACK_LEN);
+                self.asm.jcc(Cond::A, fail);
+                self.asm.alu_imm(Alu::Cmp, Rax, 8);
+                self.asm.jcc(Cond::B, fail);
+                self.asm.load(FRAME_POINTER, indexed(STACK, Rax, -8), 8);
+                self.asm.lea(STACK_POINTER, at(Rax, -8));
+            }
+            I::Dup(size) => self.pick(size.bytes(), 0, fail),
+            I::Over(size) => self.pick(size.bytes(), size.bytes(), fail),
+            I::Pick { size, depth } if fits(depth) => self.pick(size.bytes(), depth * size.bytes(), fail),
+            I::DupBytes(width) if matches!(width, 1 | 2 | 4 | 8) => self.pick(width, 0, fail),
+            I::OverBytes(width) if matches!(width, 1 | 2 | 4 | 8) => self.pick(width, width, fail),
+            I::PickBytes { width, offset } if matches!(width, 1 | 2 | 4 | 8) && fits(offset) => self.pick(width, offset, fail),
+            I::Swap(size) => self.rotate(size.bytes(), 2, fail),
+            I::Rot(size) => self.rotate(size.bytes(), 3, fail),
+            I::SwapBytes(width) if matches!(width, 1 | 2 | 4 | 8) => self.rotate(width, 2, fail),
+            I::RotBytes(width) if matches!(width, 1 | 2 | 4 | 8) => self.rotate(width, 3, fail),
+            I::PushSP | I::PushFP | I::PushIP | I::PushMaxHeapSize => {
+                match instruction {
+                    I::PushSP => {
+                        self.asm.mov_imm(Rcx, VM::STACK_START);
+                        self.asm.alu(Alu::Add, Rcx, STACK_POINTER);
+                    }
+                    I::PushFP => self.asm.mov(Rcx, FRAME_POINTER),
+                    I::PushIP => self.asm.mov_imm(Rcx, pc as u64 + 1),
+                    _ => self.asm.mov(Rcx, MEMORY_SIZE),
+                }
+                self.push(Rcx, 8, fail);
+            }
+            I::Addf(FloatSize::F64) | I::Subf(FloatSize::F64) | I::Mulf(FloatSize::F64) | I::Divf(FloatSize::F64) => {
+                let opcode = match instruction { I::Addf(_) => 0x58, I::Mulf(_) => 0x59, I::Subf(_) => 0x5c, _ => 0x5e };
+                self.check_depth(16, fail);
+                self.asm.movsd_load(0, slot(16));
+                self.asm.sse(opcode, 0, slot(8));
+                self.asm.movsd_store(slot(16), 0);
+                self.asm.alu_imm(Alu::Sub, STACK_POINTER, 8);
+            }
+            I::Cmpf(FloatSize::F64) => {
+                self.check_depth(16, fail);
+                self.asm.movsd_load(0, slot(16));
+                self.asm.mov_imm(Rdx, 0);
+                self.asm.mov_imm(Rsi, 1);
+                self.asm.mov_imm(Rdi, u64::MAX);
+                self.asm.ucomisd(0, slot(8));
+                // unordered, which faults
+                self.asm.jcc(Cond::P, fail);
+                self.asm.cmov(Cond::A, Rdx, Rsi);
+                self.asm.cmov(Cond::B, Rdx, Rdi);
+                self.replace(16, Rdx, 8, fail);
+            }
+            I::IntToFloat { from, to: FloatSize::F64 } | I::UIntToFloat { from, to: FloatSize::F64 } => {
+                let width = from.bytes();
+                self.check_depth(width, fail);
+                if let I::IntToFloat { .. } = instruction {
+                    self.asm.load_signed(Rcx, slot(width), width);
+                } else {
+                    self.asm.load(Rcx, slot(width), width);
+                    // too big for a signed conversion
+                    self.asm.test(Rcx, Rcx);
+                    self.asm.jcc(Cond::S, fail);
+                }
+                self.asm.cvtsi2sd(0, Rcx);
+                self.asm.movq_from_xmm(Rdx, 0);
+                self.replace(width, Rdx, 8, fail);
+            }
+            I::FloatToInt { from: FloatSize::F64, to } | I::FloatToUInt { from: FloatSize::F64, to } => {
+                let width = to.bytes();
+                self.check_depth(8, fail);
+                self.asm.cvttsd2si(Rdx, slot(8));
+                // out of range (or NaN) comes out as the most negative number, where Rust saturates instead
+                if let I::FloatToInt { .. } = instruction {
+                    if width == 8 {
+                        self.asm.mov_imm(Rcx, 1 << 63);
+                    } else {
+                        self.asm.mov(Rcx, Rdx);
+                        self.asm.shift_imm(4, Rcx, 64 - width as u8 * 8);
+                        self.asm.shift_imm(7, Rcx, 64 - width as u8 * 8);
+                    }
+                    self.asm.alu(Alu::Cmp, Rcx, Rdx);
+                    self.asm.jcc(if width == 8 { Cond::E } else { Cond::NE }, fail);
+                } else {
+                    self.asm.test(Rdx, Rdx);
+                    self.asm.jcc(Cond::S, fail);
+                    if width < 8 {
+                        self.asm.mov(Rcx, Rdx);
+                        self.asm.shift_imm(5, Rcx, width as u8 * 8);
+                        self.asm.jcc(Cond::NE, fail);
+                    }
+                }
+                self.replace(8, Rdx, width, fail);
+            }
+            _ => {
+                self.ticks.pop();
+                return false;
+            }
+        }
+        self.asm.alu_imm(Alu::Add, EXECUTED_COUNT, 1);
+        true
+    }
+
+    /// pushes a copy of the `width` bytes ending `offset` bytes below the top
+    fn pick(&mut self, width: u64, offset: u64, fail: Label) {
+        self.check_depth(offset + width, fail);
+        self.asm.load(Rdx, slot(offset + width), width);
+        self.push(Rdx, width, fail);
+    }
+
+    /// moves the `width` byte item `items - 1` items down to the top
+    fn rotate(&mut self, width: u64, items: u64, fail: Label) {
+        self.check_depth(items * width, fail);
+        self.asm.load(Rax, slot(items * width), width);
+        self.asm.load(Rcx, slot((items - 1) * width), width);
+        if items == 3 {
+            self.asm.load(Rdx, slot(width), width);
+            self.asm.store(slot(2 * width), Rdx, width);
+        }
+        self.asm.store(slot(items * width), Rcx, width);
+        self.asm.store(slot(width), Rax, width);
+    }
+
+    /// the whole region: the entry point, every instruction, and the ways out
+    fn compile(mut self) -> Assembler {
+        for &reg in &CALLEE_SAVED {
+            self.asm.push(reg);
+        }
+        self.asm.mov(CONTEXT, Rdi);
+        self.asm.load(STACK, at(CONTEXT, 0), 8);
+        self.asm.load(STACK_POINTER, at(CONTEXT, SP), 8);
+        self.asm.load(FRAME_POINTER, at(CONTEXT, FP), 8);
+        self.asm.load(STACK_LIMIT, at(CONTEXT, LIMIT), 8);
+        self.asm.load(STACK_LEN, at(CONTEXT, LEN), 8);
+        self.asm.load(MEMORY_BASE, at(CONTEXT, MEMORY), 8);
+        self.asm.load(MEMORY_SIZE, at(CONTEXT, MEMORY_LEN), 8);
+        self.asm.load(EXECUTED_COUNT, at(CONTEXT, EXECUTED), 8);
+        self.asm.mov(Rax, Rsi);
+        self.asm.jmp(self.dispatch);
+
+        for pc in self.start..self.end {
+            self.asm.bind(self.pcs[pc - self.start]);
+            if !self.instruction(pc) {
+                self.leave(pc, TICK);
+            }
+        }
+        self.goto(self.end);
+
+        // jumps to the instruction in `rax`, which is at most the program's length
+        let outside = self.asm.label();
+        self.asm.bind(self.dispatch);
+        self.asm.mov(Rcx, Rax);
+        self.asm.alu_imm(Alu::Sub, Rcx, self.start as i32);
+        self.asm.alu_imm(Alu::Cmp, Rcx, (self.end - self.start) as i32);
+        self.asm.jcc(Cond::AE, outside);
+        self.asm.mov_table_address(Rdx);
+        self.asm.jmp_table(Rdx, Rcx);
+        self.asm.bind(outside);
+        self.asm.store(at(CONTEXT, PC), Rax, 8);
+        self.asm.mov_imm(Rax, LEFT as u64);
+        self.asm.jmp(self.exit);
+
+        for (label, pc) in std::mem::take(&mut self.ticks) {
+            self.asm.bind(label);
+            self.leave(pc, TICK);
+        }
+
+        self.asm.bind(self.exit);
+        self.asm.store(at(CONTEXT, SP), STACK_POINTER, 8);
+        self.asm.store(at(CONTEXT, FP), FRAME_POINTER, 8);
+        self.asm.store(at(CONTEXT, LEN), STACK_LEN, 8);
+        self.asm.store(at(CONTEXT, EXECUTED), EXECUTED_COUNT, 8);
+        for &reg in CALLEE_SAVED.iter().rev() {
+            self.asm.pop(reg);
+        }
+        self.asm.ret();
+
+        self.asm.finish();
+        let mut asm = self.asm;
+        // the labels of the instructions go in the jump table, which is filled in once the code's address is known
+        asm.labels.truncate(self.pcs.len());
+        asm
+    }
+}
+
+/// native code for part of the program, in memory of its own
+struct Region {
+    code: *mut u8,
+    size: usize,
+    start: usize,
+}
+
+impl Region {
+    fn new(program: &[Instruction], start: usize) -> Option<Region> {
+        let end = program.len().min(start + REGION_LEN);
+        let mut asm = Assembler::default();
+        let pcs = (start..end).map(|_| asm.label()).collect();
+        let (dispatch, exit) = (asm.label(), asm.label());
+        let compiler = Compiler { asm, program, start, end, pcs, dispatch, exit, ticks: vec![] };
+        let asm = compiler.compile();
+
+        let table = asm.code.len().next_multiple_of(8);
+        let size = table + asm.labels.len() * 8;
+        // SAFETY: a fresh anonymous mapping, which is only touched through `code` and within `size`
+        unsafe {
+            let code = mmap(std::ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if code as isize == -1 {
+                return None;
+            }
+            let code = code as *mut u8;
+            std::ptr::copy_nonoverlapping(asm.code.as_ptr(), code, asm.code.len());
+            let table_address = code as u64 + table as u64;
+            for &at in &asm.table_addresses {
+                std::ptr::copy_nonoverlapping(table_address.to_le_bytes().as_ptr(), code.add(at), 8);
+            }
+            for (i, label) in asm.labels.iter().enumerate() {
+                let address = code as u64 + label.expect("every instruction is labelled") as u64;
+                std::ptr::copy_nonoverlapping(address.to_le_bytes().as_ptr(), code.add(table + i * 8), 8);
+            }
+            if mprotect(code as *mut c_void, size, PROT_READ | PROT_EXEC) != 0 {
+                munmap(code as *mut c_void, size);
+                return None;
+            }
+            Some(Region { code, size, start })
+        }
+    }
+
+    /// runs native code from the VM's program counter (which has to be in this region), first giving the stack up to
+    /// `headroom` bytes of spare capacity, returning whether it stopped at an instruction `tick` has to run
+    fn run(&self, vm: &mut VM, headroom: usize) -> bool {
+        let Some(sp) = vm.stack_pointer.checked_sub(VM::STACK_START).filter(|&sp| sp <= vm.stack.len() as u64) else {
+            return true;
+        };
+        if vm.stack.capacity() - vm.stack.len() < headroom / 2 {
+            let room = (vm.max_stack - 1).saturating_sub(vm.stack.len() as u64).min(headroom as u64);
+            vm.stack.reserve(room as usize);
+        }
+        let mut context = Context {
+            stack: vm.stack.as_mut_ptr(),
+            sp,
+            fp: vm.frame_pointer,
+            limit: (vm.stack.capacity() as u64).min(vm.max_stack - 1),
+            len: vm.stack.len() as u64,
+            memory: vm.main_memory.as_mut_ptr(),
+            memory_len: vm.main_memory.len() as u64,
+            executed: vm.executed,
+            pc: 0,
+        };
+        // SAFETY: the code was compiled for this program, and it only reads and writes main memory, the stack up to
+        // `limit` (which is inside its capacity) and the context. everything it grows the stack by, it writes first
+        let status = unsafe {
+            let entry: Entry = std::mem::transmute(self.code);
+            let status = entry(&mut context, vm.program_counter);
+            vm.stack.set_len(context.len as usize);
+            status
+        };
+        vm.stack_pointer = VM::STACK_START + context.sp;
+        vm.frame_pointer = context.fp;
+        vm.program_counter = context.pc;
+        vm.executed = context.executed;
+        status == TICK as u64
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        // SAFETY: this is the mapping `new` made, and nothing can run the code any more
+        unsafe {
+            munmap(self.code as *mut c_void, self.size);
+        }
+    }
+}
+
+/// runs until the program ends or faults, like `VM::run`
+pub(super) fn run(vm: &mut VM) -> Result<(), Fault> {
+    run_with(vm, HOT, STACK_HEADROOM)
+}
+
+/// `run`, compiling regions once an instruction has been reached `hot` times and giving the stack `headroom` bytes
+/// of spare capacity for native code
+fn run_with(vm: &mut VM, hot: u32, headroom: usize) -> Result<(), Fault> {
+    if vm.fuel.is_some() || vm.max_stack == 0 || vm.program.len() > i32::MAX as usize / 2 {
+        return super::fast::run(vm);
+    }
+    let len = vm.program.len();
+    let mut counts = vec![0u32; len];
+    // which region, if any, covers each instruction; the first one compiled wins
+    let mut owners: Vec<Option<usize>> = vec![None; len];
+    let mut regions: Vec<Region> = vec![];
+    loop {
+        let pc = usize::try_from(vm.program_counter).unwrap_or(usize::MAX);
+        let tick = match owners.get(pc) {
+            Some(&Some(region)) => regions[region].run(vm, headroom),
+            Some(None) => {
+                counts[pc] = counts[pc].saturating_add(1);
+                if counts[pc] == hot {
+                    if let Some(region) = Region::new(&vm.program, pc) {
+                        let end = len.min(region.start + REGION_LEN);
+                        for owner in owners[pc..end].iter_mut().filter(|owner| owner.is_none()) {
+                            *owner = Some(regions.len());
+                        }
+                        regions.push(region);
+                        continue;
+                    }
+                }
+                true
+            }
+            None => true,
+        };
+        if tick {
+            match vm.tick() {
+                Ok(()) => {}
+                Err(Fault { kind: FaultKind::ProgramEnded, .. }) => return Ok(()),
+                Err(fault) => return Err(fault),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::asm::assemble;
+    use crate::vm::tests::{edge_cases, fuzz_programs, same_as_interpreter};
+
+    #[test]
+    fn every_instruction_runs_like_the_interpreter() {
+        // compiled the first time round, so everything runs natively unless it hands itself back to `tick`
+        for program in fuzz_programs() {
+            for stack_len in [256, 33] {
+                same_as_interpreter(&program, stack_len, |vm| run_with(vm, 1, STACK_HEADROOM));
+            }
+        }
+    }
+
+    #[test]
+    fn faults_and_control_flow_are_the_same_as_the_interpreter() {
+        // regions starting partway in, so jumps, calls and returns can leave them, and a stack that's always about to
+        // run out of capacity
+        for program in edge_cases() {
+            for stack_len in [256, 33] {
+                for (hot, headroom) in [(1, STACK_HEADROOM), (2, STACK_HEADROOM), (3, STACK_HEADROOM), (1, 16)] {
+                    same_as_interpreter(&program, stack_len, |vm| run_with(vm, hot, headroom));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pushes_past_the_headroom_go_through_tick() {
+        // pushes a byte 300 times, counting down at address 0
+        let program = assemble(
+            "PUSHW 300\nPUSHD 0\nSTOREW\n.Lloop:\nPUSHB 7\nPUSHD 0\nLOADW\nPUSHW 1\nSUBW\nPUSHD 0\nSTOREW\n\
+             PUSHADDR .Lend\nPUSHD 0\nLOADW\nJZW\nJMP .Lloop\n.Lend:\n",
+        ).unwrap();
+        for headroom in [0, 1, 16, STACK_HEADROOM] {
+            same_as_interpreter(&program, 4096, |vm| run_with(vm, 1, headroom));
+            same_as_interpreter(&program, 200, |vm| run_with(vm, 1, headroom));
+        }
+        let mut vm = VM::load(&program, 64, 4096).unwrap();
+        run_with(&mut vm, 1, 16).unwrap();
+        assert_eq!((vm.get_u64(0).unwrap(), vm.stack_pointer()), (0, VM::STACK_START + 300));
+    }
+
+    #[test]
+    fn jumps_out_of_a_region_carry_on_in_the_interpreter() {
+        // a function before the loop that calls it, and one too far away for the loop's region to reach
+        let text = format!(
+            "JMP .Lmain\n.Lnear:\nENTER 8\nPUSHD 1\nSTLOCD 0\nLEAVE\nRET\n.Lmain:\nPUSHD 100\n.Lloop:\nCALL .Lnear\nCALL .Lfar\n\
+             PUSHD 1\nSUBD\nPUSHADDR .Lend\nPICKBYTES 8, 8\nTRUNCDB\nJZB\nJMP .Lloop\n.Lend:\nJMP .Lexit\n{}\
+             .Lfar:\nPUSHD 8\nLOADD\nPUSHD 3\nADDD\nPUSHD 8\nSTORED\nRET\n.Lexit:\n",
+            "PUSHB 1\nPOP 1\n".repeat(REGION_LEN),
+        );
+        let program = assemble(&text).unwrap();
+        for hot in [1, 2, 50] {
+            same_as_interpreter(&program, 256, |vm| run_with(vm, hot, STACK_HEADROOM));
+        }
+        let mut vm = VM::load(&program, 64, 256).unwrap();
+        run_with(&mut vm, 2, STACK_HEADROOM).unwrap();
+        assert_eq!(vm.get_u64(8).unwrap(), 300);
+    }
+}
@@ -0,0 +1,282 @@
+//! recording everything a run gets from outside the VM, so it can be replayed exactly
+//!
+//! a recording is the magic bytes and a little endian u16 format version, followed by a u64 count of entries. each
+//! entry is the number of instructions that had run before it as a u64 and a u8 tag:
+//!
+//! - 0, an I/O instruction: its opcode as a u8, a u8 that's 0 if it succeeded or 1 if it failed followed by the u64
+//!   result or error code, and the bytes it read (a u64 length and the bytes, empty for anything but `Read`)
+//! - 1, a host call: its ID as a u32, the stack pointer, frame pointer, program counter and stack length it left as
+//!   u64s, the stack and main memory it changed as a u64 count of (u64 offset, bytes) pairs each, and a u8 that's 1
+//!   if it failed followed by its message as bytes
+//!
+//! when replaying, `Read`, `Open`, `Close`, `Seek` and `Stat` never reach the host and host functions aren't called;
+//! `Write` still goes to the host so output shows up, but the program sees the recorded result
+
+use std::{error::Error, fmt::Display};
+
+use crate::bytecode::{module::{header, read_bytes, read_header, read_u32, read_u64, write_bytes, write_u32, write_u64, HeaderError}, take, DecodeError};
+
+use super::VM;
+
+pub const MAGIC: &[u8; 4] = b"KSRL";
+pub const VERSION: u16 = 1;
+
+/// bytes that changed in a region, as its offset and the new bytes
+pub type Patch = (u64, Vec<u8>);
+
+/// what a host function did to the VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Effects {
+    pub stack_pointer: u64,
+    pub frame_pointer: u64,
+    pub program_counter: u64,
+    pub stack_len: u64,
+    pub stack: Vec<Patch>,
+    pub memory: Vec<Patch>,
+}
+
+impl Effects {
+    /// whether the stack and frame pointers it leaves are inside the stack it leaves; whether the program counter is
+    /// inside the program depends on the program, so that's left to whatever replays it
+    pub fn consistent(&self) -> bool {
+        let stack = VM::STACK_START..=VM::STACK_START.saturating_add(self.stack_len);
+        stack.contains(&self.stack_pointer) && stack.contains(&self.frame_pointer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// an I/O instruction's result (or error code) and, for `Read`, the bytes it read
+    Io { opcode: u8, result: Result<u64, i64>, data: Vec<u8> },
+    /// a `CallHost`, and the message it failed with if it did
+    HostCall { id: u32, effects: Effects, error: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// how many instructions had run before this one
+    pub at: u64,
+    pub event: Event,
+}
+
+/// everything a run got from outside the VM, in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Decode(DecodeError),
+    /// a host call left registers that don't make sense together, like a stack pointer outside of the stack
+    Inconsistent(&'static str),
+    TrailingBytes,
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::BadMagic => write!(f, "not a kitchen-sink recording"),
+            ReplayError::UnsupportedVersion(v) => write!(f, "unsupported recording version {v} (expected {VERSION})"),
+            ReplayError::Decode(e) => write!(f, "{e}"),
+            ReplayError::Inconsistent(what) => write!(f, "inconsistent recording: {what}"),
+            ReplayError::TrailingBytes => write!(f, "trailing bytes after the recording"),
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+impl From<DecodeError> for ReplayError {
+    fn from(e: DecodeError) -> ReplayError { ReplayError::Decode(e) }
+}
+
+impl From<HeaderError> for ReplayError {
+    fn from(e: HeaderError) -> ReplayError {
+        match e {
+            HeaderError::BadMagic => ReplayError::BadMagic,
+            HeaderError::UnsupportedVersion(v) => ReplayError::UnsupportedVersion(v),
+            HeaderError::Decode(e) => ReplayError::Decode(e),
+        }
+    }
+}
+
+/// the runs of bytes that differ between `old` and `new`, including anything `new` has past the end of `old`
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<Patch> {
+    let mut patches = vec![];
+    let mut i = 0;
+    while i < new.len() {
+        if old.get(i) == Some(&new[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < new.len() && old.get(i) != Some(&new[i]) {
+            i += 1;
+        }
+        patches.push((start as u64, new[start..i].to_vec()));
+    }
+    patches
+}
+
+/// applies patches from `diff`, returning `None` if one doesn't fit
+pub fn apply(region: &mut [u8], patches: &[Patch]) -> Option<()> {
+    for (offset, bytes) in patches {
+        let start = usize::try_from(*offset).ok()?;
+        region.get_mut(start..start.checked_add(bytes.len())?)?.copy_from_slice(bytes);
+    }
+    Some(())
+}
+
+fn write_patches(out: &mut Vec<u8>, patches: &[Patch]) {
+    write_u64(out, patches.len() as u64);
+    for (offset, bytes) in patches {
+        write_u64(out, *offset);
+        write_bytes(out, bytes);
+    }
+}
+
+fn read_patches(bytes: &mut &[u8]) -> Result<Vec<Patch>, DecodeError> {
+    let count = read_u64(bytes)?;
+    // not trusting the count to preallocate with
+    let mut patches = vec![];
+    for _ in 0..count {
+        let offset = read_u64(bytes)?;
+        patches.push((offset, read_bytes(bytes)?.to_vec()));
+    }
+    Ok(patches)
+}
+
+pub fn write(recording: &Recording) -> Vec<u8> {
+    let mut out = header(MAGIC, VERSION);
+    write_u64(&mut out, recording.entries.len() as u64);
+    for entry in &recording.entries {
+        write_u64(&mut out, entry.at);
+        match &entry.event {
+            Event::Io { opcode, result, data } => {
+                out.extend_from_slice(&[0, *opcode]);
+                match result {
+                    Ok(v) => { out.push(0); write_u64(&mut out, *v); }
+                    Err(code) => { out.push(1); write_u64(&mut out, *code as u64); }
+                }
+                write_bytes(&mut out, data);
+            }
+            Event::HostCall { id, effects, error } => {
+                out.push(1);
+                write_u32(&mut out, *id);
+                for register in [effects.stack_pointer, effects.frame_pointer, effects.program_counter, effects.stack_len] {
+                    write_u64(&mut out, register);
+                }
+                write_patches(&mut out, &effects.stack);
+                write_patches(&mut out, &effects.memory);
+                match error {
+                    Some(message) => { out.push(1); write_bytes(&mut out, message.as_bytes()); }
+                    None => out.push(0),
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn read(mut bytes: &[u8]) -> Result<Recording, ReplayError> {
+    read_header(&mut bytes, MAGIC, VERSION)?;
+
+    let count = read_u64(&mut bytes)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let at = read_u64(&mut bytes)?;
+        let event = match take(&mut bytes, 1)?[0] {
+            0 => {
+                let opcode = take(&mut bytes, 1)?[0];
+                let failed = take(&mut bytes, 1)?[0];
+                let value = read_u64(&mut bytes)?;
+                let result = match failed {
+                    0 => Ok(value),
+                    1 => Err(value as i64),
+                    _ => return Err(DecodeError::InvalidOperand.into()),
+                };
+                Event::Io { opcode, result, data: read_bytes(&mut bytes)?.to_vec() }
+            }
+            1 => {
+                let id = read_u32(&mut bytes)?;
+                let effects = Effects {
+                    stack_pointer: read_u64(&mut bytes)?,
+                    frame_pointer: read_u64(&mut bytes)?,
+                    program_counter: read_u64(&mut bytes)?,
+                    stack_len: read_u64(&mut bytes)?,
+                    stack: read_patches(&mut bytes)?,
+                    memory: read_patches(&mut bytes)?,
+                };
+                if !effects.consistent() {
+                    return Err(ReplayError::Inconsistent("a host call left the stack or frame pointer outside the stack"));
+                }
+                let error = match take(&mut bytes, 1)?[0] {
+                    0 => None,
+                    1 => Some(String::from_utf8(read_bytes(&mut bytes)?.to_vec()).map_err(|_| DecodeError::InvalidOperand)?),
+                    _ => return Err(DecodeError::InvalidOperand.into()),
+                };
+                Event::HostCall { id, effects, error }
+            }
+            _ => return Err(DecodeError::InvalidOperand.into()),
+        };
+        entries.push(Entry { at, event });
+    }
+    if !bytes.is_empty() {
+        return Err(ReplayError::TrailingBytes);
+    }
+    Ok(Recording { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn effects(stack_pointer: u64, frame_pointer: u64, stack_len: u64) -> Effects {
+        Effects { stack_pointer, frame_pointer, program_counter: 3, stack_len, stack: vec![(0, vec![1, 2])], memory: vec![(4, vec![9])] }
+    }
+
+    fn recording() -> Recording {
+        Recording { entries: vec![
+            Entry { at: 2, event: Event::Io { opcode: 0x40, result: Ok(3), data: b"abc".to_vec() } },
+            Entry { at: 5, event: Event::Io { opcode: 0x41, result: Err(4), data: vec![] } },
+            Entry { at: 9, event: Event::HostCall { id: 7, effects: effects(VM::STACK_START + 8, VM::STACK_START, 8), error: None } },
+            Entry { at: 9, event: Event::HostCall { id: 8, effects: effects(VM::STACK_START, VM::STACK_START, 0), error: Some("no".to_string()) } },
+        ] }
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        let recording = recording();
+        assert_eq!(read(&write(&recording)).unwrap(), recording);
+        assert_eq!(read(&write(&Recording::default())).unwrap(), Recording::default());
+    }
+
+    #[test]
+    fn diffs_apply_back() {
+        let (old, new) = ([1, 2, 3, 4, 5], [1, 9, 9, 4, 5, 6, 7]);
+        let patches = diff(&old, &new);
+        assert_eq!(patches, [(1, vec![9, 9]), (5, vec![6, 7])]);
+        let mut region = old.to_vec();
+        region.resize(new.len(), 0);
+        assert_eq!(apply(&mut region, &patches), Some(()));
+        assert_eq!(region, new);
+        assert_eq!(apply(&mut [0; 6], &patches), None);
+        assert_eq!(apply(&mut [0; 6], &[(u64::MAX, vec![1])]), None);
+    }
+
+    #[test]
+    fn bad_recordings_are_rejected() {
+        let mut bad_tag = write(&Recording { entries: recording().entries[..1].to_vec() });
+        // the tag, after the header, the count and `at`
+        bad_tag[22] = 2;
+        assert_eq!(read(&bad_tag), Err(ReplayError::Decode(DecodeError::InvalidOperand)));
+
+        for (stack_pointer, frame_pointer) in [(VM::STACK_START + 9, VM::STACK_START), (VM::STACK_START, VM::STACK_START - 1), (0, 0)] {
+            let entry = Entry { at: 0, event: Event::HostCall { id: 0, effects: effects(stack_pointer, frame_pointer, 8), error: None } };
+            assert!(matches!(read(&write(&Recording { entries: vec![entry] })), Err(ReplayError::Inconsistent(_))));
+        }
+    }
+}
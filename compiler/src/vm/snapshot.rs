@@ -0,0 +1,211 @@
+//! saving a VM's whole state to resume later
+//!
+//! a snapshot is the magic bytes and a little endian u16 format version, followed by the program as a module (see
+//! `bytecode::module`, without a data section since memory is saved anyway), main memory and the stack as a u64
+//! length and their bytes, the stack pointer, frame pointer, program counter and stack limit as u64s, and the fuel as
+//! a u8 that's 1 if there's a limit followed by the u64 amount left, and then the number of instructions run so far as
+//! a u64, so recordings can be replayed from the snapshot
+//!
+//! only the machine is saved: the host, host functions, tracer, cost table, engine and any recording or replay have to
+//! be set up again
+
+use std::{error::Error, fmt::Display};
+
+use crate::bytecode::{module::{self, header, read_bytes, read_header, read_u64, write_bytes, write_u64, HeaderError, ModuleError}, take, DecodeError, Program};
+
+use super::VM;
+
+pub const MAGIC: &[u8; 4] = b"KSVM";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Decode(DecodeError),
+    Module(ModuleError),
+    /// the registers don't make sense together, like a stack pointer outside of the stack
+    Inconsistent(&'static str),
+    TrailingBytes,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a kitchen-sink snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v} (expected {VERSION})"),
+            SnapshotError::Decode(e) => write!(f, "{e}"),
+            SnapshotError::Module(e) => write!(f, "{e}"),
+            SnapshotError::Inconsistent(what) => write!(f, "inconsistent snapshot: {what}"),
+            SnapshotError::TrailingBytes => write!(f, "trailing bytes after the snapshot"),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> SnapshotError { SnapshotError::Decode(e) }
+}
+
+impl From<HeaderError> for SnapshotError {
+    fn from(e: HeaderError) -> SnapshotError {
+        match e {
+            HeaderError::BadMagic => SnapshotError::BadMagic,
+            HeaderError::UnsupportedVersion(v) => SnapshotError::UnsupportedVersion(v),
+            HeaderError::Decode(e) => SnapshotError::Decode(e),
+        }
+    }
+}
+
+/// saves `vm`, along with the symbols and debug info of the `program` it's running
+pub fn write(vm: &VM, program: &Program) -> Vec<u8> {
+    let program = Program {
+        instructions: vm.program.clone(), data: vec![], constants: vm.constants.clone(), symbols: program.symbols.clone(), debug: program.debug.clone() };
+
+    let mut out = header(MAGIC, VERSION);
+    write_bytes(&mut out, &module::write(&program));
+    write_bytes(&mut out, &vm.main_memory);
+    write_bytes(&mut out, &vm.stack);
+    for register in [vm.stack_pointer, vm.frame_pointer, vm.program_counter, vm.max_stack] {
+        write_u64(&mut out, register);
+    }
+    match vm.fuel {
+        Some(fuel) => { out.push(1); write_u64(&mut out, fuel); }
+        None => { out.push(0); write_u64(&mut out, 0); }
+    }
+    write_u64(&mut out, vm.executed);
+    out
+}
+
+/// restores a VM and the program it was running, with the default host
+pub fn read(mut bytes: &[u8]) -> Result<(VM, Program), SnapshotError> {
+    read_header(&mut bytes, MAGIC, VERSION)?;
+
+    let program = module::read(read_bytes(&mut bytes)?).map_err(SnapshotError::Module)?;
+    let main_memory = read_bytes(&mut bytes)?.to_vec();
+    let stack = read_bytes(&mut bytes)?.to_vec();
+    let stack_pointer = read_u64(&mut bytes)?;
+    let frame_pointer = read_u64(&mut bytes)?;
+    let program_counter = read_u64(&mut bytes)?;
+    let max_stack = read_u64(&mut bytes)?;
+    let limited = take(&mut bytes, 1)?[0];
+    let fuel = read_u64(&mut bytes)?;
+    let executed = read_u64(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(SnapshotError::TrailingBytes);
+    }
+
+    if !(VM::STACK_START..=VM::STACK_START + stack.len() as u64).contains(&stack_pointer) {
+        return Err(SnapshotError::Inconsistent("the stack pointer is outside the stack"));
+    }
+    if stack.len() as u64 > max_stack {
+        return Err(SnapshotError::Inconsistent("the stack is bigger than its limit"));
+    }
+    if program_counter > program.instructions.len() as u64 {
+        return Err(SnapshotError::Inconsistent("the program counter is outside the program"));
+    }
+    let fuel = match limited {
+        0 => None,
+        1 => Some(fuel),
+        _ => return Err(SnapshotError::Decode(DecodeError::InvalidOperand)),
+    };
+
+    let mut vm = VM::new(program.instructions.clone(), 0, max_stack as usize);
+    vm.main_memory = main_memory;
+    vm.stack = stack;
+    vm.stack_pointer = stack_pointer;
+    vm.frame_pointer = frame_pointer;
+    vm.program_counter = program_counter;
+    vm.constants = program.constants.clone();
+    vm.fuel = fuel;
+    vm.executed = executed;
+    Ok((vm, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::asm::assemble;
+
+    /// adds up 1 to 10 into address 0
+    const PROGRAM: &str = "
+        PUSHD 10
+    .Lloop:
+        DUPD
+        PUSHD 0
+        LOADD
+        ADDD
+        PUSHD 0
+        STORED
+        PUSHD 1
+        SUBD
+        DUPD
+        PUSHADDR .Lend
+        SWAPD
+        TRUNCDB
+        JZB
+        JMP .Lloop
+    .Lend:
+    ";
+
+    fn parts(sp: u64, pc: u64, max_stack: u64, limited: u8) -> Vec<u8> {
+        let mut out = header(MAGIC, VERSION);
+        write_bytes(&mut out, &module::write(&assemble("PUSHB 1\n").unwrap()));
+        write_bytes(&mut out, &[0; 4]);
+        write_bytes(&mut out, &[7; 8]);
+        for register in [sp, VM::STACK_START, pc, max_stack] {
+            write_u64(&mut out, register);
+        }
+        out.push(limited);
+        write_u64(&mut out, 100);
+        write_u64(&mut out, 5);
+        out
+    }
+
+    fn error(bytes: &[u8]) -> SnapshotError {
+        read(bytes).err().expect("the snapshot was accepted")
+    }
+
+    #[test]
+    fn snapshots_resume_where_they_left_off() {
+        let program = assemble(PROGRAM).unwrap();
+        let mut whole = VM::load(&program, 8, 256).unwrap();
+        whole.run().unwrap();
+
+        let mut vm = VM::load(&program, 8, 256).unwrap();
+        vm.set_fuel(Some(1000));
+        vm.run_for(37).unwrap();
+        let bytes = write(&vm, &program);
+        let (mut restored, restored_program) = read(&bytes).unwrap();
+        assert_eq!(restored_program.instructions, program.instructions);
+        assert_eq!(restored_program.symbols, program.symbols);
+        assert_eq!(
+            (restored.program_counter, restored.stack_pointer(), restored.frame_pointer(), restored.instructions_executed(), restored.fuel()),
+            (vm.program_counter, vm.stack_pointer(), vm.frame_pointer(), 37, Some(963)),
+        );
+        assert_eq!(write(&restored, &restored_program), bytes);
+
+        restored.run().unwrap();
+        assert_eq!((restored.main_memory.clone(), restored.stack.clone()), (whole.main_memory.clone(), whole.stack.clone()));
+        assert_eq!(restored.get_u64(0).unwrap(), 55);
+        assert_eq!(restored.instructions_executed(), whole.instructions_executed());
+    }
+
+    #[test]
+    fn bad_snapshots_are_rejected() {
+        let good = parts(VM::STACK_START, 1, 8, 0);
+        let (vm, _) = read(&good).unwrap();
+        assert_eq!((vm.instructions_executed(), vm.fuel(), vm.stack_pointer()), (5, None, VM::STACK_START));
+        assert!(matches!(error(&parts(VM::STACK_START + 9, 1, 8, 0)), SnapshotError::Inconsistent(_)));
+        assert!(matches!(error(&parts(VM::STACK_START - 1, 1, 8, 0)), SnapshotError::Inconsistent(_)));
+        assert!(matches!(error(&parts(VM::STACK_START, 2, 8, 0)), SnapshotError::Inconsistent(_)));
+        assert!(matches!(error(&parts(VM::STACK_START, 1, 7, 0)), SnapshotError::Inconsistent(_)));
+        assert_eq!(error(&parts(VM::STACK_START, 1, 8, 2)), SnapshotError::Decode(DecodeError::InvalidOperand));
+
+        let mut bad_module = good.clone();
+        // the module's magic, just after its length
+        bad_module[14] ^= 0xff;
+        assert!(matches!(error(&bad_module), SnapshotError::Module(_)));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{cell::RefCell, collections::HashMap, fmt::Write as _, io::Write, rc::Rc};
+
+use crate::bytecode::{Instruction, Program};
+
+/// what a tracer sees before each instruction runs
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub pc: u64,
+    pub instruction: Instruction,
+    pub stack_pointer: u64,
+    pub frame_pointer: u64,
+}
+
+/// an opt-in hook the VM calls on every instruction
+pub trait Tracer {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+/// so a tracer can be handed to a VM and still looked at afterwards
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.borrow_mut().trace(event)
+    }
+}
+
+/// for running two tracers at once
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.0.trace(event);
+        self.1.trace(event);
+    }
+}
+
+/// writes a line per instruction
+pub struct Log<W: Write>(pub W);
+
+impl<W: Write> Tracer for Log<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let _ = writeln!(self.0, "{:>6}  sp {:#x}  fp {:#x}  {}", event.pc, event.stack_pointer, event.frame_pointer, event.instruction);
+    }
+}
+
+/// counts how often each instruction, each kind of instruction and each call stack runs
+#[derive(Debug, Default)]
+pub struct Profile {
+    by_index: HashMap<u64, u64>,
+    by_name: HashMap<&'static str, u64>,
+    total: u64,
+    /// entry points of the functions we're in, outermost first
+    calls: Vec<u64>,
+    /// counts for each distinct call stack, interned so a `Vec` isn't made per instruction
+    stacks: HashMap<Vec<u64>, usize>,
+    stack_counts: Vec<u64>,
+    current_stack: Option<usize>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    fn current_stack(&mut self) -> usize {
+        if let Some(id) = self.current_stack {
+            return id;
+        }
+        let next = self.stack_counts.len();
+        let id = *self.stacks.entry(self.calls.clone()).or_insert(next);
+        if id == next {
+            self.stack_counts.push(0);
+        }
+        self.current_stack = Some(id);
+        id
+    }
+
+    /// a function's name for folded stacks: the symbol at its entry point, or the index
+    fn function_name(program: &Program, entry: u64) -> String {
+        match program.symbols.iter().find(|s| s.index as u64 == entry) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("{entry}"),
+        }
+    }
+
+    /// the most run instructions and kinds of instruction, with how much of the total each is
+    pub fn report(&self, program: &Program, limit: usize) -> String {
+        let mut out = String::new();
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+
+        let mut by_name: Vec<_> = self.by_name.iter().collect();
+        by_name.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(out, "{} instructions\n\n   count       %  instruction", self.total);
+        for (name, count) in by_name.into_iter().take(limit) {
+            let _ = writeln!(out, "{count:>8}  {:>5.1}%  {name}", percent(*count));
+        }
+
+        let mut by_index: Vec<_> = self.by_index.iter().collect();
+        by_index.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(out, "\n   count       %   index  instruction");
+        for (index, count) in by_index.into_iter().take(limit) {
+            let instruction = program.instructions.get(*index as usize).map(|i| i.to_string()).unwrap_or_default();
+            let symbol = program.symbols.iter().rev().find(|s| s.index as u64 <= *index).map(|s| format!("  ({}+{})", s.name, index - s.index as u64));
+            let _ = writeln!(out, "{count:>8}  {:>5.1}%  {index:>6}  {instruction}{}", percent(*count), symbol.unwrap_or_default());
+        }
+        out
+    }
+
+    /// one `outer;inner count` line per call stack, the format flamegraph tools take
+    pub fn folded(&self, program: &Program) -> String {
+        let mut stacks: Vec<(String, u64)> = self.stacks.iter()
+            .map(|(calls, &id)| {
+                let names: Vec<String> = std::iter::once("(start)".to_string())
+                    .chain(calls.iter().map(|&entry| Self::function_name(program, entry)))
+                    .collect();
+                (names.join(";"), self.stack_counts[id])
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        stacks.sort();
+        stacks.iter().map(|(stack, count)| format!("{stack} {count}\n")).collect()
+    }
+}
+
+impl Tracer for Profile {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.total += 1;
+        *self.by_index.entry(event.pc).or_default() += 1;
+        *self.by_name.entry(event.instruction.name()).or_default() += 1;
+        let stack = self.current_stack();
+        self.stack_counts[stack] += 1;
+
+        match event.instruction {
+            Instruction::Call(target) => {
+                self.calls.push(target as u64);
+                self.current_stack = None;
+            }
+            Instruction::Ret if !self.calls.is_empty() => {
+                self.calls.pop();
+                self.current_stack = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bytecode::asm::assemble, vm::VM};
+
+    /// calls `f` twice
+    const PROGRAM: &str = "    CALL f\n    CALL f\n    JMP .Lend\nf:\n    PUSHB 1\n    POP 1\n    RET\n.Lend:\n";
+
+    fn traced(tracer: impl Tracer + 'static) -> Program {
+        let program = assemble(PROGRAM).unwrap();
+        let mut vm = VM::load(&program, 0, 256).unwrap();
+        vm.set_tracer(Some(Box::new(tracer)));
+        vm.run().unwrap();
+        program
+    }
+
+    #[test]
+    fn profiles_count_instructions_and_call_stacks() {
+        let profile = Rc::new(RefCell::new(Profile::new()));
+        let program = traced(profile.clone());
+        let profile = profile.borrow();
+        assert_eq!(profile.total, 9);
+        assert_eq!(profile.by_name["Call"], 2);
+        assert_eq!(profile.by_index[&5], 2);
+        assert_eq!(profile.folded(&program), "(start) 3\n(start);f 6\n");
+
+        let report = profile.report(&program, 2);
+        assert!(report.starts_with("9 instructions\n"), "{report}");
+        assert!(report.contains("       2   22.2%       3  PUSHB 1  (f+0)\n"), "{report}");
+        // only the top 2 of each
+        assert_eq!(report.lines().filter(|line| line.contains('%')).count(), 2 + 2 + 2);
+    }
+
+    #[test]
+    fn tracers_see_every_instruction_before_it_runs() {
+        let log = Rc::new(RefCell::new(Log(vec![])));
+        let profile = Rc::new(RefCell::new(Profile::new()));
+        traced((log.clone(), profile.clone()));
+        let log = String::from_utf8(log.borrow().0.clone()).unwrap();
+        assert_eq!(log.lines().count(), 9);
+        assert_eq!(log.lines().next(), Some(format!("     0  sp {:#x}  fp {:#x}  CALL 3", VM::STACK_START, VM::STACK_START).as_str()));
+        assert_eq!(profile.borrow().total, 9);
+    }
+}